@@ -0,0 +1,368 @@
+// A whole collection run in one file, for copying around and attaching to tickets.
+// It's just another SnapshotStore, so it holds exactly what a directory would:
+// segment files, manifests and stats rows, keyed by "<timestamp>/<name>".
+//
+// Layout:
+//   "WSSARCH2" [offset of the newest index record: u64 LE, 0 if none yet]
+//   records: [key length: u32 LE][key][data length: u64 LE][data]
+// A data length of u64::MAX is a tombstone for the key. Later records for a key
+// replace earlier ones. Every so often, and on close, the writer appends an
+// index record covering everything before it and points the header at it, so
+// opening only has to walk the headers of records written since.
+
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Read, Write, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use byteorder::{ByteOrder, LittleEndian};
+
+use super::store::SnapshotStore;
+
+const MAGIC: &[u8; 8] = b"WSSARCH2";
+const HEADER_LEN: u64 = 16;
+const TOMBSTONE: u64 = std::u64::MAX;
+// Keys are "<timestamp>/<name>", so this can't clash with one.
+const INDEX_KEY: &str = "\0index";
+// Records between index writes: at least this many, and at least a quarter of
+// the index size, so rewriting the index stays cheap per record.
+const INDEX_MIN_RECORDS: usize = 64;
+
+pub struct ArchiveStore {
+    path: PathBuf,
+    inner: Mutex<Archive>,
+}
+
+struct Archive {
+    file: File,
+    // key -> (offset of data, length of data)
+    index: BTreeMap<String, (u64, u64)>,
+    end: u64,
+    writable: bool,
+    // Records appended since the index was last written
+    since_index: usize,
+}
+
+impl ArchiveStore {
+    // Opens an existing archive for writing, or creates an empty one. An
+    // incomplete record at the end, from a writer that died, is cut off.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<ArchiveStore> {
+        let path = path.as_ref();
+        let mut file = OpenOptions::new().read(true).write(true).create(true).open(path)?;
+        if file.metadata()?.len() == 0 {
+            file.write_all(MAGIC)?;
+            file.write_all(&[0u8; 8])?;
+        }
+        let file_len = file.metadata()?.len();
+        let archive = Archive::load(file, path, true)?;
+        if archive.end < file_len {
+            warn!("Discarding {} bytes of incomplete record at the end of {}", file_len - archive.end, path.display());
+            archive.file.set_len(archive.end)?;
+        }
+        debug!("Opened archive {} with {} entries", path.display(), archive.index.len());
+        Ok(ArchiveStore { path: path.to_path_buf(), inner: Mutex::new(archive) })
+    }
+
+    // Opens an archive without ever modifying it, so it's safe to read one a
+    // collector is still writing. An incomplete record at the end is ignored.
+    pub fn open_read_only<P: AsRef<Path>>(path: P) -> io::Result<ArchiveStore> {
+        let path = path.as_ref();
+        let file = File::open(path)?;
+        let file_len = file.metadata()?.len();
+        let archive = Archive::load(file, path, false)?;
+        if archive.end < file_len {
+            debug!("Ignoring {} bytes of incomplete record at the end of {}", file_len - archive.end, path.display());
+        }
+        debug!("Opened archive {} read-only with {} entries", path.display(), archive.index.len());
+        Ok(ArchiveStore { path: path.to_path_buf(), inner: Mutex::new(archive) })
+    }
+}
+
+impl Archive {
+    fn load(mut file: File, path: &Path, writable: bool) -> io::Result<Archive> {
+        let file_len = file.metadata()?.len();
+        let not_archive = || io::Error::new(io::ErrorKind::InvalidData, format!("{} is not a wss archive", path.display()));
+        let mut header = [0u8; HEADER_LEN as usize];
+        file.seek(SeekFrom::Start(0))?;
+        file.read_exact(&mut header).map_err(|_| not_archive())?;
+        if &header[..MAGIC.len()] != MAGIC {
+            return Err(not_archive());
+        }
+        let mut index = BTreeMap::new();
+        let mut offset = HEADER_LEN;
+        let index_offset = LittleEndian::read_u64(&header[MAGIC.len()..]);
+        if index_offset != 0 {
+            match read_index(&mut file, index_offset, file_len) {
+                Ok((saved, next)) => {
+                    index = saved;
+                    offset = next;
+                },
+                Err(e) => warn!("Ignoring the index of {}, reading every record instead: {:?}", path.display(), e),
+            }
+        }
+        let end = scan(&mut file, &mut index, offset, file_len)?;
+        Ok(Archive {
+            file: file,
+            index: index,
+            end: end,
+            writable: writable,
+            since_index: 0,
+        })
+    }
+
+    fn append(&mut self, key: &str, data_len: u64, data: &[u8]) -> io::Result<u64> {
+        if !self.writable {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "Archive is open read-only"));
+        }
+        let mut header = vec![0u8; 4 + key.len() + 8];
+        LittleEndian::write_u32(&mut header[0..4], key.len() as u32);
+        header[4..4 + key.len()].copy_from_slice(key.as_bytes());
+        LittleEndian::write_u64(&mut header[4 + key.len()..], data_len);
+        self.file.seek(SeekFrom::Start(self.end))?;
+        self.file.write_all(&header)?;
+        self.file.write_all(data)?;
+        let data_offset = self.end + header.len() as u64;
+        self.end = data_offset + data.len() as u64;
+        Ok(data_offset)
+    }
+
+    // Called after every record appended by the store.
+    fn appended(&mut self) -> io::Result<()> {
+        self.since_index += 1;
+        if self.since_index >= std::cmp::max(INDEX_MIN_RECORDS, self.index.len() / 4) {
+            self.write_index()?;
+        }
+        Ok(())
+    }
+
+    // Entries are [key length: u32 LE][key][data offset: u64 LE][data length: u64 LE].
+    fn write_index(&mut self) -> io::Result<()> {
+        let mut data = Vec::new();
+        for (key, &(offset, len)) in &self.index {
+            let mut entry = vec![0u8; 4 + key.len() + 16];
+            LittleEndian::write_u32(&mut entry[0..4], key.len() as u32);
+            entry[4..4 + key.len()].copy_from_slice(key.as_bytes());
+            LittleEndian::write_u64(&mut entry[4 + key.len()..12 + key.len()], offset);
+            LittleEndian::write_u64(&mut entry[12 + key.len()..], len);
+            data.extend_from_slice(&entry);
+        }
+        let record_offset = self.end;
+        self.append(INDEX_KEY, data.len() as u64, &data)?;
+        // Only point at the index once it's all there.
+        let mut pointer = [0u8; 8];
+        LittleEndian::write_u64(&mut pointer, record_offset);
+        self.file.seek(SeekFrom::Start(MAGIC.len() as u64))?;
+        self.file.write_all(&pointer)?;
+        self.since_index = 0;
+        Ok(())
+    }
+}
+
+impl Drop for Archive {
+    fn drop(&mut self) {
+        if self.writable && self.since_index > 0 {
+            if let Err(e) = self.write_index() {
+                warn!("Unable to write archive index: {:?}", e);
+            }
+        }
+    }
+}
+
+// Reads one record header at offset: (key, data offset, data length), or None
+// if the record doesn't fit in the file.
+fn read_header(file: &mut File, offset: u64, file_len: u64) -> io::Result<Option<(String, u64, u64)>> {
+    if offset + 4 > file_len {
+        return Ok(None);
+    }
+    file.seek(SeekFrom::Start(offset))?;
+    let mut key_len = [0u8; 4];
+    file.read_exact(&mut key_len)?;
+    let key_len = LittleEndian::read_u32(&key_len) as u64;
+    if offset + 4 + key_len + 8 > file_len {
+        return Ok(None);
+    }
+    let mut key = vec![0u8; key_len as usize];
+    file.read_exact(&mut key)?;
+    let mut data_len = [0u8; 8];
+    file.read_exact(&mut data_len)?;
+    let data_len = LittleEndian::read_u64(&data_len);
+    let data_offset = offset + 4 + key_len + 8;
+    if data_len != TOMBSTONE && data_offset + data_len > file_len {
+        return Ok(None);
+    }
+    Ok(Some((String::from_utf8_lossy(&key).into_owned(), data_offset, data_len)))
+}
+
+// Walk the record headers from offset, updating the index. Returns the end of
+// the last complete record.
+fn scan(file: &mut File, index: &mut BTreeMap<String, (u64, u64)>, mut offset: u64, file_len: u64) -> io::Result<u64> {
+    while let Some((key, data_offset, data_len)) = read_header(file, offset, file_len)? {
+        if data_len == TOMBSTONE {
+            index.remove(&key);
+            offset = data_offset;
+        } else {
+            if key != INDEX_KEY {
+                index.insert(key, (data_offset, data_len));
+            }
+            offset = data_offset + data_len;
+        }
+    }
+    Ok(offset)
+}
+
+// Returns the saved index and the offset of the record after it.
+fn read_index(file: &mut File, offset: u64, file_len: u64) -> io::Result<(BTreeMap<String, (u64, u64)>, u64)> {
+    let bad_index = |what: &str| io::Error::new(io::ErrorKind::InvalidData, format!("Bad index at {}: {}", offset, what));
+    let (data_offset, data_len) = match read_header(file, offset, file_len)? {
+        Some((key, data_offset, data_len)) if key == INDEX_KEY && data_len != TOMBSTONE => (data_offset, data_len),
+        _ => return Err(bad_index("not an index record")),
+    };
+    let mut data = vec![0u8; data_len as usize];
+    file.seek(SeekFrom::Start(data_offset))?;
+    file.read_exact(&mut data)?;
+    let mut index = BTreeMap::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        if pos + 4 > data.len() {
+            return Err(bad_index("truncated entry"));
+        }
+        let key_len = LittleEndian::read_u32(&data[pos..pos + 4]) as usize;
+        if pos + 4 + key_len + 16 > data.len() {
+            return Err(bad_index("truncated entry"));
+        }
+        let key = String::from_utf8_lossy(&data[pos + 4..pos + 4 + key_len]).into_owned();
+        let entry_offset = LittleEndian::read_u64(&data[pos + 4 + key_len..pos + 12 + key_len]);
+        let entry_len = LittleEndian::read_u64(&data[pos + 12 + key_len..pos + 20 + key_len]);
+        if entry_offset + entry_len > offset {
+            return Err(bad_index("entry past the index"));
+        }
+        index.insert(key, (entry_offset, entry_len));
+        pos += 4 + key_len + 16;
+    }
+    Ok((index, data_offset + data_len))
+}
+
+impl SnapshotStore for ArchiveStore {
+    fn put(&self, key: &str, data: &[u8]) -> io::Result<()> {
+        let mut archive = self.inner.lock().unwrap();
+        let data_offset = archive.append(key, data.len() as u64, data)?;
+        archive.index.insert(key.to_string(), (data_offset, data.len() as u64));
+        archive.appended()
+    }
+
+    fn get(&self, key: &str) -> io::Result<Vec<u8>> {
+        let mut archive = self.inner.lock().unwrap();
+        let (offset, len) = match archive.index.get(key) {
+            Some(&location) => location,
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, format!("No such key: {}", key))),
+        };
+        let mut data = vec![0u8; len as usize];
+        archive.file.seek(SeekFrom::Start(offset))?;
+        archive.file.read_exact(&mut data)?;
+        Ok(data)
+    }
+
+    fn list(&self, prefix: &str) -> io::Result<Vec<String>> {
+        let archive = self.inner.lock().unwrap();
+        Ok(archive.index.keys()
+           .filter(|key| key.starts_with(prefix))
+           .cloned()
+           .collect())
+    }
+
+    fn delete(&self, key: &str) -> io::Result<()> {
+        let mut archive = self.inner.lock().unwrap();
+        if !archive.index.contains_key(key) {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("No such key: {}", key)));
+        }
+        archive.append(key, TOMBSTONE, &[])?;
+        archive.index.remove(key);
+        archive.appended()
+    }
+
+    fn describe(&self) -> String {
+        format!("archive:{}", self.path.display())
+    }
+
+    // Deleting only appends tombstones, it never gives space back.
+    fn enforce_retention(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("wss-archive-{}-{}.wssa", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn reopen_uses_index_and_tail() {
+        let path = temp_path("reopen");
+        {
+            let archive = ArchiveStore::open(&path).unwrap();
+            for idx in 0..100 {
+                archive.put(&format!("t/{}", idx), &[idx as u8; 10]).unwrap();
+            }
+            archive.delete("t/5").unwrap();
+        }
+        // Written after the last index, as a collector which was killed would leave it.
+        {
+            let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+            let mut record = vec![0u8; 4];
+            LittleEndian::write_u32(&mut record, 5);
+            record.extend_from_slice(b"t/new");
+            let mut len = [0u8; 8];
+            LittleEndian::write_u64(&mut len, 3);
+            record.extend_from_slice(&len);
+            record.extend_from_slice(b"abc");
+            file.write_all(&record).unwrap();
+        }
+        let archive = ArchiveStore::open_read_only(&path).unwrap();
+        assert_eq!(archive.list("t/").unwrap().len(), 100);
+        assert_eq!(archive.get("t/42").unwrap(), vec![42u8; 10]);
+        assert_eq!(archive.get("t/new").unwrap(), b"abc".to_vec());
+        assert!(archive.get("t/5").is_err());
+        assert!(archive.put("t/x", b"x").is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn read_only_ignores_partial_record() {
+        let path = temp_path("partial");
+        {
+            let archive = ArchiveStore::open(&path).unwrap();
+            archive.put("t/a", b"hello").unwrap();
+        }
+        let complete_len = fs::metadata(&path).unwrap().len();
+        {
+            let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+            file.write_all(&[3, 0, 0, 0, b't', b'/']).unwrap();
+        }
+        let archive = ArchiveStore::open_read_only(&path).unwrap();
+        assert_eq!(archive.get("t/a").unwrap(), b"hello".to_vec());
+        assert_eq!(archive.list("").unwrap().len(), 1);
+        drop(archive);
+        assert_eq!(fs::metadata(&path).unwrap().len(), complete_len + 6);
+        // Only a writer cuts it off.
+        ArchiveStore::open(&path).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), complete_len);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        let path = temp_path("other");
+        fs::write(&path, b"WSSARCH1\0\0\0\0\0\0\0\0").unwrap();
+        assert_eq!(ArchiveStore::open_read_only(&path).err().unwrap().kind(), io::ErrorKind::InvalidData);
+        fs::write(&path, b"WSS").unwrap();
+        assert_eq!(ArchiveStore::open(&path).err().unwrap().kind(), io::ErrorKind::InvalidData);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,240 @@
+// What changed between two persisted snapshots, per segment: pages which became
+// active or went idle, were swapped out or back in, changed zero status, or
+// changed content. Counts come with the address ranges involved, so a workload
+// phase can be tied back to the mappings it touched.
+//
+// Segments are matched by start address. Where a segment changed size only the
+// pages both snapshots have are compared. Content hashes are only compared where
+// both snapshots recorded one for the page.
+
+use std::collections::BTreeMap;
+use std::io;
+use std::io::Write;
+use std::ops::Range;
+
+use super::persist;
+use super::store::SnapshotStore;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Change {
+    BecameActive,
+    WentIdle,
+    SwappedOut,
+    SwappedIn,
+    ZeroChanged,
+    HashChanged,
+}
+
+impl Change {
+    pub const ALL: [Change; 6] = [
+        Change::BecameActive, Change::WentIdle, Change::SwappedOut,
+        Change::SwappedIn, Change::ZeroChanged, Change::HashChanged];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Change::BecameActive => "became active",
+            Change::WentIdle => "went idle",
+            Change::SwappedOut => "swapped out",
+            Change::SwappedIn => "swapped in",
+            Change::ZeroChanged => "zero status changed",
+            Change::HashChanged => "content changed",
+        }
+    }
+
+    fn of(&self, from: u64, to: u64, from_hash: u64, to_hash: u64) -> bool {
+        let set = |flags: u64, bit: u8| flags & (1 << bit) != 0;
+        let idle = |flags: u64| set(flags, super::PRESENT_PAGE_BIT) && !set(flags, super::ACTIVE_PAGE_BIT);
+        match self {
+            Change::BecameActive => !set(from, super::ACTIVE_PAGE_BIT) && set(to, super::ACTIVE_PAGE_BIT),
+            Change::WentIdle => set(from, super::ACTIVE_PAGE_BIT) && idle(to),
+            Change::SwappedOut => !set(from, super::SWAPPED_PAGE_BIT) && set(to, super::SWAPPED_PAGE_BIT),
+            Change::SwappedIn => set(from, super::SWAPPED_PAGE_BIT) && set(to, super::PRESENT_PAGE_BIT),
+            Change::ZeroChanged => set(from, super::ZERO_PAGE_BIT) != set(to, super::ZERO_PAGE_BIT),
+            Change::HashChanged => from_hash != 0 && to_hash != 0 && from_hash != to_hash,
+        }
+    }
+}
+
+pub struct SegmentDiff {
+    pub addr_start: usize,
+    pub path: String,
+    // None if the segment isn't in that snapshot
+    pub from_pages: Option<usize>,
+    pub to_pages: Option<usize>,
+    // Page offset ranges, per Change::ALL
+    pub changes: Vec<Vec<Range<usize>>>,
+    // Whether both snapshots had content hashes to compare
+    pub hashes_compared: bool,
+}
+
+impl SegmentDiff {
+    pub fn count(&self, change: Change) -> usize {
+        self.ranges(change).iter().map(|range| range.end - range.start).sum()
+    }
+
+    pub fn ranges(&self, change: Change) -> &[Range<usize>] {
+        &self.changes[Change::ALL.iter().position(|&c| c == change).unwrap()]
+    }
+}
+
+pub fn diff_memory(from: &super::ProcessMemory, to: &super::ProcessMemory) -> Vec<SegmentDiff> {
+    let mut segments: BTreeMap<usize, (Option<&super::Segment>, Option<&super::Segment>)> = BTreeMap::new();
+    for segment in &from.segments {
+        segments.entry(segment.addr_start).or_insert((None, None)).0 = Some(segment);
+    }
+    for segment in &to.segments {
+        segments.entry(segment.addr_start).or_insert((None, None)).1 = Some(segment);
+    }
+    segments.into_iter().map(|(addr_start, (from, to))| {
+        let mut changes: Vec<Vec<Range<usize>>> = vec![Vec::new(); Change::ALL.len()];
+        let mut hashes_compared = false;
+        if let (Some(from), Some(to)) = (from, to) {
+            hashes_compared = !from.content_hashes.is_empty() && !to.content_hashes.is_empty();
+            let hash = |segment: &super::Segment, idx: usize| *segment.content_hashes.get(idx).unwrap_or(&0);
+            let pages = std::cmp::min(from.page_flags.len(), to.page_flags.len());
+            for idx in 0..pages {
+                for (change, ranges) in Change::ALL.iter().zip(changes.iter_mut()) {
+                    if change.of(from.page_flags[idx], to.page_flags[idx], hash(from, idx), hash(to, idx)) {
+                        // Extend the last range if this page follows on from it.
+                        match ranges.last_mut() {
+                            Some(range) if range.end == idx => range.end += 1,
+                            _ => ranges.push(idx..idx + 1),
+                        }
+                    }
+                }
+            }
+        }
+        SegmentDiff {
+            addr_start: addr_start,
+            path: to.or(from).map_or(String::new(), |segment| segment.path.clone()),
+            from_pages: from.map(|segment| segment.page_flags.len()),
+            to_pages: to.map(|segment| segment.page_flags.len()),
+            changes: changes,
+            hashes_compared: hashes_compared,
+        }
+    }).collect()
+}
+
+// Diffs the snapshots at the given timestamps, or the two newest, and writes a
+// summary with up to max_ranges address ranges per change.
+pub fn diff(store: &dyn SnapshotStore, from: Option<&str>, to: Option<&str>, max_ranges: usize, out: &mut dyn Write) -> io::Result<()> {
+    let snapshots = persist::list_snapshots(store)?;
+    let find = |timestamp: Option<&str>, newest_but: usize| -> io::Result<String> {
+        match timestamp {
+            Some(timestamp) if snapshots.iter().any(|s| s == timestamp) => Ok(timestamp.to_string()),
+            Some(timestamp) => Err(io::Error::new(io::ErrorKind::NotFound,
+                                                  format!("No snapshot {} in {}", timestamp, store.describe()))),
+            None if snapshots.len() > newest_but => Ok(snapshots[snapshots.len() - 1 - newest_but].clone()),
+            None => Err(io::Error::new(io::ErrorKind::NotFound,
+                                       format!("Need two snapshots in {}, found {}", store.describe(), snapshots.len()))),
+        }
+    };
+    let (from, to) = (find(from, 1)?, find(to, 0)?);
+    let segments = diff_memory(&persist::read_process_memory(store, &from)?, &persist::read_process_memory(store, &to)?);
+
+    writeln!(out, "{} -> {}", from, to)?;
+    let mut totals = vec![0; Change::ALL.len()];
+    let mut unchanged = 0;
+    for segment in &segments {
+        let compared = |change: &&Change| **change != Change::HashChanged || segment.hashes_compared;
+        if segment.from_pages == segment.to_pages && Change::ALL.iter().filter(compared).all(|&change| segment.count(change) == 0) {
+            unchanged += 1;
+            continue;
+        }
+        let size = |pages: usize| format!("{} pages", pages);
+        let description = match (segment.from_pages, segment.to_pages) {
+            (Some(from_pages), Some(to_pages)) if from_pages == to_pages => size(to_pages),
+            (Some(from_pages), Some(to_pages)) => format!("resized from {} to {} pages", from_pages, to_pages),
+            (None, Some(to_pages)) => format!("new, {}", size(to_pages)),
+            (Some(from_pages), None) => format!("removed, was {}", size(from_pages)),
+            (None, None) => String::new(),
+        };
+        writeln!(out, "\nSegment 0x{:x} {} ({})", segment.addr_start, segment.path, description)?;
+        for (idx, &change) in Change::ALL.iter().enumerate().filter(|(_idx, change)| compared(change)) {
+            let ranges = segment.ranges(change);
+            let count = segment.count(change);
+            totals[idx] += count;
+            if count == 0 {
+                continue;
+            }
+            let shown: Vec<String> = ranges.iter().take(max_ranges)
+                .map(|range| format!("0x{:x}-0x{:x}", segment.addr_start + range.start * 4096, segment.addr_start + range.end * 4096))
+                .collect();
+            let more = if ranges.len() > max_ranges { format!(" and {} more", ranges.len() - max_ranges) } else { String::new() };
+            writeln!(out, "  {:<20} {:>10} pages in {} ranges: {}{}", change.name(), count, ranges.len(), shown.join(", "), more)?;
+        }
+    }
+    writeln!(out, "\n{} of {} segments unchanged", unchanged, segments.len())?;
+    writeln!(out, "Total:")?;
+    for (change, total) in Change::ALL.iter().zip(totals.iter()) {
+        if *change == Change::HashChanged && !segments.iter().any(|segment| segment.hashes_compared) {
+            writeln!(out, "  {:<20} not compared; snapshots taken without content hashes", change.name())?;
+            continue;
+        }
+        writeln!(out,"  {:<20} {:>10} pages ({:.1} MiB)", change.name(), total, (*total * 4096) as f64 / (1 << 20) as f64)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::testing::memory;
+
+    const PRESENT: u64 = 1 << super::super::PRESENT_PAGE_BIT;
+    const ACTIVE: u64 = PRESENT | 1 << super::super::ACTIVE_PAGE_BIT;
+    const SWAPPED: u64 = 1 << super::super::SWAPPED_PAGE_BIT;
+    const ZERO: u64 = 1 << super::super::ZERO_PAGE_BIT;
+
+    #[test]
+    fn change_of() {
+        assert!(Change::BecameActive.of(PRESENT, ACTIVE, 0, 0));
+        assert!(!Change::BecameActive.of(ACTIVE, ACTIVE, 0, 0));
+        assert!(Change::WentIdle.of(ACTIVE, PRESENT, 0, 0));
+        // Going away entirely isn't going idle.
+        assert!(!Change::WentIdle.of(ACTIVE, 0, 0, 0));
+        assert!(Change::SwappedOut.of(PRESENT, SWAPPED, 0, 0));
+        assert!(!Change::SwappedOut.of(SWAPPED, SWAPPED, 0, 0));
+        assert!(Change::SwappedIn.of(SWAPPED, PRESENT, 0, 0));
+        assert!(!Change::SwappedIn.of(SWAPPED, 0, 0, 0));
+        assert!(Change::ZeroChanged.of(PRESENT | ZERO, PRESENT, 0, 0));
+        assert!(Change::ZeroChanged.of(PRESENT, PRESENT | ZERO, 0, 0));
+        assert!(!Change::ZeroChanged.of(PRESENT | ZERO, ACTIVE | ZERO, 0, 0));
+        assert!(Change::HashChanged.of(PRESENT, PRESENT, 1, 2));
+        assert!(!Change::HashChanged.of(PRESENT, PRESENT, 1, 1));
+        // Only where both snapshots read the page.
+        assert!(!Change::HashChanged.of(PRESENT, PRESENT, 0, 2));
+        assert!(!Change::HashChanged.of(PRESENT, PRESENT, 1, 0));
+    }
+
+    #[test]
+    fn adjacent_pages_merge_into_ranges() {
+        let from = memory(0, vec![(0x1000, vec![PRESENT; 10])]);
+        let mut to_flags = vec![PRESENT; 10];
+        for &idx in &[0, 1, 2, 5, 8, 9] {
+            to_flags[idx] = ACTIVE;
+        }
+        let to = memory(0, vec![(0x1000, to_flags)]);
+        let segments = diff_memory(&from, &to);
+        assert_eq!(segments.len(), 1);
+        assert_eq!(segments[0].ranges(Change::BecameActive), &[0..3, 5..6, 8..10]);
+        assert_eq!(segments[0].count(Change::BecameActive), 6);
+        assert!(segments[0].ranges(Change::WentIdle).is_empty());
+    }
+
+    #[test]
+    fn segments_matched_by_start() {
+        let from = memory(0, vec![(0x1000, vec![ACTIVE; 4]), (0x10000, vec![PRESENT; 2])]);
+        let to = memory(0, vec![(0x1000, vec![PRESENT; 6]), (0x20000, vec![PRESENT; 3])]);
+        let segments = diff_memory(&from, &to);
+        assert_eq!(segments.iter().map(|segment| segment.addr_start).collect::<Vec<usize>>(), vec![0x1000, 0x10000, 0x20000]);
+        // Resized: only the pages both have are compared.
+        assert_eq!((segments[0].from_pages, segments[0].to_pages), (Some(4), Some(6)));
+        assert_eq!(segments[0].ranges(Change::WentIdle).len(), 1);
+        assert_eq!(segments[0].ranges(Change::WentIdle)[0], 0..4);
+        assert_eq!((segments[1].from_pages, segments[1].to_pages), (Some(2), None));
+        assert_eq!((segments[2].from_pages, segments[2].to_pages), (None, Some(3)));
+        assert!(Change::ALL.iter().all(|&change| segments[1].count(change) == 0 && segments[2].count(change) == 0));
+        assert!(!segments[0].hashes_compared);
+    }
+}
@@ -4,7 +4,10 @@ use std::io::{BufReader, BufRead, Write, Read, Seek, SeekFrom};
 use byteorder::{ByteOrder, LittleEndian};
 use std::{thread, time};
 use chrono::Utc;
-use std::cmp::min;
+use std::cmp::{min, max};
+use std::collections::HashMap;
+use std::hash::Hasher;
+use twox_hash::XxHash64;
 //use nix::sys::{ptrace, wait, signal};
 //use nix::unistd::Pid;
 
@@ -22,9 +25,12 @@ const PAGE_SIZE: usize = 4096;
 const IDLE_BITMAP_PATH: &str = "/sys/kernel/mm/page_idle/bitmap";
 
 const KPAGEFLAGS_PATH: &str = "/proc/kpageflags";
+// Number of PFNs read from kpageflags at once when looking up single pages.
+const KPAGEFLAGS_CHUNK_PAGES: u64 = 512;
+
 // Without a process ID means get the memory activity for the whole host.
 // Note it doesn't analyze page contents, only type and activity.
-pub fn get_host_memory(sleep: u64, inspect_ram: bool) -> Result<super::ProcessMemory, std::io::Error> {
+pub fn get_host_memory(sleep: u64, inspect_ram: bool, compressor: Option<Compressor>) -> Result<super::ProcessMemory, std::io::Error> {
     let physical_segments = get_physical_segments()?;
     set_idlemap(&physical_segments)?;
     //ptrace::cont(nix_pid, None);
@@ -38,39 +44,63 @@ pub fn get_host_memory(sleep: u64, inspect_ram: bool) -> Result<super::ProcessMe
         segments: physical_segments.iter().map(|segment| {
             let kpageflags = get_kpageflags(segment).unwrap();
             let mut memory_data_memo = MemoryDataMemo::new(0, &segment, &kpageflags).unwrap();
+            let mut content_hashes: Vec<u64> = vec![0; kpageflags.len()];
+            let mut pattern_words: HashMap<usize, u64> = HashMap::new();
+            let page_flags: Vec<u64> = kpageflags.iter().enumerate().map(|(pfn_offset, pfn_flags)| {
+                // Only LRU pages have an access state, so only they count as present;
+                // free, slab, reserved etc. pages are told apart by page_types.
+                // https://github.com/torvalds/linux/blob/master/mm/page_idle.c#L18-L52
+                // https://www.kernel.org/doc/html/latest/admin-guide/mm/idle_page_tracking.html#implementation-details
+                let (present_page_add, active_page_add) = if pfn_flags & 1 << super::LRU_PAGE_BIT == 0 {
+                    (0, 0)
+                } else {
+                    (1 << super::PRESENT_PAGE_BIT,
+                     get_active_add(((segment.start_address / PAGE_SIZE) + pfn_offset) as u64, &idlemap))
+                };
+                // TODO: remove the pfn_flags != 0 check when we understand why some pages
+                // access fault into QEMU hw emulation on Xen. Maybe try GP?
+                let same_filled_add: u64 = match inspect_ram && *pfn_flags != 0 {
+                    true => match memory_data_memo.get_page_data(pfn_offset) {
+                        Ok(content) => {
+                            content_hashes[pfn_offset] = hash_page(content);
+                            let same_filled_add = same_filled_add(content, pfn_offset, &mut pattern_words);
+                            // Only idle anonymous LRU pages could go to zswap; buddy, slab
+                            // and reserved pages never look active but aren't candidates.
+                            let candidate = pfn_flags & 1 << super::LRU_PAGE_BIT != 0
+                                && pfn_flags & 1 << super::ANON_PAGE_BIT != 0;
+                            match (compressor, candidate, active_page_add | same_filled_add) {
+                                (Some(compressor), true, 0) => same_filled_add + compressor.bucket_add(content),
+                                _ => same_filled_add,
+                            }
+                        },
+                        Err(e) => panic!("Got error: {:?}", e),
+                    },
+                    false => 0
+                };
+                let ksm_page_add: u64 = match pfn_flags & 1 << super::KSM_PAGE_BIT {
+                    0 => 0,
+                    _ => 1 << super::MERGED_PAGE_BIT,
+                };
+                (pfn_flags & !(1 << super::ACTIVE_PAGE_BIT))
+                    + present_page_add
+                    + active_page_add
+                    + same_filled_add
+                    + ksm_page_add
+            }).collect();
             super::Segment {
                 addr_start: segment.start_address,
-                page_flags: kpageflags.iter().enumerate().map(|(pfn_offset, pfn_flags)| {
-                    // TODO: map and unset PRESENT big
-                    // https://github.com/torvalds/linux/blob/master/mm/page_idle.c#L18-L52
-                    // https://www.kernel.org/doc/html/latest/admin-guide/mm/idle_page_tracking.html#implementation-details
-                    let active_page_add = if pfn_flags & 1 << super::LRU_PAGE_BIT == 0 {
-                        0
-                    } else {
-                        get_active_add(((segment.start_address / PAGE_SIZE) + pfn_offset) as u64, &idlemap)
-                    };
-                    // TODO: remove the pfn_flags != 0 check when we understand why some pages
-                    // access fault into QEMU hw emulation on Xen. Maybe try GP?
-                    let zero_page_add: u64 = match inspect_ram && *pfn_flags != 0 {
-                        true => match memory_data_memo.get_page_data(pfn_offset) {
-                            Ok(content) => match content.iter().all(|&x| x == 0) {
-                                true => 1 << super::ZERO_PAGE_BIT,
-                                false => 0,
-                            },
-                            Err(e) => panic!("Got error: {:?}", e),
-                        },
-                        false => 0
-                    };
-                    (pfn_flags & !(1 << super::ACTIVE_PAGE_BIT))
-                        + active_page_add
-                        + zero_page_add
-                }).collect(),
+                path: segment.path.clone(),
+                page_flags: page_flags,
+                guest_mappings: Vec::new(),
+                page_types: kpageflags.iter().map(|&flags| super::PageType::from_kpageflags(flags)).collect(),
+                content_hashes: content_hashes,
+                pattern_words: pattern_words,
             }
         }).collect(),
     })
 }
 
-pub fn get_memory(pid: i32, sleep: u64) -> Result<super::ProcessMemory, std::io::Error> {
+pub fn get_memory(pid: i32, sleep: u64, compressor: Option<Compressor>) -> Result<super::ProcessMemory, std::io::Error> {
     //let nix_pid = Pid::from_raw(pid);
     //ptrace::attach(nix_pid);
     //wait::waitpid(nix_pid, None);
@@ -92,37 +122,54 @@ pub fn get_memory(pid: i32, sleep: u64) -> Result<super::ProcessMemory, std::io:
         segments : Vec::with_capacity(segments.len()),
     };
     let start_time = Utc::now();
+    let mut kpageflags = KpageflagsCache::new()?;
     for segment in segments {
         let pagemap: Vec<u64> = get_pagemap(pid, &segment)?;
         let mut memory_data_memo = MemoryDataMemo::new(pid, &segment, &pagemap)?;
         debug!("Pagemap for segment at {:x} with size {} has len {}", segment.start_address, segment.size, pagemap.len());
         //let all_page_data = get_page_content(pid, segment.start_address)?;
+        let mut content_hashes: Vec<u64> = vec![0; pagemap.len()];
+        let mut pattern_words: HashMap<usize, u64> = HashMap::new();
         let page_flags: Vec<u64> = pagemap.iter().enumerate().map(|(page_idx, pagemap_word)|
             if pagemap_word & 1 << 63 == 0 {
-                return pagemap_word.clone();
+                return Ok(pagemap_word.clone());
             } else {
                 if pagemap_word & 1 << 62 != 0 {
-                    return pagemap_word.clone();
+                    return Ok(pagemap_word.clone());
 
                 } else {
-                    let page_data: &[u8] = memory_data_memo.get_page_data(page_idx).unwrap();
+                    let page_data: &[u8] = memory_data_memo.get_page_data(page_idx)?;
+                    content_hashes[page_idx] = hash_page(page_data);
 
-                    let zero_page_add: u64 = match page_data.iter().all(|&x| x == 0) {
-                        true => 1 << super::ZERO_PAGE_BIT,
-                        false => 0,
-                    };
+                    let same_filled_add = same_filled_add(page_data, page_idx, &mut pattern_words);
 
                     // Bits 0-54  page frame number (PFN) if present
-                    let active_page_add = get_active_add(pagemap_word & 0x7FFFFFFFFFFFFF, &idlemap);
+                    let pfn = pagemap_word & 0x7FFFFFFFFFFFFF;
+                    let active_page_add = get_active_add(pfn, &idlemap);
+                    let ksm_page_add: u64 = match kpageflags.get(pfn)? & 1 << super::KSM_PAGE_BIT {
+                        0 => 0,
+                        _ => 1 << super::MERGED_PAGE_BIT,
+                    };
+                    // Only idle pages are candidates for zswap/zram, and same-filled
+                    // pages are stored without compressing them.
+                    let compression_add: u64 = match (compressor, active_page_add | same_filled_add) {
+                        (Some(compressor), 0) => compressor.bucket_add(page_data),
+                        _ => 0,
+                    };
                     // Zero the PFN; were going to use it to store other data resembling kpageflags
-                    return (pagemap_word & !0x7FFFFFFFFFFFFF)
-                            + zero_page_add + active_page_add;
+                    return Ok((pagemap_word & !0x7FFFFFFFFFFFFF)
+                            + same_filled_add + active_page_add + ksm_page_add + compression_add);
                 }
             }
-        ).collect();
+        ).collect::<io::Result<Vec<u64>>>()?;
         process_memory.segments.push(super::Segment {
             addr_start: segment.start_address,
+            path: segment.path.clone(),
             page_flags: page_flags,
+            guest_mappings: Vec::new(),
+            page_types: Vec::new(),
+            content_hashes: content_hashes,
+            pattern_words: pattern_words,
         });
     }
     debug!("Finished dumping segments in {} ms", (Utc::now() - start_time).num_milliseconds());
@@ -136,6 +183,95 @@ fn get_active_add(pfn: u64, idlemap: &[u8]) -> u64 {
     };
 }
 
+#[derive(Clone, Copy, Debug)]
+pub enum Compressor {
+    Lz4,
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+impl std::str::FromStr for Compressor {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Compressor, String> {
+        match s {
+            "lz4" => Ok(Compressor::Lz4),
+            #[cfg(feature = "zstd")]
+            "zstd" => Ok(Compressor::Zstd),
+            _ => Err(format!("Unsupported compressor: {}", s)),
+        }
+    }
+}
+
+impl Compressor {
+    // Estimate how well a page would compress in zswap/zram and encode the result
+    // as a bucket of eighths of a page in the compression bits of the page flags.
+    fn bucket_add(&self, page_data: &[u8]) -> u64 {
+        let compressed_size = match self {
+            Compressor::Lz4 => lz4::block::compress(page_data, None, false).unwrap().len(),
+            #[cfg(feature = "zstd")]
+            Compressor::Zstd => zstd::block::compress(page_data, 1).unwrap().len(),
+        };
+        let bucket = if compressed_size > PAGE_SIZE {
+            super::COMPRESSION_BUCKET_INCOMPRESSIBLE
+        } else {
+            max(((compressed_size * 8 + PAGE_SIZE - 1) / PAGE_SIZE) as u64, 1)
+        };
+        bucket << super::COMPRESSION_BUCKET_SHIFT
+    }
+}
+
+// Pages filled with a single repeating word are free to reclaim with zram/zswap
+// same-filled handling. Any 1, 2 or 4 byte pattern is also an 8 byte pattern, so
+// checking 8 byte words covers them all. Zero pages are flagged separately.
+fn same_filled_add(page_data: &[u8], page_offset: usize, pattern_words: &mut HashMap<usize, u64>) -> u64 {
+    let first_word = LittleEndian::read_u64(&page_data[0..8]);
+    if !page_data.chunks(8).all(|word| LittleEndian::read_u64(word) == first_word) {
+        return 0;
+    }
+    if first_word == 0 {
+        return 1 << super::ZERO_PAGE_BIT;
+    }
+    pattern_words.insert(page_offset, first_word);
+    1 << super::PATTERN_PAGE_BIT
+}
+
+fn hash_page(page_data: &[u8]) -> u64 {
+    let mut hasher = XxHash64::with_seed(0);
+    hasher.write(page_data);
+    hasher.finish()
+}
+
+// Random access to /proc/kpageflags for individual PFNs. A process's resident pages
+// tend to be clustered physically, so read and keep a chunk around each lookup.
+struct KpageflagsCache {
+    file: File,
+    chunks: HashMap<u64, Vec<u64>>,
+}
+
+impl KpageflagsCache {
+    fn new() -> std::io::Result<KpageflagsCache> {
+        Ok(KpageflagsCache {
+            file: File::open(KPAGEFLAGS_PATH)?,
+            chunks: HashMap::new(),
+        })
+    }
+
+    fn get(&mut self, pfn: u64) -> std::io::Result<u64> {
+        let chunk_idx = pfn / KPAGEFLAGS_CHUNK_PAGES;
+        if !self.chunks.contains_key(&chunk_idx) {
+            self.file.seek(SeekFrom::Start(chunk_idx * KPAGEFLAGS_CHUNK_PAGES * 8))?;
+            let mut data_bytes: Vec<u8> = Vec::with_capacity(KPAGEFLAGS_CHUNK_PAGES as usize * 8);
+            // May come up short at the end of physical memory.
+            (&mut self.file).take(KPAGEFLAGS_CHUNK_PAGES * 8).read_to_end(&mut data_bytes)?;
+            let mut data_words: Vec<u64> = vec![0; data_bytes.len() / 8];
+            LittleEndian::read_u64_into(&data_bytes[..data_words.len() * 8], &mut data_words);
+            self.chunks.insert(chunk_idx, data_words);
+        }
+        Ok(*self.chunks[&chunk_idx].get((pfn % KPAGEFLAGS_CHUNK_PAGES) as usize).unwrap_or(&0))
+    }
+}
+
 // Given an array slice of pagemap entries, where the starting element is a resident entry,
 // returns how long the contiguous segment of resident entries is.
 fn contiguous_mapped_length(pagemap: &[u64]) -> usize {
@@ -151,6 +287,7 @@ fn contiguous_mapped_length(pagemap: &[u64]) -> usize {
 struct Segment {
     pub start_address: usize,
     pub size: usize,
+    pub path: String,
 }
 
 struct MemoryDataMemo<'a> {
@@ -242,20 +379,26 @@ fn get_virtual_segments(pid: i32) -> Result<Vec<Segment>, io::Error> {
 
 fn get_physical_segments() -> Result<Vec<Segment>, io::Error> {
     let file = File::open("/proc/iomem")?;
-    Ok(parse_segment_addresses(
+    let mut segments = parse_segment_addresses(
             BufReader::new(file).lines()
             .map(|line| line.unwrap())
             .filter(|line| line.contains("System RAM"))
-            .collect()))
+            .collect());
+    for segment in &mut segments {
+        segment.path = "System RAM".to_string();
+    }
+    Ok(segments)
 }
 
 fn parse_segment_addresses(lines: Vec<String>) -> Vec<Segment> {
     let mut segments: Vec<Segment> = Vec::new();
     for line in lines {
         if let Ok((a, b)) = scan_fmt!(&line, "{x}-{x}", [hex usize], [hex usize]) {
+            // maps lines end with the pathname, which may itself contain spaces.
             segments.push(Segment {
                 start_address: a,
                 size: b - a + 1,
+                path: line.splitn(6, ' ').nth(5).unwrap_or("").trim().to_string(),
             })
         } else {
             error!("Unable to parse maps line: {}", line);
@@ -305,3 +448,33 @@ fn load_idlemap(physical_segments: &[Segment]) -> std::io::Result<Vec<u8>> {
     debug!("Idlemap of {} bytes loaded to vec with size {} in {} ms", read_counter, idlemap.len(), (Utc::now() - start_time).num_milliseconds());
     Ok(idlemap)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::RngCore;
+
+    fn bucket(page_data: &[u8]) -> u64 {
+        Compressor::Lz4.bucket_add(page_data) >> super::super::COMPRESSION_BUCKET_SHIFT
+    }
+
+    #[test]
+    fn compression_buckets() {
+        let mut page_data = vec![0u8; PAGE_SIZE];
+        assert_eq!(bucket(&page_data), 1);
+        // Random bytes don't compress; LZ4 adds framing on top.
+        rand::thread_rng().fill_bytes(&mut page_data);
+        assert_eq!(bucket(&page_data), super::super::COMPRESSION_BUCKET_INCOMPRESSIBLE);
+        // Half random, half zeroes: a little over 4/8 of a page.
+        for byte in &mut page_data[PAGE_SIZE / 2..] {
+            *byte = 0;
+        }
+        assert_eq!(bucket(&page_data), 5);
+        // An eighth of zeroes saves a little under an eighth, which rounds up to nothing saved.
+        rand::thread_rng().fill_bytes(&mut page_data);
+        for byte in &mut page_data[PAGE_SIZE * 7 / 8..] {
+            *byte = 0;
+        }
+        assert_eq!(bucket(&page_data), 8);
+    }
+}
@@ -0,0 +1,273 @@
+// Turn persisted snapshots into columnar tables for DataFrame tools. One row per
+// page per snapshot, or per block of pages when aggregating to huge-page or
+// 1 MiB granularity. Idle age is the number of consecutive snapshots a resident
+// page has been idle for, counting the current one.
+
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use std::collections::BTreeMap;
+
+use arrow::array::{ArrayRef, BooleanArray, TimestampSecondArray, UInt16Array, UInt32Array, UInt64Array};
+use arrow::datatypes::{DataType, Field, Schema, SchemaRef, TimeUnit};
+use arrow::ipc::writer::FileWriter;
+use arrow::record_batch::RecordBatch;
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::file::properties::WriterProperties;
+
+use super::persist;
+use super::statistics::IdleAges;
+use super::store::SnapshotStore;
+
+#[derive(Clone, Copy, Debug)]
+pub enum ExportFormat {
+    Parquet,
+    // Arrow IPC file
+    Arrow,
+}
+
+impl std::str::FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ExportFormat, String> {
+        match s {
+            "parquet" => Ok(ExportFormat::Parquet),
+            "arrow" => Ok(ExportFormat::Arrow),
+            _ => Err(format!("Unsupported export format: {}", s)),
+        }
+    }
+}
+
+// Size of the blocks rows are aggregated into; Page means no aggregation.
+#[derive(Clone, Copy, Debug)]
+pub enum Granularity {
+    Page,
+    OneMiB,
+    HugePage,
+}
+
+impl std::str::FromStr for Granularity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Granularity, String> {
+        match s {
+            "page" => Ok(Granularity::Page),
+            "1m" => Ok(Granularity::OneMiB),
+            "2m" => Ok(Granularity::HugePage),
+            _ => Err(format!("Unsupported granularity: {}", s)),
+        }
+    }
+}
+
+impl Granularity {
+    fn bytes(&self) -> usize {
+        match self {
+            Granularity::Page => 4096,
+            Granularity::OneMiB => 1 << 20,
+            Granularity::HugePage => 2 << 20,
+        }
+    }
+}
+
+enum TableWriter {
+    Parquet(ArrowWriter<File>),
+    Arrow(FileWriter<File>),
+}
+
+impl TableWriter {
+    fn create(path: &Path, format: ExportFormat, schema: SchemaRef) -> io::Result<TableWriter> {
+        let file = File::create(path)?;
+        Ok(match format {
+            ExportFormat::Parquet => {
+                let properties = WriterProperties::builder()
+                    .set_compression(Compression::SNAPPY)
+                    .build();
+                TableWriter::Parquet(ArrowWriter::try_new(file, schema, Some(properties)).map_err(export_error)?)
+            },
+            ExportFormat::Arrow => TableWriter::Arrow(FileWriter::try_new(file, &schema).map_err(export_error)?),
+        })
+    }
+
+    fn write(&mut self, batch: &RecordBatch) -> io::Result<()> {
+        match self {
+            TableWriter::Parquet(writer) => writer.write(batch).map_err(export_error),
+            TableWriter::Arrow(writer) => writer.write(batch).map_err(export_error),
+        }
+    }
+
+    fn close(self) -> io::Result<()> {
+        match self {
+            TableWriter::Parquet(writer) => writer.close().map(|_metadata| ()).map_err(export_error),
+            TableWriter::Arrow(mut writer) => writer.finish().map_err(export_error),
+        }
+    }
+}
+
+pub fn export(store: &dyn SnapshotStore, output: &Path, format: ExportFormat, granularity: Granularity) -> io::Result<()> {
+    let schema = match granularity {
+        Granularity::Page => page_schema(),
+        _ => block_schema(),
+    };
+    let mut writer = TableWriter::create(output, format, schema.clone())?;
+    let mut idle_ages = IdleAges::new();
+    let snapshots = persist::list_snapshots(store)?;
+    for (idx, timestamp) in snapshots.iter().enumerate() {
+        let memory = persist::read_process_memory(store, timestamp)?;
+        idle_ages.update(&memory);
+        for segment in &memory.segments {
+            let ages = idle_ages.ages(segment.addr_start);
+            let batch = match granularity {
+                Granularity::Page => page_batch(schema.clone(), memory.timestamp.timestamp(), segment, ages),
+                _ => block_batch(schema.clone(), memory.timestamp.timestamp(), segment, ages, granularity.bytes()),
+            }.map_err(export_error)?;
+            writer.write(&batch)?;
+        }
+        debug!("Exported {} ({}/{})", timestamp, idx + 1, snapshots.len());
+    }
+    writer.close()?;
+    info!("Exported {} snapshots to {}", snapshots.len(), output.display());
+    Ok(())
+}
+
+fn timestamp_field() -> Field {
+    Field::new("timestamp", DataType::Timestamp(TimeUnit::Second, Some("UTC".into())), false)
+}
+
+fn page_schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        timestamp_field(),
+        Field::new("segment", DataType::UInt64, false),
+        Field::new("page_offset", DataType::UInt64, false),
+        Field::new("present", DataType::Boolean, false),
+        Field::new("swapped", DataType::Boolean, false),
+        Field::new("active", DataType::Boolean, false),
+        Field::new("zero", DataType::Boolean, false),
+        // Null where the page contents weren't read
+        Field::new("hash", DataType::UInt64, true),
+        Field::new("idle_age", DataType::UInt16, false),
+    ]))
+}
+
+// Counts of pages per block, rather than flags.
+fn block_schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        timestamp_field(),
+        Field::new("segment", DataType::UInt64, false),
+        // Virtual address of the block, aligned to the block size
+        Field::new("block_address", DataType::UInt64, false),
+        Field::new("pages", DataType::UInt32, false),
+        Field::new("present", DataType::UInt32, false),
+        Field::new("swapped", DataType::UInt32, false),
+        Field::new("active", DataType::UInt32, false),
+        Field::new("zero", DataType::UInt32, false),
+        // A block is only as cold as its most recently used resident page; null
+        // if none are resident.
+        Field::new("min_idle_age", DataType::UInt16, true),
+    ]))
+}
+
+fn flag_column(page_flags: &[u64], bit: u8) -> ArrayRef {
+    Arc::new(BooleanArray::from(page_flags.iter().map(|flags| flags & (1 << bit) != 0).collect::<Vec<bool>>()))
+}
+
+fn page_batch(schema: SchemaRef, timestamp: i64, segment: &super::Segment, ages: &[u16]) -> arrow::error::Result<RecordBatch> {
+    let pages = segment.page_flags.len();
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(TimestampSecondArray::from(vec![timestamp; pages]).with_timezone("UTC")),
+        Arc::new(UInt64Array::from(vec![segment.addr_start as u64; pages])),
+        Arc::new(UInt64Array::from((0..pages as u64).collect::<Vec<u64>>())),
+        flag_column(&segment.page_flags, super::PRESENT_PAGE_BIT),
+        flag_column(&segment.page_flags, super::SWAPPED_PAGE_BIT),
+        flag_column(&segment.page_flags, super::ACTIVE_PAGE_BIT),
+        flag_column(&segment.page_flags, super::ZERO_PAGE_BIT),
+        Arc::new(UInt64Array::from((0..pages)
+            .map(|idx| segment.content_hashes.get(idx).cloned().filter(|&hash| hash != 0))
+            .collect::<Vec<Option<u64>>>())),
+        Arc::new(UInt16Array::from((0..pages)
+            .map(|idx| ages.get(idx).cloned().unwrap_or(0))
+            .collect::<Vec<u16>>())),
+    ];
+    RecordBatch::try_new(schema, columns)
+}
+
+#[derive(Default)]
+struct BlockCounts {
+    pages: u32,
+    present: u32,
+    swapped: u32,
+    active: u32,
+    zero: u32,
+    min_idle_age: Option<u16>,
+}
+
+fn block_batch(schema: SchemaRef, timestamp: i64, segment: &super::Segment, ages: &[u16], block_bytes: usize) -> arrow::error::Result<RecordBatch> {
+    let mut blocks: BTreeMap<u64, BlockCounts> = BTreeMap::new();
+    for (idx, page_flags) in segment.page_flags.iter().enumerate() {
+        let address = segment.addr_start + idx * 4096;
+        let block = blocks.entry((address - address % block_bytes) as u64).or_insert_with(BlockCounts::default);
+        let set = |bit: u8| (page_flags & (1 << bit) != 0) as u32;
+        block.pages += 1;
+        block.present += set(super::PRESENT_PAGE_BIT);
+        block.swapped += set(super::SWAPPED_PAGE_BIT);
+        block.active += set(super::ACTIVE_PAGE_BIT);
+        block.zero += set(super::ZERO_PAGE_BIT);
+        // Pages which aren't present have age 0 without having been used.
+        if page_flags & (1 << super::PRESENT_PAGE_BIT) != 0 {
+            let age = ages.get(idx).cloned().unwrap_or(0);
+            block.min_idle_age = Some(block.min_idle_age.map_or(age, |min_age| std::cmp::min(min_age, age)));
+        }
+    }
+    let rows = blocks.len();
+    let column = |f: &dyn Fn(&BlockCounts) -> u32| -> ArrayRef {
+        Arc::new(UInt32Array::from(blocks.values().map(f).collect::<Vec<u32>>()))
+    };
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(TimestampSecondArray::from(vec![timestamp; rows]).with_timezone("UTC")),
+        Arc::new(UInt64Array::from(vec![segment.addr_start as u64; rows])),
+        Arc::new(UInt64Array::from(blocks.keys().cloned().collect::<Vec<u64>>())),
+        column(&|block| block.pages),
+        column(&|block| block.present),
+        column(&|block| block.swapped),
+        column(&|block| block.active),
+        column(&|block| block.zero),
+        Arc::new(UInt16Array::from(blocks.values()
+            .map(|block| block.min_idle_age)
+            .collect::<Vec<Option<u16>>>())),
+    ];
+    RecordBatch::try_new(schema, columns)
+}
+
+fn export_error<E: std::fmt::Debug>(error: E) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, format!("Export error: {:?}", error))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow::array::Array;
+
+    #[test]
+    fn min_idle_age_ignores_pages_which_are_not_present() {
+        let present = 1 << super::super::PRESENT_PAGE_BIT;
+        let active = present | 1 << super::super::ACTIVE_PAGE_BIT;
+        // Four 4-page blocks: idle, partly resident, in use, and not resident at all.
+        let page_flags = vec![present, present, present, present,
+                              0, present, 0, present,
+                              active, present, 0, 0,
+                              0, 0, 0, 0];
+        let ages = vec![3, 4, 5, 6,
+                        0, 7, 0, 2,
+                        0, 1, 0, 0,
+                        0, 0, 0, 0];
+        let segment = super::super::Segment::from_page_flags(0, page_flags);
+        let batch = block_batch(block_schema(), 0, &segment, &ages, 4 * 4096).unwrap();
+        let min_idle_age = batch.column(8).as_any().downcast_ref::<UInt16Array>().unwrap();
+        assert_eq!(min_idle_age.len(), 4);
+        assert_eq!((min_idle_age.value(0), min_idle_age.value(1), min_idle_age.value(2)), (3, 2, 0));
+        assert!(min_idle_age.is_null(3));
+        let present_pages = batch.column(4).as_any().downcast_ref::<UInt32Array>().unwrap();
+        assert_eq!(present_pages.value(3), 0);
+    }
+}
@@ -6,6 +6,9 @@ extern crate chrono;
 extern crate rand;
 extern crate sysinfo;
 extern crate lz4;
+extern crate twox_hash;
+#[cfg(feature = "zstd")]
+extern crate zstd;
 
 #[macro_use]
 extern crate json;
@@ -13,20 +16,130 @@ extern crate json;
 #[macro_use]
 extern crate log;
 
+#[macro_use]
+extern crate nix;
+
 pub mod statistics;
 pub mod dump;
 pub mod persist;
+pub mod store;
+pub mod upload;
+pub mod archive;
+#[cfg(feature = "export")]
+pub mod export;
+pub mod diff;
+pub mod locality;
+pub mod metrics;
+pub mod push;
+pub mod render;
+pub mod report;
+pub mod top;
 pub mod vmm;
 
 use chrono::{DateTime, Utc};
+use std::collections::HashMap;
 
 // https://www.kernel.org/doc/Documentation/vm/pagemap.txt
 pub const LRU_PAGE_BIT: u8 = 5;
 pub const PRESENT_PAGE_BIT: u8 = 63;
+pub const SWAPPED_PAGE_BIT: u8 = 62;
 // We're going to steal bits from the PFN (0-54) of the /proc/pid/pagemap,
 // while using the same bits of /proc/kpageflags
 pub const ZERO_PAGE_BIT: u8 = 57;
 pub const ACTIVE_PAGE_BIT: u8 = 58;
+// Page is filled with one repeating non-zero word, kept in Segment::pattern_words.
+pub const PATTERN_PAGE_BIT: u8 = 59;
+// Page is merged by KSM. Copied from kpageflags' KSM bit, which can't be kept in
+// place: on a swapped page bits 5-54 are the swap offset.
+pub const MERGED_PAGE_BIT: u8 = 60;
+// Estimated compressed size of idle pages, in eighths of a page, rounded up: 1-8.
+// 0 means not estimated, and COMPRESSION_BUCKET_INCOMPRESSIBLE that compressing
+// made the page bigger, so zswap/zram would store it as is.
+pub const COMPRESSION_BUCKET_SHIFT: u8 = 48;
+pub const COMPRESSION_BUCKET_MASK: u64 = 0xf;
+pub const COMPRESSION_BUCKET_INCOMPRESSIBLE: u64 = 9;
+
+// /proc/kpageflags bits used to classify host pages.
+// https://www.kernel.org/doc/Documentation/admin-guide/mm/pagemap.rst
+pub const SLAB_PAGE_BIT: u8 = 7;
+pub const BUDDY_PAGE_BIT: u8 = 10;
+pub const MMAP_PAGE_BIT: u8 = 11;
+pub const ANON_PAGE_BIT: u8 = 12;
+pub const SWAPCACHE_PAGE_BIT: u8 = 13;
+pub const NOPAGE_PAGE_BIT: u8 = 20;
+pub const KSM_PAGE_BIT: u8 = 21;
+// Renamed KPF_OFFLINE in 5.0; balloon drivers are what set it.
+pub const BALLOON_PAGE_BIT: u8 = 23;
+pub const RESERVED_PAGE_BIT: u8 = 32;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum PageType {
+    // No flags at all, or no struct page behind the PFN.
+    NoPage,
+    Buddy,
+    Reserved,
+    Balloon,
+    Slab,
+    Ksm,
+    SwapCache,
+    Anon,
+    // Mapped file pages
+    Mmap,
+    // Unmapped page cache
+    File,
+    Other,
+}
+
+impl PageType {
+    pub const ALL: [PageType; 11] = [
+        PageType::NoPage, PageType::Buddy, PageType::Reserved, PageType::Balloon,
+        PageType::Slab, PageType::Ksm, PageType::SwapCache, PageType::Anon,
+        PageType::Mmap, PageType::File, PageType::Other];
+
+    // Order matters: a KSM page is also anon, a swapcache page may also be anon, etc.
+    pub fn from_kpageflags(flags: u64) -> PageType {
+        let set = |bit: u8| flags & (1 << bit) != 0;
+        if flags == 0 || set(NOPAGE_PAGE_BIT) {
+            PageType::NoPage
+        } else if set(BUDDY_PAGE_BIT) {
+            PageType::Buddy
+        } else if set(RESERVED_PAGE_BIT) {
+            PageType::Reserved
+        } else if set(BALLOON_PAGE_BIT) {
+            PageType::Balloon
+        } else if set(SLAB_PAGE_BIT) {
+            PageType::Slab
+        } else if set(KSM_PAGE_BIT) {
+            PageType::Ksm
+        } else if set(SWAPCACHE_PAGE_BIT) {
+            PageType::SwapCache
+        } else if set(ANON_PAGE_BIT) {
+            PageType::Anon
+        } else if set(MMAP_PAGE_BIT) {
+            PageType::Mmap
+        } else if set(LRU_PAGE_BIT) {
+            PageType::File
+        } else {
+            PageType::Other
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            PageType::NoPage => "nopage",
+            PageType::Buddy => "buddy",
+            PageType::Reserved => "reserved",
+            PageType::Balloon => "balloon",
+            PageType::Slab => "slab",
+            PageType::Ksm => "ksm",
+            PageType::SwapCache => "swapcache",
+            PageType::Anon => "anon",
+            PageType::Mmap => "mmap",
+            PageType::File => "file",
+            PageType::Other => "other",
+        }
+    }
+}
 
 pub struct ProcessMemory {
     pub timestamp: DateTime<Utc>,
@@ -37,7 +150,83 @@ pub struct ProcessMemory {
 
 pub struct Segment {
     pub addr_start: usize,
+    // Pathname from /proc/pid/maps, e.g. "/usr/lib/libc.so.6" or "[heap]"; empty
+    // for anonymous mappings. "System RAM" in host mode.
+    pub path: String,
     // For now these flags are just what we get back from /proc/pid/pagemap
     // OR /proc/kpageflags. We may want to standardize bits at some point...
-    pub page_flags: Vec<u64>
+    pub page_flags: Vec<u64>,
+    // Where this segment appears in the guest's physical address space, if it
+    // backs guest RAM. Filled in by the VMM integration; empty otherwise.
+    pub guest_mappings: Vec<GuestMapping>,
+    // Host mode only: classification of each PFN by its kpageflags. Empty for processes.
+    pub page_types: Vec<PageType>,
+    // xxHash64 of each page's contents where we read it, 0 otherwise.
+    pub content_hashes: Vec<u64>,
+    // Page offset -> repeating word, for pages with PATTERN_PAGE_BIT set.
+    pub pattern_words: HashMap<usize, u64>,
+}
+
+// A contiguous guest-physical range backed by part of a host segment.
+// One QEMU RAM block is often split around the PCI hole, so a segment may have several.
+pub struct GuestMapping {
+    // Byte offset from the start of the segment
+    pub segment_offset: usize,
+    pub guest_phys_addr: usize,
+    pub size: usize,
+}
+
+impl Segment {
+    // A segment with nothing but page flags, e.g. as read back from a snapshot.
+    pub fn from_page_flags(addr_start: usize, page_flags: Vec<u64>) -> Segment {
+        Segment {
+            addr_start: addr_start,
+            path: String::new(),
+            page_flags: page_flags,
+            guest_mappings: Vec::new(),
+            page_types: Vec::new(),
+            content_hashes: Vec::new(),
+            pattern_words: HashMap::new(),
+        }
+    }
+
+    // Guest-physical address of the given page, if that page is visible to the guest.
+    pub fn guest_phys_addr(&self, page_offset: usize) -> Option<usize> {
+        let offset = page_offset * 4096;
+        self.guest_mappings.iter()
+            .find(|m| offset >= m.segment_offset && offset < m.segment_offset + m.size)
+            .map(|m| m.guest_phys_addr + (offset - m.segment_offset))
+    }
+
+    // Coarse classification for reports: guest-ram, ram (host mode), anon, file,
+    // or a special mapping's own name such as "[heap]" or "[stack]".
+    pub fn kind(&self) -> &str {
+        if !self.guest_mappings.is_empty() {
+            "guest-ram"
+        } else if self.path == "System RAM" {
+            "ram"
+        } else if self.path.is_empty() {
+            "anon"
+        } else if self.path.starts_with('[') {
+            &self.path
+        } else {
+            "file"
+        }
+    }
+}
+
+// Fixtures shared by the module tests.
+#[cfg(test)]
+mod testing {
+    use chrono::{TimeZone, Utc};
+
+    // A sample taken secs after the epoch, with one segment per (address, page flags).
+    pub fn memory(secs: i64, segments: Vec<(usize, Vec<u64>)>) -> super::ProcessMemory {
+        super::ProcessMemory {
+            timestamp: Utc.timestamp_opt(secs, 0).unwrap(),
+            segments: segments.into_iter()
+                .map(|(addr_start, page_flags)| super::Segment::from_page_flags(addr_start, page_flags))
+                .collect(),
+        }
+    }
 }
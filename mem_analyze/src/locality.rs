@@ -0,0 +1,199 @@
+// How idle memory is laid out, not just how much there is. Reclaim at huge-page
+// granularity only pays off if idle pages come in 2 MiB-aligned, fully idle
+// blocks; lots of short idle runs between active pages mean it doesn't.
+// A page is idle if it's present and wasn't accessed; pages which aren't present
+// end both active and idle runs.
+
+const HUGE_PAGE_PAGES: usize = 512;
+const HUGE_PAGE_SIZE: usize = HUGE_PAGE_PAGES * 4096;
+
+// Run lengths, in pages, are bucketed as 1, 2-7, 8-63, 64-511 and 512+.
+pub const RUN_BUCKETS: usize = 5;
+pub const RUN_BUCKET_NAMES: [&str; RUN_BUCKETS] = ["1", "2_7", "8_63", "64_511", "512_plus"];
+
+#[derive(Clone, Debug, Default)]
+pub struct RunLengths {
+    // Number of runs per bucket
+    pub buckets: [u64; RUN_BUCKETS],
+    pub runs: u64,
+    pub pages: u64,
+    pub longest: u64,
+}
+
+impl RunLengths {
+    fn add(&mut self, length: u64) {
+        if length == 0 {
+            return;
+        }
+        // Past the first, buckets grow by a factor of 8: 2-7 -> 1, 8-63 -> 2, ...
+        let bucket = match length {
+            1 => 0,
+            _ => 1 + (63 - length.leading_zeros()) as usize / 3,
+        };
+        self.buckets[std::cmp::min(bucket, RUN_BUCKETS - 1)] += 1;
+        self.runs += 1;
+        self.pages += length;
+        self.longest = std::cmp::max(self.longest, length);
+    }
+
+    fn merge(&mut self, other: &RunLengths) {
+        for (bucket, count) in self.buckets.iter_mut().zip(other.buckets.iter()) {
+            *bucket += count;
+        }
+        self.runs += other.runs;
+        self.pages += other.pages;
+        self.longest = std::cmp::max(self.longest, other.longest);
+    }
+
+    pub fn mean(&self) -> f64 {
+        if self.runs == 0 { 0.0 } else { self.pages as f64 / self.runs as f64 }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Locality {
+    pub active: RunLengths,
+    pub idle: RunLengths,
+    // Idle pages in 2 MiB-aligned blocks where every page is idle
+    pub huge_idle_pages: u64,
+}
+
+impl Locality {
+    pub fn of_segment(segment: &super::Segment) -> Locality {
+        let mut locality = Locality::default();
+        let mut active_run = 0;
+        let mut idle_run = 0;
+        for &page_flags in &segment.page_flags {
+            if is_idle(page_flags) {
+                idle_run += 1;
+                locality.active.add(active_run);
+                active_run = 0;
+            } else if page_flags & (1 << super::ACTIVE_PAGE_BIT) != 0 {
+                active_run += 1;
+                locality.idle.add(idle_run);
+                idle_run = 0;
+            } else {
+                locality.active.add(active_run);
+                locality.idle.add(idle_run);
+                active_run = 0;
+                idle_run = 0;
+            }
+        }
+        locality.active.add(active_run);
+        locality.idle.add(idle_run);
+
+        // Only whole blocks inside the segment count.
+        let segment_end = segment.addr_start + segment.page_flags.len() * 4096;
+        let mut block = (segment.addr_start + HUGE_PAGE_SIZE - 1) & !(HUGE_PAGE_SIZE - 1);
+        while block + HUGE_PAGE_SIZE <= segment_end {
+            let first_page = (block - segment.addr_start) / 4096;
+            if segment.page_flags[first_page..first_page + HUGE_PAGE_PAGES].iter().all(|&flags| is_idle(flags)) {
+                locality.huge_idle_pages += HUGE_PAGE_PAGES as u64;
+            }
+            block += HUGE_PAGE_SIZE;
+        }
+        locality
+    }
+
+    // One per segment, in order; computed once per sample and shared by the
+    // totals and the segment rows.
+    pub fn of_segments(memory: &super::ProcessMemory) -> Vec<Locality> {
+        memory.segments.iter().map(Locality::of_segment).collect()
+    }
+
+    pub fn merge(&mut self, other: &Locality) {
+        self.active.merge(&other.active);
+        self.idle.merge(&other.idle);
+        self.huge_idle_pages += other.huge_idle_pages;
+    }
+
+    // 0 when all idle memory is one run, approaching 1 as it splinters.
+    pub fn idle_fragmentation(&self) -> f64 {
+        if self.idle.pages == 0 { 0.0 } else { 1.0 - self.idle.longest as f64 / self.idle.pages as f64 }
+    }
+
+    // Share of idle memory which huge-page-granularity reclaim could take.
+    pub fn huge_idle_fraction(&self) -> f64 {
+        if self.idle.pages == 0 { 0.0 } else { self.huge_idle_pages as f64 / self.idle.pages as f64 }
+    }
+}
+
+fn is_idle(page_flags: u64) -> bool {
+    page_flags & (1 << super::PRESENT_PAGE_BIT) != 0 && page_flags & (1 << super::ACTIVE_PAGE_BIT) == 0
+}
+
+// Whole-sample summary of Locality::of_segments, logged alongside the page counts.
+pub fn locality_analytics(localities: &[Locality]) -> Locality {
+    let mut total = Locality::default();
+    for locality in localities {
+        total.merge(locality);
+    }
+    info!("Active runs: {} (mean {:.1} pages), idle runs: {} (mean {:.1} pages, longest {})",
+          total.active.runs, total.active.mean(), total.idle.runs, total.idle.mean(), total.idle.longest);
+    info!("Idle run lengths (pages): {}", RUN_BUCKET_NAMES.iter().zip(total.idle.buckets.iter())
+          .map(|(name, count)| format!("{}: {}", name.replace("_plus", "+").replace('_', "-"), count))
+          .collect::<Vec<String>>()
+          .join(", "));
+    info!("Idle fragmentation {:.2}; {:.1}% of idle memory is in fully idle 2 MiB blocks",
+          total.idle_fragmentation(), 100.0 * total.huge_idle_fraction());
+    total
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::Segment;
+
+    const IDLE: u64 = 1 << super::super::PRESENT_PAGE_BIT;
+    const ACTIVE: u64 = IDLE | 1 << super::super::ACTIVE_PAGE_BIT;
+
+    #[test]
+    fn bucket_boundaries() {
+        for &(length, bucket) in &[(1, 0), (2, 1), (7, 1), (8, 2), (63, 2), (64, 3), (511, 3), (512, 4), (100_000, 4)] {
+            let mut runs = RunLengths::default();
+            runs.add(length);
+            let mut expected = [0; RUN_BUCKETS];
+            expected[bucket] = 1;
+            assert_eq!(runs.buckets, expected, "run of {}", length);
+        }
+        let mut runs = RunLengths::default();
+        runs.add(0);
+        assert_eq!(runs.runs, 0);
+    }
+
+    #[test]
+    fn non_present_pages_split_runs() {
+        let mut page_flags = vec![IDLE; 3];
+        page_flags.push(0);
+        page_flags.extend(vec![IDLE; 2]);
+        page_flags.extend(vec![ACTIVE; 4]);
+        page_flags.push(0);
+        page_flags.push(ACTIVE);
+        page_flags.push(0);
+        let locality = Locality::of_segment(&Segment::from_page_flags(0x1000, page_flags));
+        assert_eq!((locality.idle.runs, locality.idle.pages, locality.idle.longest), (2, 5, 3));
+        assert_eq!(locality.idle.buckets, [0, 2, 0, 0, 0]);
+        assert_eq!((locality.active.runs, locality.active.pages, locality.active.longest), (2, 5, 4));
+        assert_eq!(locality.active.buckets, [1, 1, 0, 0, 0]);
+        assert!((locality.idle_fragmentation() - 0.4).abs() < 1e-9);
+    }
+
+    #[test]
+    fn huge_blocks_are_aligned() {
+        // Starts a page before a 2 MiB boundary and covers two more blocks, the
+        // last of which is cut short.
+        let addr_start = 0x40000000 - 4096;
+        let mut page_flags = vec![IDLE; 1 + 2 * HUGE_PAGE_PAGES - 1];
+        let locality = Locality::of_segment(&Segment::from_page_flags(addr_start, page_flags.clone()));
+        assert_eq!(locality.huge_idle_pages, HUGE_PAGE_PAGES as u64);
+        assert_eq!(locality.idle.longest, page_flags.len() as u64);
+
+        // An active last page rules the only whole block out, even though the
+        // unaligned span before it is a run of 512 idle pages.
+        page_flags[HUGE_PAGE_PAGES] = ACTIVE;
+        let locality = Locality::of_segment(&Segment::from_page_flags(addr_start, page_flags));
+        assert_eq!(locality.huge_idle_pages, 0);
+        assert_eq!(locality.idle.longest, HUGE_PAGE_PAGES as u64);
+        assert_eq!(locality.huge_idle_fraction(), 0.0);
+    }
+}
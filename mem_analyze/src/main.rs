@@ -9,20 +9,35 @@ extern crate telnet;
 extern crate log;
 
 use std::env;
+use std::fs::File;
 use simplelog::*;
-use chrono::Utc;
-use clap::{Arg, App};
+use chrono::{Duration, Utc};
+use clap::{Arg, App, ArgMatches, SubCommand};
+use std::path::{Path, PathBuf};
+use mem_analyze::store::{SnapshotStore, LocalStore, S3Store, S3Config};
+use mem_analyze::upload::UploadQueue;
+use mem_analyze::archive::ArchiveStore;
+use mem_analyze::persist::{Persister, RetentionPolicy};
+use mem_analyze::statistics::{StatsFormat, StatsWriter, StatsRecord, SegmentRecord, IdleAges, AccessHistory, WssEstimator};
+use mem_analyze::locality::Locality;
+use mem_analyze::metrics::Metrics;
+use mem_analyze::push::{Pusher, PushProtocol};
+use mem_analyze::render::RenderOptions;
+use mem_analyze::report::ReportOptions;
+use mem_analyze::top::TopOptions;
 
 const SLEEP_TIME: u64 = 10;
+const OUTPUT_DIR: &str = "/tmp/wss";
+const UPLOAD_QUEUE_BYTES: usize = 64 << 20;
+const UPLOAD_RETRIES: u32 = 5;
+const WSS_WINDOW: usize = 60;
+const HISTORY_WINDOW: u32 = 16;
+const WSS_HEADROOM_PERCENT: f64 = 10.0;
+// Where the secret for --s3-access-key comes from without --s3-secret-key-file.
+const S3_SECRET_KEY_ENV: &str = "WSS_S3_SECRET_KEY";
 
 fn main() -> std::io::Result<()> {
 
-    CombinedLogger::init(
-        vec![
-            TermLogger::new(LevelFilter::Debug, Config::default()).unwrap(),
-        ]
-    ).unwrap();
-
     let matches = App::new("MemAnalyze")
         .version("0.1")
         .author("jgowans")
@@ -42,21 +57,302 @@ fn main() -> std::io::Result<()> {
         .arg(Arg::with_name("pageout")
              .long("pageout")
              .takes_value(true))
+        .arg(Arg::with_name("pageout-addresses")
+             .long("pageout-addresses")
+             .takes_value(true)
+             .possible_values(&["host", "guest-physical"])
+             .default_value("host")
+             .help("Address space of the pages sent to QEMU's pageout_pages"))
         .arg(Arg::with_name("inspect-ram")
              .short("i")
              .long("inspect-ram")
              .multiple(true))
+        .arg(Arg::with_name("compressibility")
+             .long("compressibility")
+             .takes_value(true)
+             .validator(|compressor| compressor.parse::<mem_analyze::dump::Compressor>().map(|_compressor| ()))
+             .help("Estimate how idle pages compress: lz4, or zstd if built with it"))
         .arg(Arg::with_name("s3-persist")
              .long("s3")
-             .multiple(true))
+             .multiple(true)
+             .help("Shorthand for --store s3"))
+        .arg(Arg::with_name("s3-endpoint")
+             .long("s3-endpoint")
+             .takes_value(true)
+             .help("Custom endpoint URL for S3-compatible stores"))
+        .arg(Arg::with_name("s3-bucket")
+             .long("s3-bucket")
+             .takes_value(true))
+        .arg(Arg::with_name("s3-prefix")
+             .long("s3-prefix")
+             .takes_value(true)
+             .help("Key prefix [default: hostname]"))
+        .arg(Arg::with_name("s3-access-key")
+             .long("s3-access-key")
+             .takes_value(true)
+             .help("Access key id; the secret key is read from --s3-secret-key-file or $WSS_S3_SECRET_KEY"))
+        .arg(Arg::with_name("s3-secret-key-file")
+             .long("s3-secret-key-file")
+             .takes_value(true)
+             .requires("s3-access-key")
+             .help("File holding the secret key for --s3-access-key"))
+        .arg(Arg::with_name("spool-dir")
+             .long("spool-dir")
+             .takes_value(true)
+             .help("Where uploads wait when they can't be sent"))
+        .arg(Arg::with_name("upload-queue-mb")
+             .long("upload-queue-mb")
+             .takes_value(true)
+             .help("Memory for queued uploads before spooling to disk"))
+        .arg(Arg::with_name("upload-retries")
+             .long("upload-retries")
+             .takes_value(true))
+        .arg(Arg::with_name("keyframe-interval")
+             .long("keyframe-interval")
+             .takes_value(true)
+             .help("Persist a full snapshot every N, deltas in between [default: 1]"))
+        .arg(Arg::with_name("output-dir")
+             .long("output-dir")
+             .takes_value(true)
+             .help("Root for local snapshots, stats and the spool [default: /tmp/wss]"))
+        .arg(Arg::with_name("retention-max-age")
+             .long("retention-max-age")
+             .takes_value(true)
+             .help("Hours of local snapshots, and of each stats file, to keep"))
+        .arg(Arg::with_name("retention-max-mb")
+             .long("retention-max-mb")
+             .takes_value(true)
+             .help("Total size of local snapshots, and of each stats file, to keep"))
+        .arg(Arg::with_name("retention-keep-every")
+             .long("retention-keep-every")
+             .takes_value(true)
+             .help("Only keep every Nth snapshot once older than --retention-downsample-after"))
+        .arg(Arg::with_name("retention-downsample-after")
+             .long("retention-downsample-after")
+             .takes_value(true)
+             .requires("retention-keep-every")
+             .help("Hours before snapshots are downsampled [default: 0]"))
+        .arg(Arg::with_name("archive")
+             .long("archive")
+             .takes_value(true)
+             .help("File for --store archive [default: <output-dir>/<pid>.wssa]"))
+        .arg(Arg::with_name("store")
+             .long("store")
+             .takes_value(true)
+             .multiple(true)
+             .possible_values(&["local", "s3", "archive"])
+             .help("Where to persist snapshots; may be repeated [default: local]"))
+        .arg(Arg::with_name("stats-output")
+             .long("stats-output")
+             .takes_value(true)
+             .help("File statistics are appended to [default: <output-dir>/<pid>.<format>]"))
+        .arg(Arg::with_name("stats-format")
+             .long("stats-format")
+             .takes_value(true)
+             .possible_values(&["csv", "jsonl"])
+             .default_value("csv"))
+        .arg(Arg::with_name("segment-stats-output")
+             .long("segment-stats-output")
+             .takes_value(true)
+             .help("File per-segment statistics are appended to [default: <output-dir>/<pid>.segments.<format>]"))
+        .arg(Arg::with_name("history-window")
+             .long("history-window")
+             .takes_value(true)
+             .help("Samples of per-page access history kept for hot/warm/cold tiers, at most 64 [default: 16]"))
+        .arg(Arg::with_name("hot-threshold")
+             .long("hot-threshold")
+             .takes_value(true)
+             .help("Samples a page must be active in to be hot [default: half the history window]"))
+        .arg(Arg::with_name("wss-window")
+             .long("wss-window")
+             .takes_value(true)
+             .help("Samples in the rolling WSS estimate [default: 60]"))
+        .arg(Arg::with_name("wss-headroom")
+             .long("wss-headroom")
+             .takes_value(true)
+             .help("Percent added to p95 WSS for the recommended size [default: 10]"))
+        .arg(Arg::with_name("metrics-listen")
+             .long("metrics-listen")
+             .takes_value(true)
+             .help("Serve Prometheus metrics at http://<addr>/metrics, e.g. 0.0.0.0:9100"))
+        .arg(Arg::with_name("push")
+             .long("push")
+             .takes_value(true)
+             .possible_values(&["statsd", "otlp"])
+             .requires("push-endpoint")
+             .help("Push statistics each iteration"))
+        .arg(Arg::with_name("push-endpoint")
+             .long("push-endpoint")
+             .takes_value(true)
+             .help("host:port for statsd, http://host[:port][/path] for otlp"))
+        .subcommand(SubCommand::with_name("export")
+             .about("Export persisted snapshots as Parquet or Arrow IPC tables (needs the export feature)")
+             .arg(Arg::with_name("input")
+                  .required(true)
+                  .help("Snapshot directory, e.g. /tmp/wss/<pid>, or archive file"))
+             .arg(Arg::with_name("output")
+                  .required(true))
+             .arg(Arg::with_name("format")
+                  .long("format")
+                  .takes_value(true)
+                  .possible_values(&["parquet", "arrow"])
+                  .default_value("parquet"))
+             .arg(Arg::with_name("granularity")
+                  .long("granularity")
+                  .takes_value(true)
+                  .possible_values(&["page", "1m", "2m"])
+                  .default_value("page")
+                  .help("Aggregate pages into blocks of this size")))
+        .subcommand(SubCommand::with_name("render")
+             .about("Draw page map frames, an animation and a chart of persisted snapshots")
+             .arg(Arg::with_name("input")
+                  .required(true)
+                  .help("Snapshot directory, e.g. /tmp/wss/<pid>, or archive file"))
+             .arg(Arg::with_name("output")
+                  .required(true)
+                  .help("Directory for frame-NNN.png, animation.gif or animation.png, and chart.png"))
+             .arg(Arg::with_name("animation")
+                  .long("animation")
+                  .takes_value(true)
+                  .possible_values(&["gif", "apng", "none"])
+                  .default_value("gif"))
+             .arg(Arg::with_name("no-frames")
+                  .long("no-frames")
+                  .help("Don't write each frame as a PNG"))
+             .arg(Arg::with_name("frame-delay-ms")
+                  .long("frame-delay-ms")
+                  .takes_value(true)
+                  .default_value("1000"))
+             .arg(Arg::with_name("max-size")
+                  .long("max-size")
+                  .takes_value(true)
+                  .default_value("1024")
+                  .help("Largest frame side in pixels; larger page maps are scaled down")))
+        .subcommand(SubCommand::with_name("report")
+             .about("Write a self-contained HTML report of a collection run")
+             .arg(Arg::with_name("input")
+                  .required(true)
+                  .help("Snapshot directory, e.g. /tmp/wss/<pid>, or archive file"))
+             .arg(Arg::with_name("output")
+                  .required(true)
+                  .help("HTML file to write"))
+             .arg(Arg::with_name("stats")
+                  .long("stats")
+                  .takes_value(true)
+                  .help("Statistics file written with --stats-output [default: <input>.csv or <input>.jsonl if present, \
+                         otherwise the statistics kept with each snapshot]"))
+             .arg(Arg::with_name("page-maps")
+                  .long("page-maps")
+                  .takes_value(true)
+                  .default_value("8")
+                  .help("Number of snapshots to include page maps of"))
+             .arg(Arg::with_name("max-size")
+                  .long("max-size")
+                  .takes_value(true)
+                  .default_value("512")
+                  .help("Largest page map side in pixels")))
+        .subcommand(SubCommand::with_name("diff")
+             .about("Show which pages changed state or content between two persisted snapshots")
+             .arg(Arg::with_name("input")
+                  .required(true)
+                  .help("Snapshot directory, e.g. /tmp/wss/<pid>, or archive file"))
+             .arg(Arg::with_name("from")
+                  .long("from")
+                  .takes_value(true)
+                  .help("Snapshot timestamp, as listed in the input [default: the second newest]"))
+             .arg(Arg::with_name("to")
+                  .long("to")
+                  .takes_value(true)
+                  .help("Snapshot timestamp [default: the newest]"))
+             .arg(Arg::with_name("max-ranges")
+                  .long("max-ranges")
+                  .takes_value(true)
+                  .default_value("10")
+                  .help("Address ranges listed per segment and change")))
+        .subcommand(SubCommand::with_name("top")
+             .about("Live view of page counts, fault rates and a segment's page map")
+             .arg(Arg::with_name("pid")
+                  .short("p")
+                  .long("pid")
+                  .takes_value(true)
+                  .multiple(true)
+                  .help("May be repeated; switch between them with n and p [default: whole host]"))
+             .arg(Arg::with_name("sleep")
+                  .short("s")
+                  .long("sleep")
+                  .takes_value(true)
+                  .default_value("10")
+                  .help("Initial sampling interval; change it with + and -"))
+             .arg(Arg::with_name("log")
+                  .long("log")
+                  .takes_value(true)
+                  .default_value("/tmp/wss/top.log")
+                  .help("Where log messages go while the terminal is in use")))
+        .subcommand(SubCommand::with_name("receive")
+             .about("Log statistics pushed with --push, as a stand-in collector")
+             .arg(Arg::with_name("protocol")
+                  .long("protocol")
+                  .takes_value(true)
+                  .possible_values(&["statsd", "otlp"])
+                  .default_value("statsd"))
+             .arg(Arg::with_name("listen")
+                  .long("listen")
+                  .takes_value(true)
+                  .help("[default: 127.0.0.1:8125 for statsd, 127.0.0.1:4318 for otlp]")))
         .get_matches();
 
-    let region: String = match matches.value_of("region") {
-        Some(region) => region.to_string(),
-        None => match env::var("EC2_PUBLIC_REGION") {
-            Ok(region) => region.to_string(),
-            Err(_e) => panic!("Region not passed not available from env var")
+    // The live view owns the terminal, so its logging goes to a file.
+    if let Some(top_matches) = matches.subcommand_matches("top") {
+        let log_path = Path::new(top_matches.value_of("log").unwrap());
+        if let Some(parent) = log_path.parent() {
+            std::fs::create_dir_all(parent)?;
         }
+        CombinedLogger::init(
+            vec![
+                WriteLogger::new(LevelFilter::Info, Config::default(), File::create(log_path)?),
+            ]
+        ).unwrap();
+        return top(top_matches);
+    }
+
+    CombinedLogger::init(
+        vec![
+            TermLogger::new(LevelFilter::Debug, Config::default()).unwrap(),
+        ]
+    ).unwrap();
+
+    if let Some(export_matches) = matches.subcommand_matches("export") {
+        return export(export_matches);
+    }
+    if let Some(render_matches) = matches.subcommand_matches("render") {
+        return render(render_matches);
+    }
+    if let Some(diff_matches) = matches.subcommand_matches("diff") {
+        return diff(diff_matches);
+    }
+    if let Some(report_matches) = matches.subcommand_matches("report") {
+        return report(report_matches);
+    }
+    if let Some(receive_matches) = matches.subcommand_matches("receive") {
+        return receive(receive_matches);
+    }
+
+    // Only needed when persisting to S3
+    let region: Option<String> = match matches.value_of("region") {
+        Some(region) => Some(region.to_string()),
+        None => env::var("EC2_PUBLIC_REGION").ok(),
+    };
+    // Not taken as an argument, where anyone could see it in ps.
+    let s3_credentials: Option<(String, String)> = match matches.value_of("s3-access-key") {
+        Some(access_key) => {
+            let secret_key = match matches.value_of("s3-secret-key-file") {
+                Some(path) => std::fs::read_to_string(path)?.trim().to_string(),
+                None => env::var(S3_SECRET_KEY_ENV).map_err(|_e| std::io::Error::new(std::io::ErrorKind::InvalidInput,
+                    format!("--s3-access-key needs --s3-secret-key-file or ${}", S3_SECRET_KEY_ENV)))?,
+            };
+            Some((access_key.to_string(), secret_key))
+        },
+        None => None,
     };
 
     let pids: Vec<i32> = match matches.values_of("pid") {
@@ -73,9 +369,106 @@ fn main() -> std::io::Result<()> {
         Some(pageout) => pageout.parse().expect("pageout must be u64"),
         None => 0,
     };
+    let pageout_addresses: mem_analyze::vmm::PageoutAddresses = matches.value_of("pageout-addresses").unwrap().parse().unwrap();
+
+    let compressor: Option<mem_analyze::dump::Compressor> = match matches.value_of("compressibility") {
+        Some(compressor) => Some(compressor.parse().unwrap()),
+        None => None,
+    };
 
     let inspect_ram: bool = matches.is_present("inspect-ram");
-    let s3_persist: bool = matches.is_present("s3-persist");
+    let mut store_kinds: Vec<&str> = match matches.values_of("store") {
+        Some(values) => values.collect(),
+        None => vec!["local"],
+    };
+    if matches.is_present("s3-persist") && !store_kinds.contains(&"s3") {
+        store_kinds.push("s3");
+    }
+    let output_dir = matches.value_of("output-dir").unwrap_or(OUTPUT_DIR);
+    std::fs::create_dir_all(output_dir)?;
+    let spool_dir = match matches.value_of("spool-dir") {
+        Some(spool_dir) => spool_dir.to_string(),
+        None => format!("{}/spool", output_dir),
+    };
+    let upload_queue_bytes: usize = match matches.value_of("upload-queue-mb") {
+        Some(mb) => mb.parse::<usize>().expect("upload-queue-mb must be usize") << 20,
+        None => UPLOAD_QUEUE_BYTES,
+    };
+    let upload_retries: u32 = match matches.value_of("upload-retries") {
+        Some(retries) => retries.parse().expect("upload-retries must be u32"),
+        None => UPLOAD_RETRIES,
+    };
+    let store_pid = if pids.len() > 0 { pids[0] } else { 0 };
+    let stores: Vec<Box<dyn SnapshotStore>> = store_kinds.iter().map(|kind| -> Box<dyn SnapshotStore> {
+        match *kind {
+            "local" => Box::new(LocalStore::new(format!("{}/{}", output_dir, store_pid))),
+            "s3" => Box::new(UploadQueue::new(Box::new(S3Store::new(&S3Config {
+                region: match &region {
+                    Some(region) => region.clone(),
+                    None => panic!("Region not passed not available from env var"),
+                },
+                endpoint: matches.value_of("s3-endpoint").map(|v| v.to_string()),
+                bucket: matches.value_of("s3-bucket").map(|v| v.to_string()),
+                prefix: matches.value_of("s3-prefix").map(|v| v.to_string()),
+                credentials: s3_credentials.clone(),
+            }).expect("Unable to set up S3 store")), Path::new(&spool_dir), upload_queue_bytes, upload_retries)
+                .expect("Unable to set up upload queue")),
+            "archive" => Box::new(ArchiveStore::open(match matches.value_of("archive") {
+                Some(path) => path.to_string(),
+                None => format!("{}/{}.wssa", output_dir, store_pid),
+            }).expect("Unable to open archive")),
+            _ => unreachable!(),
+        }
+    }).collect();
+
+    let keyframe_interval: u32 = match matches.value_of("keyframe-interval") {
+        Some(interval) => interval.parse().expect("keyframe-interval must be u32"),
+        None => 1,
+    };
+    let hours = |name: &str| matches.value_of(name)
+        .map(|hours| Duration::hours(hours.parse().expect("hours must be i64")));
+    let retention = RetentionPolicy {
+        max_age: hours("retention-max-age"),
+        max_total_bytes: matches.value_of("retention-max-mb")
+            .map(|mb| mb.parse::<u64>().expect("retention-max-mb must be u64") << 20),
+        keep_every: matches.value_of("retention-keep-every")
+            .map(|n| n.parse().expect("retention-keep-every must be u32")),
+        downsample_after: hours("retention-downsample-after"),
+    };
+    // The stats files are kept for as long as the snapshots.
+    let (stats_max_age, stats_max_bytes) = (retention.max_age, retention.max_total_bytes);
+    let mut persister = Persister::new(store_pid, sleep, keyframe_interval, stores, retention);
+    let stats_format: StatsFormat = matches.value_of("stats-format").unwrap().parse().unwrap();
+    let stats_path = match matches.value_of("stats-output") {
+        Some(path) => path.to_string(),
+        None => format!("{}/{}.{}", output_dir, store_pid, stats_format.extension()),
+    };
+    let stats_writer: StatsWriter<StatsRecord> = StatsWriter::new(&stats_path, stats_format)?
+        .rotate(stats_max_age, stats_max_bytes);
+    let segment_stats_path = match matches.value_of("segment-stats-output") {
+        Some(path) => path.to_string(),
+        None => format!("{}/{}.segments.{}", output_dir, store_pid, stats_format.extension()),
+    };
+    let segment_stats_writer: StatsWriter<SegmentRecord> = StatsWriter::new(&segment_stats_path, stats_format)?
+        .rotate(stats_max_age, stats_max_bytes);
+    let mut idle_ages = IdleAges::new();
+    let history_window: u32 = matches.value_of("history-window")
+        .map_or(HISTORY_WINDOW, |n| n.parse().expect("history-window must be u32"));
+    let mut access_history = AccessHistory::new(history_window, matches.value_of("hot-threshold")
+        .map_or((history_window + 1) / 2, |n| n.parse().expect("hot-threshold must be u32")));
+    let mut wss_estimator = WssEstimator::new(
+        matches.value_of("wss-window").map_or(WSS_WINDOW, |n| n.parse().expect("wss-window must be usize")),
+        matches.value_of("wss-headroom").map_or(WSS_HEADROOM_PERCENT, |p| p.parse().expect("wss-headroom must be f64")) / 100.0);
+
+    let metrics: Option<Metrics> = match matches.value_of("metrics-listen") {
+        Some(addr) => Some(Metrics::serve(addr)?),
+        None => None,
+    };
+
+    let pusher: Option<Pusher> = match matches.value_of("push") {
+        Some(protocol) => Some(Pusher::new(protocol.parse().unwrap(), matches.value_of("push-endpoint").unwrap())?),
+        None => None,
+    };
 
     let mut vmm = mem_analyze::vmm::Vmm::new();
 
@@ -83,22 +476,127 @@ fn main() -> std::io::Result<()> {
         info!("PID supplied: {:?}\n", pids);
         loop {
             let start_time = Utc::now();
-            let process_memory = mem_analyze::dump::get_memory(pids[0], sleep)?;
-            mem_analyze::statistics::page_analytics(pids[0], &process_memory);
-            mem_analyze::persist::write_process_memory(pids[0], &region, &process_memory, s3_persist)?;
-            vmm.swap_some_out(&process_memory.segments[0], pageout);
+            let mut process_memory = mem_analyze::dump::get_memory(pids[0], sleep, compressor)?;
+            vmm.annotate_guest_physical(&mut process_memory);
+            let localities = Locality::of_segments(&process_memory);
+            let mut stats = mem_analyze::statistics::page_analytics(Some(pids[0]), &process_memory, &localities);
+            wss_estimator.update(&mut stats);
+            idle_ages.update(&process_memory);
+            access_history.update(&process_memory);
+            access_history.tier_analytics(&process_memory, &mut stats);
+            stats_writer.append(&stats)?;
+            let segment_stats = mem_analyze::statistics::segment_analytics(
+                &process_memory, &localities, &idle_ages, &access_history);
+            segment_stats_writer.append_all(&segment_stats)?;
+            if let Some(metrics) = &metrics {
+                metrics.update(Some(pids[0]), &segment_stats, &stats);
+            }
+            if let Some(pusher) = &pusher {
+                if let Err(e) = pusher.push(Some(pids[0]), &stats) {
+                    warn!("Unable to push statistics: {:?}", e);
+                }
+            }
+            persister.write_process_memory(&process_memory, &stats)?;
+            vmm.swap_some_out(&process_memory.segments[0], pageout, pageout_addresses);
+            let elapsed = Utc::now() - start_time;
+            if let Some(metrics) = &metrics {
+                metrics.observe_duration(Some(pids[0]), elapsed.to_std().unwrap_or_default());
+            }
             info!("---------- Completed analysis in in {} ms ----------",
-                  (Utc::now() - start_time).num_milliseconds());
+                  elapsed.num_milliseconds());
         }
     } else {
         info!("No PIDs; analyzing whole system\n");
         loop {
             let start_time = Utc::now();
-            let process_memory = mem_analyze::dump::get_host_memory(sleep, inspect_ram)?;
-            mem_analyze::statistics::page_analytics(pids[0], &process_memory);
-            mem_analyze::persist::write_process_memory(0, &region, &process_memory, s3_persist)?;
+            let process_memory = mem_analyze::dump::get_host_memory(sleep, inspect_ram, compressor)?;
+            let localities = Locality::of_segments(&process_memory);
+            let mut stats = mem_analyze::statistics::page_analytics(None, &process_memory, &localities);
+            wss_estimator.update(&mut stats);
+            idle_ages.update(&process_memory);
+            access_history.update(&process_memory);
+            access_history.tier_analytics(&process_memory, &mut stats);
+            stats_writer.append(&stats)?;
+            let segment_stats = mem_analyze::statistics::segment_analytics(
+                &process_memory, &localities, &idle_ages, &access_history);
+            segment_stats_writer.append_all(&segment_stats)?;
+            if let Some(metrics) = &metrics {
+                metrics.update(None, &segment_stats, &stats);
+            }
+            if let Some(pusher) = &pusher {
+                if let Err(e) = pusher.push(None, &stats) {
+                    warn!("Unable to push statistics: {:?}", e);
+                }
+            }
+            persister.write_process_memory(&process_memory, &stats)?;
+            let elapsed = Utc::now() - start_time;
+            if let Some(metrics) = &metrics {
+                metrics.observe_duration(None, elapsed.to_std().unwrap_or_default());
+            }
             info!("---------- Completed analysis in in {} ms ----------",
-                  (Utc::now() - start_time).num_milliseconds());
+                  elapsed.num_milliseconds());
         }
     }
 }
+
+#[cfg(feature = "export")]
+fn export(matches: &ArgMatches) -> std::io::Result<()> {
+    let store = mem_analyze::store::open_path(Path::new(matches.value_of("input").unwrap()))?;
+    mem_analyze::export::export(&*store,
+                                Path::new(matches.value_of("output").unwrap()),
+                                matches.value_of("format").unwrap().parse().unwrap(),
+                                matches.value_of("granularity").unwrap().parse().unwrap())
+}
+
+#[cfg(not(feature = "export"))]
+fn export(_matches: &ArgMatches) -> std::io::Result<()> {
+    Err(std::io::Error::new(std::io::ErrorKind::Other, "Built without export; rebuild with --features export"))
+}
+
+fn render(matches: &ArgMatches) -> std::io::Result<()> {
+    let store = mem_analyze::store::open_path(Path::new(matches.value_of("input").unwrap()))?;
+    mem_analyze::render::render(&*store, Path::new(matches.value_of("output").unwrap()), &RenderOptions {
+        animation: matches.value_of("animation").unwrap().parse().unwrap(),
+        frames: !matches.is_present("no-frames"),
+        frame_delay_ms: matches.value_of("frame-delay-ms").unwrap().parse().expect("frame-delay-ms must be u16"),
+        max_side: matches.value_of("max-size").unwrap().parse().expect("max-size must be usize"),
+    })
+}
+
+fn diff(matches: &ArgMatches) -> std::io::Result<()> {
+    let store = mem_analyze::store::open_path(Path::new(matches.value_of("input").unwrap()))?;
+    let stdout = std::io::stdout();
+    mem_analyze::diff::diff(&*store, matches.value_of("from"), matches.value_of("to"),
+                            matches.value_of("max-ranges").unwrap().parse().expect("max-ranges must be usize"),
+                            &mut stdout.lock())
+}
+
+fn report(matches: &ArgMatches) -> std::io::Result<()> {
+    let input = Path::new(matches.value_of("input").unwrap());
+    let store = mem_analyze::store::open_path(input)?;
+    mem_analyze::report::report(&*store, input, Path::new(matches.value_of("output").unwrap()), &ReportOptions {
+        stats: matches.value_of("stats").map(PathBuf::from),
+        page_maps: matches.value_of("page-maps").unwrap().parse().expect("page-maps must be usize"),
+        max_side: matches.value_of("max-size").unwrap().parse().expect("max-size must be usize"),
+    })
+}
+
+fn top(matches: &ArgMatches) -> std::io::Result<()> {
+    mem_analyze::top::top(&TopOptions {
+        pids: match matches.values_of("pid") {
+            Some(values) => values.map(|p| p.parse().expect("Can't parse to i32")).collect(),
+            None => Vec::new(),
+        },
+        interval: matches.value_of("sleep").unwrap().parse().expect("time must be u64"),
+    })
+}
+
+fn receive(matches: &ArgMatches) -> std::io::Result<()> {
+    let protocol: PushProtocol = matches.value_of("protocol").unwrap().parse().unwrap();
+    let addr = match (matches.value_of("listen"), protocol) {
+        (Some(addr), _) => addr,
+        (None, PushProtocol::Statsd) => "127.0.0.1:8125",
+        (None, PushProtocol::Otlp) => "127.0.0.1:4318",
+    };
+    mem_analyze::push::receive(protocol, addr)
+}
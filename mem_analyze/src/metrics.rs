@@ -0,0 +1,232 @@
+// Prometheus text exposition of the latest sample, served from /metrics by a
+// background thread so long-running collection can be scraped like anything
+// else in the fleet. Only the most recent sample per PID is kept.
+
+use std::collections::BTreeMap;
+use std::fmt::Write as FmtWrite;
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::{thread, time};
+use sys_info::hostname;
+
+use super::statistics::{SegmentRecord, StatsRecord};
+
+// Upper bounds, in seconds. A collection includes the sleep between samples.
+const DURATION_BUCKETS: [f64; 10] = [0.1, 0.5, 1.0, 2.5, 5.0, 10.0, 15.0, 30.0, 60.0, 120.0];
+
+pub struct Metrics {
+    state: Arc<Mutex<MetricsState>>,
+}
+
+struct MetricsState {
+    hostname: String,
+    // Keyed by the pid label
+    samples: BTreeMap<String, Sample>,
+    durations: BTreeMap<String, Histogram>,
+}
+
+struct Sample {
+    segments: Vec<SegmentRecord>,
+    stats: StatsRecord,
+}
+
+struct Histogram {
+    // Non-cumulative counts per bucket; the last is +Inf.
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Metrics {
+    // Starts serving on addr, e.g. "0.0.0.0:9100".
+    pub fn serve(addr: &str) -> io::Result<Metrics> {
+        let listener = TcpListener::bind(addr)?;
+        let metrics = Metrics {
+            state: Arc::new(Mutex::new(MetricsState {
+                hostname: hostname().unwrap_or_default(),
+                samples: BTreeMap::new(),
+                durations: BTreeMap::new(),
+            })),
+        };
+        let state = metrics.state.clone();
+        thread::Builder::new()
+            .name("metrics".to_string())
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    match stream {
+                        Ok(stream) => if let Err(e) = handle(stream, &state) {
+                            debug!("Metrics request failed: {:?}", e);
+                        },
+                        Err(e) => warn!("Unable to accept metrics connection: {:?}", e),
+                    }
+                }
+            })?;
+        info!("Serving metrics on http://{}/metrics", addr);
+        Ok(metrics)
+    }
+
+    // pid is None in host mode. segments are this sample's segment_analytics().
+    pub fn update(&self, pid: Option<i32>, segments: &[SegmentRecord], stats: &StatsRecord) {
+        let mut state = self.state.lock().unwrap();
+        state.samples.insert(pid_label(pid), Sample { segments: segments.to_vec(), stats: stats.clone() });
+    }
+
+    pub fn observe_duration(&self, pid: Option<i32>, duration: time::Duration) {
+        let seconds = duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1e9;
+        let mut state = self.state.lock().unwrap();
+        let histogram = state.durations.entry(pid_label(pid)).or_insert_with(|| Histogram {
+            buckets: vec![0; DURATION_BUCKETS.len() + 1],
+            sum: 0.0,
+            count: 0,
+        });
+        let bucket = DURATION_BUCKETS.iter().position(|&bound| seconds <= bound).unwrap_or(DURATION_BUCKETS.len());
+        histogram.buckets[bucket] += 1;
+        histogram.sum += seconds;
+        histogram.count += 1;
+    }
+}
+
+fn pid_label(pid: Option<i32>) -> String {
+    match pid {
+        Some(pid) => pid.to_string(),
+        None => "host".to_string(),
+    }
+}
+
+fn handle(stream: TcpStream, state: &Mutex<MetricsState>) -> io::Result<()> {
+    stream.set_read_timeout(Some(time::Duration::from_secs(5)))?;
+    // A scraper which stops reading mustn't hold up the next one.
+    stream.set_write_timeout(Some(time::Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // Drain the headers; we don't need any of them.
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+    let mut fields = request_line.split_whitespace();
+    let (status, body) = match (fields.next(), fields.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", render(&state.lock().unwrap())),
+        (Some("GET"), Some(_)) => ("404 Not Found", "Try /metrics\n".to_string()),
+        _ => ("405 Method Not Allowed", String::new()),
+    };
+    let mut stream = stream;
+    write!(stream, "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+           status, body.len(), body)?;
+    stream.flush()
+}
+
+fn render(state: &MetricsState) -> String {
+    let mut out = String::new();
+    let segment_gauges: [(&str, &str, fn(&SegmentRecord) -> u64); 6] = [
+        ("wss_total_pages", "Pages mapped in the segment", |r| (r.size_bytes / 4096) as u64),
+        ("wss_lru_pages", "Pages on an LRU list", |r| r.lru_pages),
+        ("wss_zero_pages", "Resident pages filled with zeroes", |r| r.zero_pages),
+        ("wss_active_pages", "Pages accessed since the last sample", |r| r.active_pages),
+        ("wss_present_pages", "Resident pages", |r| r.present_pages),
+        ("wss_swapped_pages", "Pages swapped out", |r| r.swapped_pages),
+    ];
+    for (name, help, value) in segment_gauges.iter() {
+        header(&mut out, name, help, "gauge");
+        for (pid, sample) in &state.samples {
+            for segment in &sample.segments {
+                let _ = writeln!(out, "{}{{hostname=\"{}\",pid=\"{}\",segment=\"0x{:x}\"}} {}",
+                                 name, escape(&state.hostname), pid, segment.address, value(segment));
+            }
+        }
+    }
+
+    let process_metrics: [(&str, &str, &str, fn(&StatsRecord) -> u64); 7] = [
+        ("wss_bytes", "Working set size: bytes accessed since the last sample", "gauge", |s| s.active_pages * 4096),
+        ("wss_minor_faults_total", "Minor page faults", "counter", |s| s.minflt),
+        ("wss_major_faults_total", "Major page faults", "counter", |s| s.majflt),
+        ("wss_swap_bytes", "Bytes swapped out", "gauge", |s| s.swap_bytes),
+        ("wss_p95_bytes", "95th percentile WSS over the rolling window", "gauge", |s| s.wss_p95_bytes),
+        ("wss_ewma_bytes", "Exponentially weighted moving average of WSS", "gauge", |s| s.wss_ewma_bytes),
+        ("wss_recommended_bytes", "Recommended memory size: p95 WSS plus headroom", "gauge", |s| s.recommended_bytes),
+    ];
+    for (name, help, kind, value) in process_metrics.iter() {
+        header(&mut out, name, help, kind);
+        for (pid, sample) in &state.samples {
+            let _ = writeln!(out, "{}{{hostname=\"{}\",pid=\"{}\"}} {}",
+                             name, escape(&state.hostname), pid, value(&sample.stats));
+        }
+    }
+
+    let name = "wss_collection_duration_seconds";
+    header(&mut out, name, "Time to take and analyze a sample, including the sleep", "histogram");
+    for (pid, histogram) in &state.durations {
+        let labels = format!("hostname=\"{}\",pid=\"{}\"", escape(&state.hostname), pid);
+        let mut cumulative = 0;
+        for (idx, count) in histogram.buckets.iter().enumerate() {
+            cumulative += count;
+            let bound = match DURATION_BUCKETS.get(idx) {
+                Some(bound) => bound.to_string(),
+                None => "+Inf".to_string(),
+            };
+            let _ = writeln!(out, "{}_bucket{{{},le=\"{}\"}} {}", name, labels, bound, cumulative);
+        }
+        let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, histogram.sum);
+        let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, histogram.count);
+    }
+    out
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state() -> MetricsState {
+        let mut state = MetricsState {
+            hostname: "rack \"7\"\\a\nb".to_string(),
+            samples: BTreeMap::new(),
+            durations: BTreeMap::new(),
+        };
+        let segments = vec![
+            SegmentRecord { address: 0x1000, size_bytes: 8 * 4096, present_pages: 6, active_pages: 2, lru_pages: 5, ..Default::default() },
+            SegmentRecord { address: 0x20000, size_bytes: 4096, swapped_pages: 1, ..Default::default() },
+        ];
+        let stats = StatsRecord { active_pages: 2, minflt: 17, ..Default::default() };
+        state.samples.insert("42".to_string(), Sample { segments: segments, stats: stats });
+        state.durations.insert("42".to_string(), Histogram { buckets: vec![0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 2], sum: 400.5, count: 3 });
+        state
+    }
+
+    #[test]
+    fn exposition() {
+        let out = render(&state());
+        let lines: Vec<&str> = out.lines().collect();
+        let labels = "hostname=\"rack \\\"7\\\"\\\\a\\nb\",pid=\"42\"";
+        // Every metric is introduced once, by HELP then TYPE, before its samples.
+        for (name, kind) in &[("wss_total_pages", "gauge"), ("wss_minor_faults_total", "counter"),
+                              ("wss_collection_duration_seconds", "histogram")] {
+            let help = lines.iter().position(|line| line.starts_with(&format!("# HELP {} ", name))).unwrap();
+            assert_eq!(lines[help + 1], format!("# TYPE {} {}", name, kind));
+            assert_eq!(lines.iter().filter(|line| line.starts_with(&format!("# TYPE {} ", name))).count(), 1);
+        }
+        assert!(lines.contains(&format!("wss_total_pages{{{},segment=\"0x1000\"}} 8", labels).as_str()));
+        assert!(lines.contains(&format!("wss_lru_pages{{{},segment=\"0x1000\"}} 5", labels).as_str()));
+        assert!(lines.contains(&format!("wss_swapped_pages{{{},segment=\"0x20000\"}} 1", labels).as_str()));
+        assert!(lines.contains(&format!("wss_bytes{{{}}} 8192", labels).as_str()));
+        assert!(lines.contains(&format!("wss_minor_faults_total{{{}}} 17", labels).as_str()));
+        // Buckets are cumulative.
+        assert!(lines.contains(&format!("wss_collection_duration_seconds_bucket{{{},le=\"0.5\"}} 1", labels).as_str()));
+        assert!(lines.contains(&format!("wss_collection_duration_seconds_bucket{{{},le=\"120\"}} 1", labels).as_str()));
+        assert!(lines.contains(&format!("wss_collection_duration_seconds_bucket{{{},le=\"+Inf\"}} 3", labels).as_str()));
+        assert!(lines.contains(&format!("wss_collection_duration_seconds_count{{{}}} 3", labels).as_str()));
+        // Escaping keeps every sample on one line.
+        assert!(lines.iter().all(|line| line.starts_with('#') || line.starts_with("wss_")));
+    }
+}
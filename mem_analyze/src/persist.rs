@@ -7,86 +7,857 @@
 // 6 : modified since last run
 // 7 : version
 
-use std::fs;
 use std::io;
-use std::fs::File;
-use std::io::Write;
-use chrono::SecondsFormat;
+use std::io::Read;
+use std::hash::Hasher;
 use std::collections::HashMap;
-use sys_info::hostname;
+use chrono::{DateTime, Duration, Utc, SecondsFormat};
 use byteorder::{ByteOrder, LittleEndian};
-use lz4::EncoderBuilder;
+use lz4::{EncoderBuilder, Decoder};
+use json::JsonValue;
+use sys_info::{hostname, os_release};
+use twox_hash::XxHash64;
 
-use rusoto_core::Region;
-use rusoto_s3::S3Client;
-use rusoto_s3::S3;
-use rusoto_s3::PutObjectRequest;
+use super::store::SnapshotStore;
+use super::statistics::{StatsRecord, StatsRow};
 
-pub fn write_process_memory(pid: i32, region: &str, memory: &super::ProcessMemory, s3_persist: bool) -> std::io::Result<()> {
-    let base_dir = format!("/tmp/wss/{}/{}", pid, memory.timestamp.to_rfc3339_opts(SecondsFormat::Secs, true));
-    fs::create_dir_all(&base_dir)?;
+// Bump whenever the manifest or segment file layout changes incompatibly.
+pub const MANIFEST_VERSION: u32 = 1;
+pub const MANIFEST_NAME: &str = "manifest.json";
+pub const STATS_NAME: &str = "stats.csv";
+// xxHash64 of each page's contents, one u64 per page, 0 where not read. Encoded
+// like the page flags, against the same base. Kept out of the top level so the
+// 0x<addr> files are only ever page flags.
+const HASHES_DIR: &str = "hashes";
+// (page offset, repeating word) pairs for pages with the pattern bit set.
+const PATTERNS_DIR: &str = "patterns";
+const PATTERNS_ENCODING: &str = "lz4-frame/offset-word/u64le";
+// Host mode only: one byte per page, indexing the manifest's "page_types" list.
+const TYPES_DIR: &str = "types";
+const TYPES_ENCODING: &str = "lz4-frame/u8";
+// The full page flags of the segment.
+const KEYFRAME_ENCODING: &str = "lz4-frame/u64le";
+// Page flags XORed with the same segment in the "base" snapshot, then run-length
+// encoded as repeated (zero words: u32, literal words: u32, literals...). The base
+// is the last snapshot with the segment as a keyframe, so a delta never needs
+// more than one other file to decode.
+const DELTA_ENCODING: &str = "lz4-frame/xor-rle/u64le";
 
-    let hostname: String = match hostname() {
-        Ok(hostname) => hostname,
-        Err(e) => panic!(e),
+// Limits on how much history to keep. Snapshots are removed a whole delta chain
+// (keyframe plus the deltas based on it) at a time, and the chain currently being
+// written is never removed.
+#[derive(Default)]
+pub struct RetentionPolicy {
+    pub max_age: Option<Duration>,
+    pub max_total_bytes: Option<u64>,
+    // Chains older than downsample_after are thinned to every keep_every'th.
+    pub keep_every: Option<u32>,
+    pub downsample_after: Option<Duration>,
+}
+
+impl RetentionPolicy {
+    fn is_unlimited(&self) -> bool {
+        self.max_age.is_none() && self.max_total_bytes.is_none() && self.keep_every.is_none()
+    }
+}
+
+pub struct Persister {
+    pid: i32,
+    interval: u64,
+    stores: Vec<Box<dyn SnapshotStore>>,
+    retention: RetentionPolicy,
+    // Write a full snapshot every this many; 1 means never write deltas.
+    keyframe_interval: u32,
+    since_keyframe: u32,
+    // Segment start -> the timestamp, page flags and content hashes (empty if
+    // none were written) it was last written in full.
+    bases: HashMap<usize, (String, Vec<u64>, Vec<u64>)>,
+    // Per store, what retention knows is in it, so the store is only listed once.
+    // None until it has been listed, or after a failure leaves it unsure.
+    chains: Vec<Option<Vec<SnapshotChain>>>,
+}
+
+impl Persister {
+    pub fn new(pid: i32, interval: u64, keyframe_interval: u32, stores: Vec<Box<dyn SnapshotStore>>, retention: RetentionPolicy) -> Persister {
+        Persister {
+            pid: pid,
+            interval: interval,
+            chains: stores.iter().map(|_store| None).collect(),
+            stores: stores,
+            retention: retention,
+            keyframe_interval: std::cmp::max(keyframe_interval, 1),
+            since_keyframe: 0,
+            bases: HashMap::new(),
+        }
+    }
+
+    // Writes each segment's page flags to <timestamp>/0x<addr>, the statistics
+    // record to <timestamp>/stats.csv, and a manifest describing the snapshot at
+    // <timestamp>/manifest.json.
+    pub fn write_process_memory(&mut self, memory: &super::ProcessMemory, stats: &StatsRecord) -> std::io::Result<()> {
+        let timestamp = memory.timestamp.to_rfc3339_opts(SecondsFormat::Secs, true);
+        let mut stored_all = put_all(&self.stores, &format!("{}/{}", timestamp, STATS_NAME), &stats.to_csv()?);
+        let keyframe = self.bases.is_empty() || self.since_keyframe + 1 >= self.keyframe_interval;
+        if keyframe {
+            self.bases.clear();
+        }
+        let mut segments = JsonValue::new_array();
+        let mut bytes: u64 = 0;
+        let mut new_bases: Vec<(usize, Vec<u64>, Vec<u64>)> = Vec::new();
+        for segment in &memory.segments {
+            let has_hashes = segment.content_hashes.iter().any(|&hash| hash != 0);
+            // Segments which are new, or have changed size, can't be deltas.
+            let base = self.bases.get(&segment.addr_start)
+                .filter(|(_base_timestamp, base_flags, _base_hashes)| base_flags.len() == segment.page_flags.len());
+            let (encoding, stored) = match base {
+                Some((_base_timestamp, base_flags, _base_hashes)) => (DELTA_ENCODING, lz4_compress(&encode_delta(&segment.page_flags, base_flags))?),
+                None => (KEYFRAME_ENCODING, lz4_compress(&words_to_bytes(&segment.page_flags))?),
+            };
+            if base.is_none() {
+                let base_hashes = if has_hashes { segment.content_hashes.clone() } else { Vec::new() };
+                new_bases.push((segment.addr_start, segment.page_flags.clone(), base_hashes));
+            }
+            let file_name = format!("0x{:x}", segment.addr_start);
+            stored_all &= put_all(&self.stores, &format!("{}/{}", timestamp, file_name), stored.as_slice());
+            bytes += stored.len() as u64;
+            let mut segment_manifest = object!{
+                "address" => format!("0x{:x}", segment.addr_start),
+                "path" => segment.path.clone(),
+                "file" => file_name,
+                "pages" => segment.page_flags.len(),
+                "size" => segment.page_flags.len() * 4096,
+                "encoding" => encoding,
+                "stored_size" => stored.len(),
+                "checksum" => format!("xxh64:{:016x}", checksum(&stored)),
+                "guest_mappings" => segment.guest_mappings.iter().map(|mapping| object!{
+                    "segment_offset" => mapping.segment_offset,
+                    "guest_phys_addr" => format!("0x{:x}", mapping.guest_phys_addr),
+                    "size" => mapping.size,
+                }).collect::<Vec<JsonValue>>(),
+            };
+            if let Some((base_timestamp, _base_flags, _base_hashes)) = base {
+                segment_manifest["base"] = base_timestamp.as_str().into();
+            }
+            if has_hashes {
+                // Most pages keep their contents, so against the base most words XOR to 0.
+                let hashes_base = base.filter(|(_base_timestamp, _base_flags, base_hashes)| base_hashes.len() == segment.content_hashes.len());
+                let (hashes_encoding, hashes) = match hashes_base {
+                    Some((_base_timestamp, _base_flags, base_hashes)) => (DELTA_ENCODING, lz4_compress(&encode_delta(&segment.content_hashes, base_hashes))?),
+                    None => (KEYFRAME_ENCODING, lz4_compress(&words_to_bytes(&segment.content_hashes))?),
+                };
+                let hashes_name = format!("{}/0x{:x}", HASHES_DIR, segment.addr_start);
+                stored_all &= put_all(&self.stores, &format!("{}/{}", timestamp, hashes_name), hashes.as_slice());
+                bytes += hashes.len() as u64;
+                segment_manifest["hashes"] = object!{
+                    "file" => hashes_name,
+                    "encoding" => hashes_encoding,
+                    "stored_size" => hashes.len(),
+                    "checksum" => format!("xxh64:{:016x}", checksum(&hashes)),
+                };
+                if let Some((base_timestamp, _base_flags, _base_hashes)) = hashes_base {
+                    segment_manifest["hashes"]["base"] = base_timestamp.as_str().into();
+                }
+            }
+            if !segment.page_types.is_empty() {
+                let type_bytes: Vec<u8> = segment.page_types.iter()
+                    .map(|page_type| super::PageType::ALL.iter().position(|t| t == page_type).unwrap() as u8)
+                    .collect();
+                let types = lz4_compress(&type_bytes)?;
+                let types_name = format!("{}/0x{:x}", TYPES_DIR, segment.addr_start);
+                stored_all &= put_all(&self.stores, &format!("{}/{}", timestamp, types_name), types.as_slice());
+                bytes += types.len() as u64;
+                segment_manifest["page_types"] = object!{
+                    "file" => types_name,
+                    "encoding" => TYPES_ENCODING,
+                    "stored_size" => types.len(),
+                    "checksum" => format!("xxh64:{:016x}", checksum(&types)),
+                };
+            }
+            if !segment.pattern_words.is_empty() {
+                let mut pairs: Vec<(&usize, &u64)> = segment.pattern_words.iter().collect();
+                pairs.sort();
+                let words: Vec<u64> = pairs.iter().flat_map(|(&offset, &word)| vec![offset as u64, word]).collect();
+                let patterns = lz4_compress(&words_to_bytes(&words))?;
+                let patterns_name = format!("{}/0x{:x}", PATTERNS_DIR, segment.addr_start);
+                stored_all &= put_all(&self.stores, &format!("{}/{}", timestamp, patterns_name), patterns.as_slice());
+                bytes += patterns.len() as u64;
+                segment_manifest["patterns"] = object!{
+                    "file" => patterns_name,
+                    "encoding" => PATTERNS_ENCODING,
+                    "stored_size" => patterns.len(),
+                    "checksum" => format!("xxh64:{:016x}", checksum(&patterns)),
+                };
+            }
+            segments.push(segment_manifest).unwrap();
+        }
+        let manifest = object!{
+            "manifest_version" => MANIFEST_VERSION,
+            "tool" => env!("CARGO_PKG_NAME"),
+            "tool_version" => env!("CARGO_PKG_VERSION"),
+            "timestamp" => timestamp.clone(),
+            "mode" => if self.pid == 0 { "host" } else { "process" },
+            "pid" => self.pid,
+            "hostname" => hostname().unwrap_or_default(),
+            "kernel_version" => os_release().unwrap_or_default(),
+            "page_size" => 4096,
+            "interval_seconds" => self.interval,
+            "keyframe" => keyframe,
+            "flag_bits" => flag_bits(),
+            "page_types" => super::PageType::ALL.iter().map(|page_type| page_type.name()).collect::<Vec<&str>>(),
+            "stats" => STATS_NAME,
+            "segments" => segments,
+        };
+        stored_all &= put_all(&self.stores, &format!("{}/{}", timestamp, MANIFEST_NAME), manifest.pretty(2).as_bytes());
+
+        self.since_keyframe = if keyframe { 0 } else { self.since_keyframe + 1 };
+        for (addr_start, page_flags, content_hashes) in new_bases {
+            self.bases.insert(addr_start, (timestamp.clone(), page_flags, content_hashes));
+        }
+        // A store may be missing part of this snapshot, so nothing can be based on it.
+        if !stored_all {
+            self.bases.clear();
+        }
+
+        if !self.retention.is_unlimited() {
+            let chain_secs = (self.interval * self.keyframe_interval as u64) as i64;
+            for (store, chains) in self.stores.iter().zip(self.chains.iter_mut()) {
+                if !store.enforce_retention() {
+                    continue;
+                }
+                if !stored_all {
+                    *chains = None;
+                }
+                match chains {
+                    Some(chains) => add_snapshot(chains, timestamp.clone(), memory.timestamp, keyframe, bytes),
+                    None => match scan_chains(&**store) {
+                        Ok(scanned) => *chains = Some(scanned),
+                        Err(e) => {
+                            error!("Unable to list snapshots in {}: {:?}", store.describe(), e);
+                            continue;
+                        },
+                    },
+                }
+                if let Err(e) = enforce_retention(&**store, chains.as_mut().unwrap(), &self.retention, chain_secs) {
+                    error!("Unable to enforce retention on {}: {:?}", store.describe(), e);
+                    *chains = None;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// A keyframe and the deltas which depend on it.
+struct SnapshotChain {
+    timestamps: Vec<String>,
+    oldest: DateTime<Utc>,
+    newest: DateTime<Utc>,
+    bytes: u64,
+}
+
+fn add_snapshot(chains: &mut Vec<SnapshotChain>, timestamp: String, taken: DateTime<Utc>, keyframe: bool, bytes: u64) {
+    match chains.last_mut() {
+        Some(chain) if !keyframe => {
+            chain.timestamps.push(timestamp);
+            chain.newest = taken;
+            chain.bytes += bytes;
+        },
+        _ => chains.push(SnapshotChain { timestamps: vec![timestamp], oldest: taken, newest: taken, bytes: bytes }),
+    }
+}
+
+// Reads every manifest in the store, so only done when it's first needed.
+fn scan_chains(store: &dyn SnapshotStore) -> io::Result<Vec<SnapshotChain>> {
+    let mut chains: Vec<SnapshotChain> = Vec::new();
+    for timestamp in list_snapshots(store)? {
+        let manifest = read_manifest(store, &timestamp)?;
+        let bytes = snapshot_bytes(store, &timestamp, &manifest)?;
+        let taken = DateTime::parse_from_rfc3339(&timestamp)
+            .map_err(|e| invalid_data(format!("Bad timestamp {}: {:?}", timestamp, e)))?
+            .with_timezone(&Utc);
+        add_snapshot(&mut chains, timestamp, taken, manifest["keyframe"].as_bool() != Some(false), bytes);
+    }
+    Ok(chains)
+}
+
+// Removes expired chains from the store and from chains. chain_secs is roughly
+// how much time one chain covers.
+fn enforce_retention(store: &dyn SnapshotStore, all_chains: &mut Vec<SnapshotChain>, policy: &RetentionPolicy, chain_secs: i64) -> io::Result<()> {
+    let now = Utc::now();
+    let mut chains: Vec<SnapshotChain> = std::mem::take(all_chains);
+    // The live chain is always kept.
+    let live = match chains.pop() {
+        Some(chain) => chain,
+        None => return Ok(()),
     };
 
-    for (segment_start, segment_data) in process_to_page_summary(&memory).into_iter() {
-        let mut compressed: Vec<u8> = Vec::new();
-        let mut encoder = EncoderBuilder::new()
-            .level(4)
-            .build(&mut compressed)?;
-        io::copy(&mut segment_data.as_slice(), &mut encoder)?;
-        encoder.finish().1?;
-        write_to_file(&base_dir, segment_start, compressed.as_slice())?;
-        if s3_persist {
-            write_to_s3(region,
-                        &format!("{}/{}", hostname, memory.timestamp.to_rfc3339_opts(SecondsFormat::Secs, true)),
-                        segment_start, compressed);
+    let mut expired: Vec<SnapshotChain> = Vec::new();
+    if let Some(max_age) = policy.max_age {
+        let (old, keep): (Vec<SnapshotChain>, Vec<SnapshotChain>) = chains.into_iter()
+            .partition(|chain| now - chain.newest > max_age);
+        expired.extend(old);
+        chains = keep;
+    }
+    if let Some(keep_every) = policy.keep_every {
+        // Thin by time rather than position so that applying this every iteration
+        // is stable: keep the oldest chain in each window of keep_every chains.
+        let downsample_after = policy.downsample_after.unwrap_or_else(Duration::zero);
+        let window_secs = std::cmp::max(chain_secs * keep_every as i64, 1);
+        let mut kept: Vec<SnapshotChain> = Vec::new();
+        for chain in chains {
+            let same_window = kept.last().map_or(false, |previous: &SnapshotChain|
+                previous.oldest.timestamp() / window_secs == chain.oldest.timestamp() / window_secs);
+            if now - chain.newest > downsample_after && same_window {
+                expired.push(chain);
+            } else {
+                kept.push(chain);
+            }
+        }
+        chains = kept;
+    }
+    if let Some(max_total_bytes) = policy.max_total_bytes {
+        let mut total_bytes: u64 = live.bytes + chains.iter().map(|chain| chain.bytes).sum::<u64>();
+        while total_bytes > max_total_bytes && !chains.is_empty() {
+            let chain = chains.remove(0);
+            total_bytes -= chain.bytes;
+            expired.push(chain);
+        }
+    }
+
+    all_chains.extend(chains);
+    all_chains.push(live);
+
+    if expired.is_empty() {
+        return Ok(());
+    }
+    // One listing, however many snapshots have expired.
+    let mut keys_by_timestamp: HashMap<String, Vec<String>> = HashMap::new();
+    for key in store.list("")? {
+        if let Some(idx) = key.find('/') {
+            keys_by_timestamp.entry(key[..idx].to_string()).or_default().push(key);
+        }
+    }
+    for chain in expired {
+        // Newest first, so a partially deleted chain still has its keyframe.
+        for timestamp in chain.timestamps.iter().rev() {
+            debug!("Retention: removing snapshot {} from {}", timestamp, store.describe());
+            // Manifest last, so a half-deleted snapshot is still listed and retried.
+            let mut keys = keys_by_timestamp.remove(timestamp).unwrap_or_default();
+            keys.sort_by_key(|key| key.ends_with(MANIFEST_NAME));
+            for key in keys {
+                store.delete(&key)?;
+            }
         }
     }
     Ok(())
 }
 
-fn process_to_page_summary(memory: &super::ProcessMemory) -> HashMap<usize, Vec<u8>> {
-    let mut segment_data = HashMap::new();
-    for segment in &memory.segments {
-        let mut page_summaries: Vec<u8>  = Vec::with_capacity(8 * segment.page_flags.len());
-        page_summaries.resize(8 * segment.page_flags.len(), 0);
-        LittleEndian::write_u64_into(&segment.page_flags, &mut page_summaries);
-        segment_data.insert(segment.addr_start, page_summaries);
+fn snapshot_bytes(store: &dyn SnapshotStore, timestamp: &str, manifest: &JsonValue) -> io::Result<u64> {
+    let mut bytes: u64 = 0;
+    for segment in manifest["segments"].members() {
+        bytes += match segment["stored_size"].as_u64() {
+            Some(size) => size,
+            // Older snapshots don't record it
+            None => store.get(&format!("{}/{}", timestamp, segment["file"].as_str().unwrap_or("")))?.len() as u64,
+        };
+        bytes += segment["hashes"]["stored_size"].as_u64().unwrap_or(0);
+        bytes += segment["patterns"]["stored_size"].as_u64().unwrap_or(0);
+        bytes += segment["page_types"]["stored_size"].as_u64().unwrap_or(0);
     }
-    return segment_data;
+    Ok(bytes)
 }
 
-fn write_to_file(base_dir: &str, segment_start: usize, segment_data: &[u8]) -> std::io::Result<()> {
-    let mut file = File::create(format!("{}/0x{:x}", base_dir, segment_start))?;
-    info!("Persisting process memory metadata to: {:?}", &file);
-    file.write(segment_data)?;
-    Ok(())
+// Timestamps of every snapshot in the store, oldest first.
+pub fn list_snapshots(store: &dyn SnapshotStore) -> io::Result<Vec<String>> {
+    let mut timestamps: Vec<String> = store.list("")?.iter()
+        .filter_map(|key| key.split('/').next())
+        .filter(|timestamp| DateTime::parse_from_rfc3339(timestamp).is_ok())
+        .map(|timestamp| timestamp.to_string())
+        .collect();
+    timestamps.dedup();
+    Ok(timestamps)
 }
 
-fn write_to_s3(region_str: &str, base_key: &str, segment_start: usize, segment_data: Vec<u8>) {
-    match S3Client::new(region_rusto(region_str)).put_object(PutObjectRequest {
-        body: Some(segment_data.into()),
-        bucket: format!("jgowans-wss-{}", region_str),
-        key: format!("{}/0x{:x}", base_key, segment_start),
-        ..Default::default()
-    }).sync() {
-        Ok(_resp) => {
-            info!("PutObject success");
-        },
-        Err(error) => {
-            error!("PutObject error: {:?}", error);
+// Reconstruct the snapshot taken at the given timestamp, following deltas back to
+// their keyframe. Snapshots from before manifests existed are plain keyframes.
+pub fn read_process_memory(store: &dyn SnapshotStore, timestamp: &str) -> io::Result<super::ProcessMemory> {
+    read_snapshot(store, timestamp, true)
+}
+
+// Like read_process_memory, but without the content hashes, pattern words and
+// page types, for going over every snapshot in a run.
+pub fn read_page_flags(store: &dyn SnapshotStore, timestamp: &str) -> io::Result<super::ProcessMemory> {
+    read_snapshot(store, timestamp, false)
+}
+
+fn read_snapshot(store: &dyn SnapshotStore, timestamp: &str, full: bool) -> io::Result<super::ProcessMemory> {
+    let manifest = read_manifest(store, timestamp)?;
+    let mut segments = Vec::new();
+    for segment_manifest in manifest["segments"].members() {
+        let addr_start = parse_address(&segment_manifest["address"])?;
+        let mut segment = super::Segment::from_page_flags(addr_start, read_segment_words(store, timestamp, segment_manifest, None)?);
+        segment.path = segment_manifest["path"].as_str().unwrap_or("").to_string();
+        segment.guest_mappings = segment_manifest["guest_mappings"].members().map(|mapping| Ok(super::GuestMapping {
+            segment_offset: mapping["segment_offset"].as_usize().unwrap_or(0),
+            guest_phys_addr: parse_address(&mapping["guest_phys_addr"])?,
+            size: mapping["size"].as_usize().unwrap_or(0),
+        })).collect::<io::Result<Vec<super::GuestMapping>>>()?;
+        if !full {
+            segments.push(segment);
+            continue;
+        }
+        if segment_manifest["hashes"].is_object() {
+            segment.content_hashes = read_segment_words(store, timestamp, segment_manifest, Some("hashes"))?;
+        }
+        if segment_manifest["page_types"].is_object() {
+            let names: Vec<&str> = manifest["page_types"].members().map(|name| name.as_str().unwrap_or("")).collect();
+            segment.page_types = read_checked(store, timestamp, &segment_manifest["page_types"])?.iter()
+                .map(|&idx| names.get(idx as usize)
+                     .and_then(|name| super::PageType::ALL.iter().find(|page_type| page_type.name() == *name))
+                     .cloned()
+                     .unwrap_or(super::PageType::Other))
+                .collect();
+        }
+        if segment_manifest["patterns"].is_object() {
+            segment.pattern_words = bytes_to_words(&read_checked(store, timestamp, &segment_manifest["patterns"])?)
+                .chunks(2)
+                .filter(|pair| pair.len() == 2)
+                .map(|pair| (pair[0] as usize, pair[1]))
+                .collect();
+        }
+        segments.push(segment);
+    }
+    Ok(super::ProcessMemory {
+        timestamp: DateTime::parse_from_rfc3339(timestamp)
+            .map_err(|e| invalid_data(format!("Bad timestamp {}: {:?}", timestamp, e)))?
+            .with_timezone(&Utc),
+        segments: segments,
+    })
+}
+
+pub fn read_manifest(store: &dyn SnapshotStore, timestamp: &str) -> io::Result<JsonValue> {
+    match store.get(&format!("{}/{}", timestamp, MANIFEST_NAME)) {
+        Ok(data) => json::parse(&String::from_utf8_lossy(&data))
+            .map_err(|e| invalid_data(format!("Bad manifest for {}: {:?}", timestamp, e))),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => legacy_manifest(store, timestamp),
+        Err(e) => Err(e),
+    }
+}
+
+// Make up the manifest a snapshot would have had, from its 0x<addr> files.
+fn legacy_manifest(store: &dyn SnapshotStore, timestamp: &str) -> io::Result<JsonValue> {
+    let mut segments = JsonValue::new_array();
+    for key in store.list(&format!("{}/0x", timestamp))? {
+        let file_name = key[timestamp.len() + 1..].to_string();
+        segments.push(object!{
+            "address" => file_name.clone(),
+            "file" => file_name,
+            "encoding" => KEYFRAME_ENCODING,
+        }).unwrap();
+    }
+    if segments.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("No snapshot at {}", timestamp)));
+    }
+    Ok(object!{
+        "timestamp" => timestamp,
+        "keyframe" => true,
+        "segments" => segments,
+    })
+}
+
+// Fetch a file described in the manifest, verify it and undo the LZ4 framing.
+fn read_checked(store: &dyn SnapshotStore, timestamp: &str, file_manifest: &JsonValue) -> io::Result<Vec<u8>> {
+    let file_name = file_manifest["file"].as_str().unwrap_or("");
+    let stored = store.get(&format!("{}/{}", timestamp, file_name))?;
+    if let Some(expected) = file_manifest["checksum"].as_str() {
+        let actual = format!("xxh64:{:016x}", checksum(&stored));
+        if actual != expected {
+            return Err(invalid_data(format!("Checksum mismatch for {}/{}: {} != {}", timestamp, file_name, actual, expected)));
         }
     }
+    lz4_decompress(&stored)
 }
 
-fn region_rusto(region_str: &str) -> Region {
-    return match region_str {
-        "us-east-1" => Region::UsEast1,
-        "eu-west-2" => Region::EuWest2,
-        "sa-east-1" => Region::SaEast1,
-        _ => panic!("Invalid region: {}", region_str),
+// A segment's page flags, or with field "hashes" its content hashes, following
+// a delta back to its base.
+fn read_segment_words(store: &dyn SnapshotStore, timestamp: &str, segment_manifest: &JsonValue, field: Option<&str>) -> io::Result<Vec<u64>> {
+    let file_manifest = match field {
+        Some(field) => &segment_manifest[field],
+        None => segment_manifest,
     };
+    let file_name = file_manifest["file"].as_str().unwrap_or("");
+    let data = read_checked(store, timestamp, file_manifest)?;
+    match file_manifest["encoding"].as_str() {
+        Some(KEYFRAME_ENCODING) => Ok(bytes_to_words(&data)),
+        Some(DELTA_ENCODING) => {
+            let base_timestamp = file_manifest["base"].as_str()
+                .ok_or_else(|| invalid_data(format!("Delta {}/{} has no base", timestamp, file_name)))?;
+            let base_manifest = read_manifest(store, base_timestamp)?;
+            let base_segment = base_manifest["segments"].members()
+                .find(|base_segment| base_segment["address"] == segment_manifest["address"])
+                .ok_or_else(|| invalid_data(format!("Base {} has no segment {}", base_timestamp, file_name)))?;
+            let base_words = read_segment_words(store, base_timestamp, base_segment, field)?;
+            decode_delta(&data, &base_words)
+        },
+        encoding => Err(invalid_data(format!("Unknown encoding {:?} for {}/{}", encoding, timestamp, file_name))),
+    }
+}
+
+fn encode_delta(page_flags: &[u64], base_flags: &[u64]) -> Vec<u8> {
+    let xored: Vec<u64> = page_flags.iter().zip(base_flags.iter()).map(|(a, b)| a ^ b).collect();
+    let mut encoded: Vec<u8> = Vec::new();
+    let mut idx = 0;
+    while idx < xored.len() {
+        let zeros = xored[idx..].iter().take_while(|&&word| word == 0).count();
+        idx += zeros;
+        let literals = xored[idx..].iter().take_while(|&&word| word != 0).count();
+        let mut header = [0u8; 8];
+        LittleEndian::write_u32(&mut header[0..4], zeros as u32);
+        LittleEndian::write_u32(&mut header[4..8], literals as u32);
+        encoded.extend_from_slice(&header);
+        encoded.extend_from_slice(&words_to_bytes(&xored[idx..idx + literals]));
+        idx += literals;
+    }
+    encoded
+}
+
+fn decode_delta(encoded: &[u8], base_flags: &[u64]) -> io::Result<Vec<u64>> {
+    let mut page_flags: Vec<u64> = Vec::with_capacity(base_flags.len());
+    let mut pos = 0;
+    while pos + 8 <= encoded.len() {
+        let zeros = LittleEndian::read_u32(&encoded[pos..pos + 4]) as usize;
+        let literals = LittleEndian::read_u32(&encoded[pos + 4..pos + 8]) as usize;
+        pos += 8;
+        if pos + literals * 8 > encoded.len() || page_flags.len() + zeros + literals > base_flags.len() {
+            return Err(invalid_data("Truncated or oversized delta".to_string()));
+        }
+        let idx = page_flags.len();
+        page_flags.extend_from_slice(&base_flags[idx..idx + zeros]);
+        for (word, base) in bytes_to_words(&encoded[pos..pos + literals * 8]).iter().zip(&base_flags[idx + zeros..]) {
+            page_flags.push(word ^ base);
+        }
+        pos += literals * 8;
+    }
+    if page_flags.len() != base_flags.len() {
+        return Err(invalid_data("Delta doesn't cover the whole segment".to_string()));
+    }
+    Ok(page_flags)
+}
+
+fn words_to_bytes(words: &[u64]) -> Vec<u8> {
+    let mut bytes: Vec<u8> = vec![0; 8 * words.len()];
+    LittleEndian::write_u64_into(words, &mut bytes);
+    bytes
+}
+
+fn bytes_to_words(bytes: &[u8]) -> Vec<u64> {
+    let mut words: Vec<u64> = vec![0; bytes.len() / 8];
+    LittleEndian::read_u64_into(&bytes[..words.len() * 8], &mut words);
+    words
+}
+
+fn lz4_compress(data: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut compressed: Vec<u8> = Vec::new();
+    let mut encoder = EncoderBuilder::new()
+        .level(4)
+        .build(&mut compressed)?;
+    io::copy(&mut &data[..], &mut encoder)?;
+    encoder.finish().1?;
+    Ok(compressed)
+}
+
+fn lz4_decompress(data: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut decompressed: Vec<u8> = Vec::new();
+    Decoder::new(data)?.read_to_end(&mut decompressed)?;
+    Ok(decompressed)
+}
+
+fn parse_address(value: &JsonValue) -> io::Result<usize> {
+    let address = value.as_str().unwrap_or("");
+    usize::from_str_radix(address.trim_start_matches("0x"), 16)
+        .map_err(|e| invalid_data(format!("Bad address {:?}: {:?}", address, e)))
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// One broken sink shouldn't stop the others from getting the snapshot. Returns
+// whether every store took it.
+fn put_all(stores: &[Box<dyn SnapshotStore>], key: &str, data: &[u8]) -> bool {
+    let mut stored = true;
+    for store in stores {
+        if let Err(e) = store.put(key, data) {
+            error!("Unable to persist {} to {}: {:?}", key, store.describe(), e);
+            stored = false;
+        }
+    }
+    stored
+}
+
+fn checksum(data: &[u8]) -> u64 {
+    let mut hasher = XxHash64::with_seed(0);
+    hasher.write(data);
+    hasher.finish()
+}
+
+// What each bit of the persisted page flags means.
+fn flag_bits() -> JsonValue {
+    object!{
+        "present" => super::PRESENT_PAGE_BIT,
+        "swapped" => super::SWAPPED_PAGE_BIT,
+        "lru" => super::LRU_PAGE_BIT,
+        "ksm" => super::MERGED_PAGE_BIT,
+        "zero" => super::ZERO_PAGE_BIT,
+        "active" => super::ACTIVE_PAGE_BIT,
+        "pattern" => super::PATTERN_PAGE_BIT,
+        "compression_bucket" => object!{
+            "shift" => super::COMPRESSION_BUCKET_SHIFT,
+            "mask" => super::COMPRESSION_BUCKET_MASK,
+            "unit" => "eighths of a page, 0 = not estimated",
+            "incompressible" => super::COMPRESSION_BUCKET_INCOMPRESSIBLE,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::testing::memory;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use super::super::store::{FlakyStore, MemoryStore};
+
+    #[test]
+    fn delta_round_trip() {
+        let base: Vec<u64> = (0..1000).map(|idx| idx * 7).collect();
+        let mut page_flags = base.clone();
+        // Changes at the start, a run in the middle and the very last word.
+        page_flags[0] ^= 1;
+        for flags in &mut page_flags[400..450] {
+            *flags |= 1 << 63;
+        }
+        page_flags[999] = 0;
+        let encoded = encode_delta(&page_flags, &base);
+        assert_eq!(decode_delta(&encoded, &base).unwrap(), page_flags);
+        // Literal runs only hold changed words.
+        assert_eq!(encoded.len(), 3 * 8 + 52 * 8);
+    }
+
+    #[test]
+    fn delta_edge_cases() {
+        let base: Vec<u64> = vec![5; 64];
+        // Identical: one run of zeros and no literals.
+        let encoded = encode_delta(&base, &base);
+        assert_eq!(encoded.len(), 8);
+        assert_eq!(decode_delta(&encoded, &base).unwrap(), base);
+        // Everything changed: no zeros.
+        let changed: Vec<u64> = vec![6; 64];
+        assert_eq!(decode_delta(&encode_delta(&changed, &base), &base).unwrap(), changed);
+        // Empty segment.
+        assert_eq!(decode_delta(&encode_delta(&[], &[]), &[]).unwrap(), Vec::<u64>::new());
+    }
+
+    #[test]
+    fn delta_rejects_bad_input() {
+        let base: Vec<u64> = vec![1; 16];
+        let mut changed = base.clone();
+        changed[8] = 2;
+        let encoded = encode_delta(&changed, &base);
+        // Cut off in the middle of the literals.
+        assert!(decode_delta(&encoded[..encoded.len() - 4], &base).is_err());
+        // Against a base of a different size.
+        assert!(decode_delta(&encoded, &base[..8]).is_err());
+        assert!(decode_delta(&encoded, &vec![1; 32]).is_err());
+    }
+
+    #[test]
+    fn deltas_are_based_on_keyframes() {
+        let store = MemoryStore::new();
+        let stores: Vec<Box<dyn SnapshotStore>> = vec![Box::new(store)];
+        let mut persister = Persister::new(1, 1, 10, stores, RetentionPolicy::default());
+        let mut flags: Vec<u64> = vec![1 << 63; 32];
+        for secs in 0..4 {
+            flags[secs as usize] |= 1 << 58;
+            persister.write_process_memory(&memory(secs, vec![(0x1000, flags.clone())]), &StatsRecord::default()).unwrap();
+        }
+        let store = &*persister.stores[0];
+        let snapshots = list_snapshots(store).unwrap();
+        assert_eq!(snapshots.len(), 4);
+        for timestamp in &snapshots[1..] {
+            let manifest = read_manifest(store, timestamp).unwrap();
+            assert_eq!(manifest["segments"][0]["encoding"], DELTA_ENCODING);
+            assert_eq!(manifest["segments"][0]["base"], snapshots[0].as_str());
+        }
+        assert_eq!(read_process_memory(store, &snapshots[3]).unwrap().segments[0].page_flags, flags);
+    }
+
+    #[test]
+    fn deltas_of_stable_memory_are_small() {
+        let stores: Vec<Box<dyn SnapshotStore>> = vec![Box::new(MemoryStore::new())];
+        let mut persister = Persister::new(1, 1, 10, stores, RetentionPolicy::default());
+        let pages = 10000;
+        let mut memory = memory(0, vec![(0x1000, (0..pages).map(|idx| 1 << 63 | (idx as u64 & 1) << 58).collect())]);
+        // Hashes look random, as real ones do.
+        memory.segments[0].content_hashes = (0..pages).map(|idx| {
+            let mut hasher = XxHash64::with_seed(0);
+            hasher.write_usize(idx);
+            hasher.finish()
+        }).collect();
+        persister.write_process_memory(&memory, &StatsRecord::default()).unwrap();
+        // 1% of pages change state and contents.
+        memory.timestamp += Duration::seconds(1);
+        for idx in (0..pages).step_by(100) {
+            memory.segments[0].page_flags[idx] ^= 1 << 58;
+            memory.segments[0].content_hashes[idx] ^= 0xffff;
+        }
+        persister.write_process_memory(&memory, &StatsRecord::default()).unwrap();
+
+        let store = &*persister.stores[0];
+        let snapshots = list_snapshots(store).unwrap();
+        let stored = |timestamp: &str| snapshot_bytes(store, timestamp, &read_manifest(store, timestamp).unwrap()).unwrap();
+        let (keyframe, delta) = (stored(&snapshots[0]), stored(&snapshots[1]));
+        assert!(delta * 10 < keyframe, "delta {} bytes, keyframe {} bytes", delta, keyframe);
+        let manifest = read_manifest(store, &snapshots[1]).unwrap();
+        assert_eq!(manifest["segments"][0]["hashes"]["encoding"], DELTA_ENCODING);
+        let read = read_process_memory(store, &snapshots[1]).unwrap();
+        assert_eq!(read.segments[0].page_flags, memory.segments[0].page_flags);
+        assert_eq!(read.segments[0].content_hashes, memory.segments[0].content_hashes);
+    }
+
+    #[test]
+    fn keyframe_after_failed_write() {
+        let failing = Arc::new(AtomicBool::new(false));
+        let stores: Vec<Box<dyn SnapshotStore>> = vec![Box::new(FlakyStore { inner: MemoryStore::new(), failing: failing.clone() })];
+        let mut persister = Persister::new(1, 1, 10, stores, RetentionPolicy::default());
+        let flags: Vec<u64> = vec![1 << 63; 8];
+        persister.write_process_memory(&memory(0, vec![(0x1000, flags.clone())]), &StatsRecord::default()).unwrap();
+        failing.store(true, Ordering::SeqCst);
+        persister.write_process_memory(&memory(1, vec![(0x1000, flags.clone())]), &StatsRecord::default()).unwrap();
+        failing.store(false, Ordering::SeqCst);
+        persister.write_process_memory(&memory(2, vec![(0x1000, flags.clone())]), &StatsRecord::default()).unwrap();
+        let store = &*persister.stores[0];
+        let snapshots = list_snapshots(store).unwrap();
+        assert_eq!(snapshots.len(), 2);
+        assert_eq!(read_manifest(store, &snapshots[1]).unwrap()["keyframe"], true);
+        assert_eq!(read_process_memory(store, &snapshots[1]).unwrap().segments[0].page_flags, flags);
+    }
+
+    #[test]
+    fn retention_removes_whole_chains() {
+        let stores: Vec<Box<dyn SnapshotStore>> = vec![Box::new(MemoryStore::new())];
+        let retention = RetentionPolicy { max_total_bytes: Some(1), ..Default::default() };
+        let mut persister = Persister::new(1, 1, 3, stores, retention);
+        let mut flags: Vec<u64> = vec![1 << 63; 16];
+        for secs in 0..8 {
+            flags[secs as usize] |= 1 << 58;
+            persister.write_process_memory(&memory(secs, vec![(0x1000, flags.clone())]), &StatsRecord::default()).unwrap();
+        }
+        // Chains of 3; only the live one, started by the 7th snapshot, is left.
+        let store = &*persister.stores[0];
+        let snapshots = list_snapshots(store).unwrap();
+        assert_eq!(snapshots.len(), 2);
+        assert_eq!(read_manifest(store, &snapshots[0]).unwrap()["keyframe"], true);
+        assert_eq!(read_process_memory(store, &snapshots[1]).unwrap().segments[0].page_flags, flags);
+        assert_eq!(persister.chains[0].as_ref().unwrap().len(), 1);
+    }
+
+    // Counts list calls.
+    struct ListCountingStore {
+        inner: MemoryStore,
+        lists: std::sync::atomic::AtomicUsize,
+    }
+
+    impl SnapshotStore for ListCountingStore {
+        fn put(&self, key: &str, data: &[u8]) -> io::Result<()> {
+            self.inner.put(key, data)
+        }
+        fn get(&self, key: &str) -> io::Result<Vec<u8>> {
+            self.inner.get(key)
+        }
+        fn list(&self, prefix: &str) -> io::Result<Vec<String>> {
+            self.lists.fetch_add(1, Ordering::SeqCst);
+            self.inner.list(prefix)
+        }
+        fn delete(&self, key: &str) -> io::Result<()> {
+            self.inner.delete(key)
+        }
+        fn describe(&self) -> String {
+            "counting".to_string()
+        }
+    }
+
+    #[test]
+    fn retention_lists_once() {
+        use chrono::TimeZone;
+        let store = ListCountingStore { inner: MemoryStore::new(), lists: Default::default() };
+        let mut chains = Vec::new();
+        for secs in 0..6 {
+            let taken = Utc.timestamp_opt(secs, 0).unwrap();
+            let timestamp = taken.to_rfc3339_opts(SecondsFormat::Secs, true);
+            for name in &[MANIFEST_NAME, "0.flags", STATS_NAME] {
+                store.put(&format!("{}/{}", timestamp, name), b"x").unwrap();
+            }
+            add_snapshot(&mut chains, timestamp, taken, secs % 2 == 0, 10);
+        }
+        // Not part of any snapshot
+        store.put("notes", b"x").unwrap();
+        let policy = RetentionPolicy { max_total_bytes: Some(20), ..Default::default() };
+        enforce_retention(&store, &mut chains, &policy, 2).unwrap();
+        assert_eq!(store.lists.load(Ordering::SeqCst), 1);
+        assert_eq!(chains.len(), 1);
+        // Nothing more has expired, so nothing is listed.
+        enforce_retention(&store, &mut chains, &policy, 2).unwrap();
+        assert_eq!(store.lists.load(Ordering::SeqCst), 1);
+        let left: Vec<String> = store.list("").unwrap().into_iter().filter(|key| key.ends_with(MANIFEST_NAME)).collect();
+        assert_eq!(left, vec![
+            format!("1970-01-01T00:00:04Z/{}", MANIFEST_NAME),
+            format!("1970-01-01T00:00:05Z/{}", MANIFEST_NAME)]);
+        assert_eq!(store.list("").unwrap().len(), 7);
+    }
+
+    #[test]
+    fn memory_store_round_trip() {
+        let stores: Vec<Box<dyn SnapshotStore>> = vec![Box::new(MemoryStore::new())];
+        let mut persister = Persister::new(1, 1, 2, stores, RetentionPolicy::default());
+        let mut memory = memory(0, vec![(0x1000, vec![1 << 63, 1 << 62, 0, 1 << 63 | 1 << 58]), (0x100000, vec![1 << 63; 3])]);
+        memory.segments[0].path = "[heap]".to_string();
+        memory.segments[0].content_hashes = vec![11, 0, 0, 12];
+        memory.segments[1].pattern_words.insert(2, 0x0101010101010101);
+        memory.segments[1].page_types = vec![super::super::PageType::Anon, super::super::PageType::Buddy, super::super::PageType::NoPage];
+        memory.segments[1].pattern_words.insert(0, 0xdeadbeef);
+        memory.segments[1].guest_mappings.push(super::super::GuestMapping {
+            segment_offset: 4096,
+            guest_phys_addr: 0x80000000,
+            size: 8192,
+        });
+        let stats = StatsRecord { total_pages: 7, present_pages: 4, ..Default::default() };
+        persister.write_process_memory(&memory, &stats).unwrap();
+        memory.timestamp += Duration::seconds(1);
+        memory.segments[1].page_flags[2] = 0;
+        persister.write_process_memory(&memory, &stats).unwrap();
+
+        let store = &*persister.stores[0];
+        let snapshots = list_snapshots(store).unwrap();
+        assert_eq!(snapshots.len(), 2);
+        let read = read_process_memory(store, &snapshots[1]).unwrap();
+        assert_eq!(read.timestamp, memory.timestamp);
+        assert_eq!(read.segments.len(), 2);
+        for (read, written) in read.segments.iter().zip(memory.segments.iter()) {
+            assert_eq!(read.addr_start, written.addr_start);
+            assert_eq!(read.path, written.path);
+            assert_eq!(read.page_flags, written.page_flags);
+            assert_eq!(read.guest_mappings.len(), written.guest_mappings.len());
+        }
+        assert_eq!(read.segments[0].content_hashes, vec![11, 0, 0, 12]);
+        assert!(read.segments[1].content_hashes.is_empty());
+        assert!(read.segments[0].pattern_words.is_empty());
+        assert!(read.segments[0].page_types.is_empty());
+        assert_eq!(read.segments[1].page_types, memory.segments[1].page_types);
+        assert_eq!(read.segments[1].pattern_words, memory.segments[1].pattern_words);
+        assert_eq!(read.segments[1].guest_phys_addr(1), Some(0x80000000));
+        assert_eq!(read.segments[1].guest_phys_addr(0), None);
+        let stats_csv = String::from_utf8(store.get(&format!("{}/{}", snapshots[1], STATS_NAME)).unwrap()).unwrap();
+        assert_eq!(stats_csv, String::from_utf8(stats.to_csv().unwrap()).unwrap());
+    }
 }
@@ -0,0 +1,475 @@
+// Pushes each sample's statistics to a collector, for hosts which can't be
+// scraped. StatsD goes over UDP with DogStatsD-style tags; OTLP is the JSON
+// encoding over HTTP, posted to <endpoint>/v1/metrics. `receive` is a stand-in
+// collector which logs whatever arrives, for trying this out locally.
+
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs, UdpSocket};
+use std::time::Duration;
+use json::JsonValue;
+use sys_info::hostname;
+
+use super::statistics::{StatsRecord, StatsRow};
+
+const METRIC_PREFIX: &str = "wss.";
+// Keep StatsD datagrams under a typical MTU.
+const MAX_DATAGRAM: usize = 1432;
+const OTLP_PATH: &str = "/v1/metrics";
+// Cumulative counts rather than levels.
+const COUNTERS: [&str; 6] = ["minflt", "majflt", "pswpin", "pswpout", "pgmajfault", "workingset_refault"];
+// Counted since the process started in process mode; the rest since boot.
+const PROCESS_COUNTERS: [&str; 2] = ["minflt", "majflt"];
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+// Far more than one sample's metrics; anything bigger isn't ours.
+const MAX_OTLP_BODY: usize = 4 << 20;
+
+#[derive(Clone, Copy, Debug)]
+pub enum PushProtocol {
+    Statsd,
+    Otlp,
+}
+
+impl std::str::FromStr for PushProtocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<PushProtocol, String> {
+        match s {
+            "statsd" => Ok(PushProtocol::Statsd),
+            "otlp" => Ok(PushProtocol::Otlp),
+            _ => Err(format!("Unsupported push protocol: {}", s)),
+        }
+    }
+}
+
+pub struct Pusher {
+    protocol: PushProtocol,
+    // host:port for StatsD, http://host[:port][/path] for OTLP
+    endpoint: String,
+    hostname: String,
+    socket: Option<UdpSocket>,
+    // Seconds since the epoch, for OTLP sums' startTimeUnixNano
+    boot_time: u64,
+}
+
+impl Pusher {
+    pub fn new(protocol: PushProtocol, endpoint: &str) -> io::Result<Pusher> {
+        let socket = match protocol {
+            PushProtocol::Statsd => {
+                let socket = UdpSocket::bind("0.0.0.0:0")?;
+                socket.connect(endpoint)?;
+                Some(socket)
+            },
+            PushProtocol::Otlp => {
+                parse_http_endpoint(endpoint)?;
+                None
+            },
+        };
+        Ok(Pusher {
+            protocol: protocol,
+            endpoint: endpoint.to_string(),
+            hostname: hostname().unwrap_or_default(),
+            socket: socket,
+            boot_time: boot_time().unwrap_or_else(|e| {
+                warn!("Unable to read boot time: {:?}", e);
+                0
+            }),
+        })
+    }
+
+    // pid is None in host mode.
+    pub fn push(&self, pid: Option<i32>, stats: &StatsRecord) -> io::Result<()> {
+        let cgroup = cgroup(pid);
+        let pid = match pid {
+            Some(pid) => pid.to_string(),
+            None => "host".to_string(),
+        };
+        let values: Vec<(&str, String)> = StatsRecord::HEADER.iter().cloned()
+            .zip(stats.to_row())
+            .filter(|(name, _value)| *name != "timestamp")
+            .collect();
+        match self.protocol {
+            PushProtocol::Statsd => self.push_statsd(&pid, &cgroup, &values),
+            PushProtocol::Otlp => {
+                let process_start = match pid.parse::<i32>() {
+                    Ok(pid) => process_start_time(pid, self.boot_time).unwrap_or(self.boot_time),
+                    Err(_) => self.boot_time,
+                };
+                self.push_otlp(&pid, &cgroup, stats.timestamp, process_start, &values)
+            },
+        }
+    }
+
+    fn push_statsd(&self, pid: &str, cgroup: &str, values: &[(&str, String)]) -> io::Result<()> {
+        let socket = self.socket.as_ref().unwrap();
+        for datagram in self.statsd_datagrams(pid, cgroup, values) {
+            socket.send(datagram.as_bytes())?;
+        }
+        debug!("Pushed {} metrics to statsd at {}", values.len(), self.endpoint);
+        Ok(())
+    }
+
+    // One line per metric, as many lines per datagram as fit.
+    fn statsd_datagrams(&self, pid: &str, cgroup: &str, values: &[(&str, String)]) -> Vec<String> {
+        let tags = format!("pid:{},cgroup:{},hostname:{}", pid, cgroup, self.hostname);
+        let mut datagrams = Vec::new();
+        let mut datagram = String::new();
+        for (name, value) in values {
+            let line = format!("{}{}:{}|g|#{}", METRIC_PREFIX, name, value, tags);
+            if !datagram.is_empty() && datagram.len() + 1 + line.len() > MAX_DATAGRAM {
+                datagrams.push(std::mem::take(&mut datagram));
+            }
+            if !datagram.is_empty() {
+                datagram.push('\n');
+            }
+            datagram.push_str(&line);
+        }
+        if !datagram.is_empty() {
+            datagrams.push(datagram);
+        }
+        datagrams
+    }
+
+    fn push_otlp(&self, pid: &str, cgroup: &str, timestamp: i64, process_start: u64, values: &[(&str, String)]) -> io::Result<()> {
+        let body = self.otlp_body(pid, cgroup, timestamp, process_start, values);
+        http_post(&self.endpoint, body.dump().as_bytes())?;
+        debug!("Pushed {} metrics to OTLP at {}", values.len(), self.endpoint);
+        Ok(())
+    }
+
+    // process_start is when the process counters started, in seconds since the epoch.
+    fn otlp_body(&self, pid: &str, cgroup: &str, timestamp: i64, process_start: u64, values: &[(&str, String)]) -> JsonValue {
+        let unix_nano = |secs: u64| (secs * 1_000_000_000).to_string();
+        let metrics: Vec<JsonValue> = values.iter().map(|(name, value)| {
+            let mut data_point = object!{
+                "timeUnixNano" => unix_nano(timestamp as u64),
+                "asInt" => value.clone(),
+                "attributes" => JsonValue::Array(vec![attribute("pid", pid), attribute("cgroup", cgroup)]),
+            };
+            let mut metric = object!{ "name" => format!("{}{}", METRIC_PREFIX, name) };
+            if COUNTERS.contains(name) {
+                let start = if PROCESS_COUNTERS.contains(name) { process_start } else { self.boot_time };
+                data_point["startTimeUnixNano"] = unix_nano(start).into();
+            }
+            let data_points = JsonValue::Array(vec![data_point]);
+            if COUNTERS.contains(name) {
+                metric["sum"] = object!{
+                    "dataPoints" => data_points,
+                    // AGGREGATION_TEMPORALITY_CUMULATIVE
+                    "aggregationTemporality" => 2,
+                    "isMonotonic" => true,
+                };
+            } else {
+                metric["gauge"] = object!{ "dataPoints" => data_points };
+            }
+            metric
+        }).collect();
+        object!{
+            "resourceMetrics" => JsonValue::Array(vec![object!{
+                "resource" => object!{
+                    "attributes" => JsonValue::Array(vec![
+                        attribute("host.name", &self.hostname),
+                        attribute("service.name", env!("CARGO_PKG_NAME"))]),
+                },
+                "scopeMetrics" => JsonValue::Array(vec![object!{
+                    "scope" => object!{
+                        "name" => env!("CARGO_PKG_NAME"),
+                        "version" => env!("CARGO_PKG_VERSION"),
+                    },
+                    "metrics" => metrics,
+                }]),
+            }]),
+        }
+    }
+}
+
+fn attribute(key: &str, value: &str) -> JsonValue {
+    object!{
+        "key" => key,
+        "value" => object!{ "stringValue" => value },
+    }
+}
+
+// The memory controller's cgroup; "/" in host mode or if it can't be read.
+fn cgroup(pid: Option<i32>) -> String {
+    let path = match pid {
+        Some(pid) => format!("/proc/{}/cgroup", pid),
+        None => return "/".to_string(),
+    };
+    let mut contents = String::new();
+    if let Err(e) = File::open(&path).and_then(|mut file| file.read_to_string(&mut contents)) {
+        debug!("Unable to read {}: {:?}", path, e);
+        return "/".to_string();
+    }
+    // Lines are "<id>:<controllers>:<path>"; cgroup v2 has a single "0::<path>".
+    contents.lines()
+        .filter_map(|line| {
+            let mut fields = line.splitn(3, ':');
+            let _id = fields.next()?;
+            let controllers = fields.next()?;
+            let path = fields.next()?;
+            Some((controllers, path))
+        })
+        .find(|(controllers, _path)| controllers.is_empty() || controllers.split(',').any(|c| c == "memory"))
+        .map(|(_controllers, path)| path.to_string())
+        .unwrap_or_else(|| "/".to_string())
+}
+
+// From the btime line of /proc/stat, in seconds since the epoch.
+fn boot_time() -> io::Result<u64> {
+    let mut contents = String::new();
+    File::open("/proc/stat")?.read_to_string(&mut contents)?;
+    contents.lines()
+        .find(|line| line.starts_with("btime "))
+        .and_then(|line| line["btime ".len()..].trim().parse().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "No btime in /proc/stat"))
+}
+
+// Field 22 of /proc/<pid>/stat is the start time in clock ticks after boot.
+fn process_start_time(pid: i32, boot_time: u64) -> io::Result<u64> {
+    let mut contents = String::new();
+    File::open(format!("/proc/{}/stat", pid))?.read_to_string(&mut contents)?;
+    // The command name is in parentheses and may contain spaces.
+    let after_comm = &contents[contents.rfind(')').map_or(0, |idx| idx + 1)..];
+    let ticks: u64 = after_comm.split_whitespace().nth(19)
+        .and_then(|ticks| ticks.parse().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("Bad /proc/{}/stat", pid)))?;
+    let ticks_per_sec = match unsafe { nix::libc::sysconf(nix::libc::_SC_CLK_TCK) } {
+        ticks_per_sec if ticks_per_sec > 0 => ticks_per_sec as u64,
+        _ => 100,
+    };
+    Ok(boot_time + ticks / ticks_per_sec)
+}
+
+// (host:port, path). Only plain HTTP; put a local collector in front for anything else.
+fn parse_http_endpoint(endpoint: &str) -> io::Result<(String, String)> {
+    let rest = match endpoint.trim_end_matches('/').splitn(2, "://").collect::<Vec<&str>>().as_slice() {
+        ["http", rest] => rest.to_string(),
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                       format!("OTLP endpoint must be http://host[:port][/path]: {}", endpoint))),
+    };
+    let (authority, path) = match rest.find('/') {
+        Some(idx) => (rest[..idx].to_string(), rest[idx..].to_string()),
+        None => (rest.clone(), OTLP_PATH.to_string()),
+    };
+    let authority = if authority.contains(':') { authority } else { format!("{}:4318", authority) };
+    Ok((authority, path))
+}
+
+fn http_post(endpoint: &str, body: &[u8]) -> io::Result<()> {
+    let (authority, path) = parse_http_endpoint(endpoint)?;
+    let mut stream = connect(&authority)?;
+    stream.set_read_timeout(Some(HTTP_TIMEOUT))?;
+    stream.set_write_timeout(Some(HTTP_TIMEOUT))?;
+    write!(stream, "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+           path, authority, body.len())?;
+    stream.write_all(body)?;
+    let mut status_line = String::new();
+    BufReader::new(stream).read_line(&mut status_line)?;
+    match status_line.split_whitespace().nth(1) {
+        Some(status) if status.starts_with('2') => Ok(()),
+        _ => Err(io::Error::new(io::ErrorKind::Other, format!("OTLP push to {} failed: {}", endpoint, status_line.trim_end()))),
+    }
+}
+
+// Like TcpStream::connect, but a collector which doesn't answer can't hold up sampling.
+fn connect(authority: &str) -> io::Result<TcpStream> {
+    let mut last_error = io::Error::new(io::ErrorKind::NotFound, format!("No addresses for {}", authority));
+    for addr in authority.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, HTTP_TIMEOUT) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error)
+}
+
+// Listens on addr and logs every metric received. Never returns unless the
+// socket can't be bound.
+pub fn receive(protocol: PushProtocol, addr: &str) -> io::Result<()> {
+    match protocol {
+        PushProtocol::Statsd => {
+            let socket = UdpSocket::bind(addr)?;
+            info!("Listening for StatsD on udp://{}", addr);
+            loop {
+                let (from, lines) = receive_statsd(&socket)?;
+                for line in lines {
+                    info!("{}: {}", from, line);
+                }
+            }
+        },
+        PushProtocol::Otlp => {
+            let listener = TcpListener::bind(addr)?;
+            info!("Listening for OTLP/HTTP on http://{}{}", addr, OTLP_PATH);
+            for stream in listener.incoming() {
+                match stream.and_then(receive_otlp) {
+                    Ok((request_line, metrics)) => {
+                        info!("{}", request_line);
+                        for metric in metrics {
+                            info!("  {}", metric);
+                        }
+                    },
+                    Err(e) => warn!("Bad OTLP request: {:?}", e),
+                }
+            }
+            Ok(())
+        },
+    }
+}
+
+// One datagram: the sender and its lines.
+fn receive_statsd(socket: &UdpSocket) -> io::Result<(SocketAddr, Vec<String>)> {
+    let mut buf = [0u8; 65536];
+    let (len, from) = socket.recv_from(&mut buf)?;
+    Ok((from, String::from_utf8_lossy(&buf[..len]).lines().map(|line| line.to_string()).collect()))
+}
+
+// One request: "<sender>: <request line>" and a "name{attributes} value" line per data point.
+fn receive_otlp(stream: TcpStream) -> io::Result<(String, Vec<String>)> {
+    let from = stream.peer_addr()?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut content_length = 0;
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        let lower = header.to_lowercase();
+        if lower.starts_with("content-length:") {
+            content_length = lower["content-length:".len()..].trim().parse().unwrap_or(0);
+        }
+        header.clear();
+    }
+    if content_length > MAX_OTLP_BODY {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                  format!("Request body of {} bytes is over {}", content_length, MAX_OTLP_BODY)));
+    }
+    let mut body = vec![0u8; content_length];
+    reader.read_exact(&mut body)?;
+    let mut stream = stream;
+    write!(stream, "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: 2\r\nConnection: close\r\n\r\n{{}}")?;
+
+    let request = json::parse(&String::from_utf8_lossy(&body))
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", e)))?;
+    let mut metrics = Vec::new();
+    for resource in request["resourceMetrics"].members() {
+        for scope in resource["scopeMetrics"].members() {
+            for metric in scope["metrics"].members() {
+                let data_points = if metric["sum"].is_null() { &metric["gauge"]["dataPoints"] } else { &metric["sum"]["dataPoints"] };
+                for point in data_points.members() {
+                    let attributes: Vec<String> = point["attributes"].members()
+                        .map(|attribute| format!("{}={}", attribute["key"], attribute["value"]["stringValue"]))
+                        .collect();
+                    metrics.push(format!("{}{{{}}} {}", metric["name"], attributes.join(","), point["asInt"]));
+                }
+            }
+        }
+    }
+    Ok((format!("{}: {}", from, request_line.trim_end()), metrics))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pusher(protocol: PushProtocol, endpoint: &str) -> Pusher {
+        let mut pusher = Pusher::new(protocol, endpoint).unwrap();
+        pusher.hostname = "web-1".to_string();
+        pusher.boot_time = 1_600_000_000;
+        pusher
+    }
+
+    fn values(count: usize) -> Vec<(&'static str, String)> {
+        StatsRecord::HEADER[1..].iter().cloned().take(count)
+            .enumerate()
+            .map(|(idx, name)| (name, idx.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn statsd_lines() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let pusher = pusher(PushProtocol::Statsd, &receiver.local_addr().unwrap().to_string());
+        let datagrams = pusher.statsd_datagrams("42", "/user.slice/app.scope", &[("active_pages", "7".to_string()), ("minflt", "9".to_string())]);
+        assert_eq!(datagrams, vec![
+            "wss.active_pages:7|g|#pid:42,cgroup:/user.slice/app.scope,hostname:web-1\n\
+             wss.minflt:9|g|#pid:42,cgroup:/user.slice/app.scope,hostname:web-1"]);
+    }
+
+    #[test]
+    fn statsd_datagrams_fit_the_mtu() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let pusher = pusher(PushProtocol::Statsd, &receiver.local_addr().unwrap().to_string());
+        let values = values(StatsRecord::HEADER.len() - 1);
+        let datagrams = pusher.statsd_datagrams("host", "/", &values);
+        assert!(datagrams.len() > 1);
+        assert!(datagrams.iter().all(|datagram| datagram.len() <= MAX_DATAGRAM));
+        // Lines are never split across datagrams.
+        let lines: Vec<&str> = datagrams.iter().flat_map(|datagram| datagram.lines()).collect();
+        assert_eq!(lines.len(), values.len());
+        assert!(lines.iter().all(|line| line.starts_with(METRIC_PREFIX) && line.ends_with("|g|#pid:host,cgroup:/,hostname:web-1")));
+    }
+
+    #[test]
+    fn otlp_json_body() {
+        let pusher = pusher(PushProtocol::Otlp, "http://collector");
+        let body = pusher.otlp_body("42", "/", 1_700_000_000, 1_650_000_000,
+                                    &[("active_pages", "7".to_string()), ("minflt", "9".to_string()), ("pswpin", "3".to_string())]);
+        let resource = &body["resourceMetrics"][0];
+        assert_eq!(resource["resource"]["attributes"][0]["key"], "host.name");
+        assert_eq!(resource["resource"]["attributes"][0]["value"]["stringValue"], "web-1");
+        let metrics = &resource["scopeMetrics"][0]["metrics"];
+        assert_eq!(metrics.len(), 3);
+
+        let gauge = &metrics[0];
+        assert_eq!(gauge["name"], "wss.active_pages");
+        assert!(gauge["sum"].is_null());
+        let point = &gauge["gauge"]["dataPoints"][0];
+        assert_eq!(point["timeUnixNano"], "1700000000000000000");
+        assert_eq!(point["asInt"], "7");
+        assert!(point["startTimeUnixNano"].is_null());
+        assert_eq!(point["attributes"][0]["key"], "pid");
+        assert_eq!(point["attributes"][0]["value"]["stringValue"], "42");
+        assert_eq!(point["attributes"][1]["key"], "cgroup");
+
+        // Process counters start with the process, system counters at boot.
+        let minflt = &metrics[1]["sum"];
+        assert_eq!(minflt["aggregationTemporality"], 2);
+        assert_eq!(minflt["isMonotonic"], true);
+        assert_eq!(minflt["dataPoints"][0]["startTimeUnixNano"], "1650000000000000000");
+        assert_eq!(metrics[2]["sum"]["dataPoints"][0]["startTimeUnixNano"], "1600000000000000000");
+    }
+
+    #[test]
+    fn default_otlp_endpoint() {
+        assert_eq!(parse_http_endpoint("http://collector").unwrap(), ("collector:4318".to_string(), OTLP_PATH.to_string()));
+        assert_eq!(parse_http_endpoint("http://127.0.0.1:9000/otlp/").unwrap(), ("127.0.0.1:9000".to_string(), "/otlp".to_string()));
+        assert!(parse_http_endpoint("https://collector").is_err());
+    }
+
+    #[test]
+    fn receivers_get_pushed_samples() {
+        let stats = StatsRecord { timestamp: 1_700_000_000, active_pages: 7, ..Default::default() };
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let receiver = std::thread::spawn(move || receive_otlp(listener.accept().unwrap().0).unwrap());
+        pusher(PushProtocol::Otlp, &endpoint).push(None, &stats).unwrap();
+        let (request_line, metrics) = receiver.join().unwrap();
+        assert!(request_line.ends_with(&format!("POST {} HTTP/1.1", OTLP_PATH)));
+        assert!(metrics.contains(&"wss.active_pages{pid=host,cgroup=/} 7".to_string()), "{:?}", metrics);
+
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        pusher(PushProtocol::Statsd, &socket.local_addr().unwrap().to_string()).push(None, &stats).unwrap();
+        let (_from, lines) = receive_statsd(&socket).unwrap();
+        assert!(lines.contains(&"wss.active_pages:7|g|#pid:host,cgroup:/,hostname:web-1".to_string()), "{:?}", lines);
+    }
+
+    #[test]
+    fn own_start_time_is_after_boot() {
+        let boot_time = boot_time().unwrap();
+        let start = process_start_time(std::process::id() as i32, boot_time).unwrap();
+        let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs();
+        assert!(boot_time <= start && start <= now);
+    }
+}
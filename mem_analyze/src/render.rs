@@ -0,0 +1,446 @@
+// Pictures of persisted snapshots: a page map frame per snapshot, optionally
+// combined into an animated GIF or APNG, and a stacked chart of the page counts
+// over time. Everything is drawn with one small palette, so frames are written
+// as indexed images.
+//
+// Page map colours, as create_gif.rb used to draw them:
+//   active: red, idle: green (brighter when the page is all zeroes),
+//   swapped: grey, not present: black.
+// Segments are laid end to end, ignoring the gaps between them, and the page map
+// is wrapped into a square. Big address spaces are scaled down so the side is at
+// most max_side pixels; a pixel then shows its hottest page.
+
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use chrono::{DateTime, Utc};
+
+use super::persist;
+use super::store::SnapshotStore;
+
+pub const BLACK: u8 = 0;
+pub const ACTIVE: u8 = 1;
+pub const ACTIVE_ZERO: u8 = 2;
+pub const IDLE: u8 = 3;
+pub const IDLE_ZERO: u8 = 4;
+pub const SWAPPED: u8 = 5;
+const WHITE: u8 = 6;
+const GRID: u8 = 7;
+const PALETTE: [u8; 24] = [
+    0x00, 0x00, 0x00,
+    0x7F, 0x00, 0x00,
+    0xFF, 0x00, 0x00,
+    0x00, 0x7F, 0x00,
+    0x00, 0xFF, 0x00,
+    0x60, 0x60, 0x60,
+    0xFF, 0xFF, 0xFF,
+    0x30, 0x30, 0x30,
+];
+
+const CHART_WIDTH: usize = 800;
+const CHART_HEIGHT: usize = 400;
+
+#[derive(Clone, Copy, Debug)]
+pub enum Animation {
+    Gif,
+    Apng,
+    None,
+}
+
+impl std::str::FromStr for Animation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Animation, String> {
+        match s {
+            "gif" => Ok(Animation::Gif),
+            "apng" => Ok(Animation::Apng),
+            "none" => Ok(Animation::None),
+            _ => Err(format!("Unsupported animation format: {}", s)),
+        }
+    }
+}
+
+pub struct RenderOptions {
+    pub animation: Animation,
+    // Also write each frame as frame-NNN.png
+    pub frames: bool,
+    pub frame_delay_ms: u16,
+    pub max_side: usize,
+}
+
+// An indexed-colour image.
+struct Canvas {
+    width: usize,
+    height: usize,
+    pixels: Vec<u8>,
+}
+
+impl Canvas {
+    fn new(width: usize, height: usize) -> Canvas {
+        Canvas { width: width, height: height, pixels: vec![BLACK; width * height] }
+    }
+
+    fn set(&mut self, x: usize, y: usize, colour: u8) {
+        if x < self.width && y < self.height {
+            self.pixels[y * self.width + x] = colour;
+        }
+    }
+
+    fn fill(&mut self, x: usize, y: usize, width: usize, height: usize, colour: u8) {
+        for row in y..y + height {
+            for column in x..x + width {
+                self.set(column, row, colour);
+            }
+        }
+    }
+
+    // Digits and ':' only, which is all the labels need. The text's bottom-right
+    // corner is placed at (right, bottom).
+    fn text(&mut self, right: usize, bottom: usize, text: &str, scale: usize, colour: u8) {
+        let advance = 4 * scale;
+        let mut x = right.saturating_sub(text.len() * advance);
+        let y = bottom.saturating_sub(5 * scale);
+        for c in text.chars() {
+            if let Some(glyph) = glyph(c) {
+                for (row, bits) in glyph.iter().enumerate() {
+                    for column in 0..3 {
+                        if bits & (0b100 >> column) != 0 {
+                            self.fill(x + column * scale, y + row * scale, scale, scale, colour);
+                        }
+                    }
+                }
+            }
+            x += advance;
+        }
+    }
+
+    fn write_png(&self, path: &Path) -> io::Result<()> {
+        self.encode_png(BufWriter::new(File::create(path)?))
+    }
+
+    fn encode_png<W: Write>(&self, out: W) -> io::Result<()> {
+        let mut encoder = png_encoder(out, self.width, self.height);
+        encoder.set_compression(png::Compression::Best);
+        let mut writer = encoder.write_header().map_err(render_error)?;
+        writer.write_image_data(&self.pixels).map_err(render_error)?;
+        writer.finish().map_err(render_error)
+    }
+}
+
+// 3x5 glyphs, one row per entry, most significant of the 3 bits on the left.
+fn glyph(c: char) -> Option<[u8; 5]> {
+    Some(match c {
+        '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+        '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+        '2' => [0b111, 0b001, 0b111, 0b100, 0b111],
+        '3' => [0b111, 0b001, 0b111, 0b001, 0b111],
+        '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+        '5' => [0b111, 0b100, 0b111, 0b001, 0b111],
+        '6' => [0b111, 0b100, 0b111, 0b101, 0b111],
+        '7' => [0b111, 0b001, 0b010, 0b010, 0b010],
+        '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+        '9' => [0b111, 0b101, 0b111, 0b001, 0b111],
+        ':' => [0b000, 0b010, 0b000, 0b010, 0b000],
+        _ => return None,
+    })
+}
+
+fn png_encoder<W: Write>(out: W, width: usize, height: usize) -> png::Encoder<'static, W> {
+    let mut encoder = png::Encoder::new(out, width as u32, height as u32);
+    encoder.set_color(png::ColorType::Indexed);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_palette(PALETTE.to_vec());
+    encoder
+}
+
+// Where each segment goes in the page map, from the newest snapshot.
+struct Layout {
+    // Segment start -> (first page in the map, pages)
+    segments: BTreeMap<usize, (usize, usize)>,
+    pages: usize,
+    // Pages per pixel
+    scale: usize,
+    side: usize,
+    label_height: usize,
+}
+
+impl Layout {
+    fn new(memory: &super::ProcessMemory, max_side: usize) -> Layout {
+        let mut layout_segments = BTreeMap::new();
+        let mut pages = 0;
+        let mut segments: Vec<&super::Segment> = memory.segments.iter().collect();
+        segments.sort_by_key(|segment| segment.addr_start);
+        for segment in segments {
+            layout_segments.insert(segment.addr_start, (pages, segment.page_flags.len()));
+            pages += segment.page_flags.len();
+        }
+        let max_side = std::cmp::max(max_side, 1);
+        let mut scale = 1;
+        while ceil_sqrt((pages + scale - 1) / scale) > max_side {
+            scale *= 2;
+        }
+        let side = std::cmp::max(ceil_sqrt((pages + scale - 1) / scale), 1);
+        Layout {
+            segments: layout_segments,
+            pages: pages,
+            scale: scale,
+            side: side,
+            label_height: std::cmp::max((side + 29) / 30, 7),
+        }
+    }
+}
+
+fn ceil_sqrt(n: usize) -> usize {
+    let mut root = (n as f64).sqrt() as usize;
+    while root * root < n {
+        root += 1;
+    }
+    root
+}
+
+// Hotter pages win when several share a pixel.
+pub fn page_colour(page_flags: u64) -> u8 {
+    let zero = page_flags & (1 << super::ZERO_PAGE_BIT) != 0;
+    if page_flags & (1 << super::PRESENT_PAGE_BIT) != 0 {
+        match (page_flags & (1 << super::ACTIVE_PAGE_BIT) != 0, zero) {
+            (true, false) => ACTIVE,
+            (true, true) => ACTIVE_ZERO,
+            (false, false) => IDLE,
+            (false, true) => IDLE_ZERO,
+        }
+    } else if page_flags & (1 << super::SWAPPED_PAGE_BIT) != 0 {
+        SWAPPED
+    } else {
+        BLACK
+    }
+}
+
+pub fn heat(colour: u8) -> u8 {
+    match colour {
+        ACTIVE => 5,
+        ACTIVE_ZERO => 4,
+        IDLE => 3,
+        IDLE_ZERO => 2,
+        SWAPPED => 1,
+        _ => 0,
+    }
+}
+
+fn page_map(layout: &Layout, memory: &super::ProcessMemory, elapsed: &str) -> Canvas {
+    let mut canvas = Canvas::new(layout.side, layout.side + layout.label_height);
+    for segment in &memory.segments {
+        // A segment which was bigger in this snapshot is cut off, not drawn over its neighbour.
+        let (offset, pages) = match layout.segments.get(&segment.addr_start) {
+            Some(&placement) => placement,
+            None => continue,
+        };
+        for (idx, &page_flags) in segment.page_flags.iter().take(pages).enumerate() {
+            let pixel = (offset + idx) / layout.scale;
+            let colour = page_colour(page_flags);
+            let current = &mut canvas.pixels[pixel];
+            if heat(colour) > heat(*current) {
+                *current = colour;
+            }
+        }
+    }
+    let scale = std::cmp::max((layout.label_height - 2) / 5, 1);
+    canvas.text(layout.side - 1, layout.side + layout.label_height - 1, elapsed, scale, WHITE);
+    canvas
+}
+
+// Fractions of all pages, per snapshot.
+struct Counts {
+    active: f64,
+    idle: f64,
+    swapped: f64,
+    zero: f64,
+}
+
+fn counts(memory: &super::ProcessMemory) -> Counts {
+    let (mut total, mut active, mut idle, mut swapped, mut zero) = (0, 0, 0, 0, 0);
+    for segment in &memory.segments {
+        for &page_flags in &segment.page_flags {
+            total += 1;
+            match page_colour(page_flags) {
+                ACTIVE | ACTIVE_ZERO => active += 1,
+                IDLE | IDLE_ZERO => idle += 1,
+                SWAPPED => swapped += 1,
+                _ => {},
+            }
+            if page_flags & (1 << super::ZERO_PAGE_BIT) != 0 {
+                zero += 1;
+            }
+        }
+    }
+    let total = std::cmp::max(total, 1) as f64;
+    Counts {
+        active: active as f64 / total,
+        idle: idle as f64 / total,
+        swapped: swapped as f64 / total,
+        zero: zero as f64 / total,
+    }
+}
+
+// Active, idle and swapped stacked from the bottom, with the zero-filled share
+// as a white line over the top. Gridlines every 25%.
+fn chart(series: &[Counts]) -> Canvas {
+    let mut canvas = Canvas::new(CHART_WIDTH, CHART_HEIGHT);
+    for quarter in 1..4 {
+        canvas.fill(0, CHART_HEIGHT * quarter / 4, CHART_WIDTH, 1, GRID);
+    }
+    if series.is_empty() {
+        return canvas;
+    }
+    let height = |fraction: f64| (fraction * CHART_HEIGHT as f64).round() as usize;
+    for x in 0..CHART_WIDTH {
+        let counts = &series[x * series.len() / CHART_WIDTH];
+        let mut bottom = CHART_HEIGHT;
+        for &(fraction, colour) in &[(counts.active, ACTIVE), (counts.idle, IDLE), (counts.swapped, SWAPPED)] {
+            let bar = std::cmp::min(height(fraction), bottom);
+            canvas.fill(x, bottom - bar, 1, bar, colour);
+            bottom -= bar;
+        }
+        let zero = std::cmp::min(height(counts.zero), CHART_HEIGHT - 1);
+        canvas.fill(x, CHART_HEIGHT - 1 - zero, 1, 2, WHITE);
+    }
+    canvas
+}
+
+enum AnimationWriter {
+    Gif(gif::Encoder<BufWriter<File>>),
+    Apng(png::Writer<BufWriter<File>>),
+}
+
+impl AnimationWriter {
+    fn create(output_dir: &Path, animation: Animation, layout: &Layout, frame_count: usize, delay_ms: u16) -> io::Result<Option<AnimationWriter>> {
+        let (width, height) = (layout.side, layout.side + layout.label_height);
+        Ok(match animation {
+            Animation::Gif => {
+                if height > u16::MAX as usize {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                              format!("{}x{} frames are too big for a GIF; lower --max-size or use --animation apng",
+                                                      width, height)));
+                }
+                let file = BufWriter::new(File::create(output_dir.join("animation.gif"))?);
+                let mut encoder = gif::Encoder::new(file, width as u16, height as u16, &PALETTE).map_err(render_error)?;
+                encoder.set_repeat(gif::Repeat::Infinite).map_err(render_error)?;
+                Some(AnimationWriter::Gif(encoder))
+            },
+            Animation::Apng => {
+                let mut encoder = png_encoder(BufWriter::new(File::create(output_dir.join("animation.png"))?), width, height);
+                encoder.set_animated(frame_count as u32, 0).map_err(render_error)?;
+                encoder.set_frame_delay(delay_ms, 1000).map_err(render_error)?;
+                Some(AnimationWriter::Apng(encoder.write_header().map_err(render_error)?))
+            },
+            Animation::None => None,
+        })
+    }
+
+    fn add(&mut self, canvas: &Canvas, delay_ms: u16) -> io::Result<()> {
+        match self {
+            AnimationWriter::Gif(encoder) => {
+                let mut frame = gif::Frame::default();
+                frame.width = canvas.width as u16;
+                frame.height = canvas.height as u16;
+                frame.buffer = Cow::Borrowed(&canvas.pixels);
+                frame.delay = delay_ms / 10;
+                encoder.write_frame(&frame).map_err(render_error)
+            },
+            AnimationWriter::Apng(writer) => writer.write_image_data(&canvas.pixels).map_err(render_error),
+        }
+    }
+
+    fn finish(self) -> io::Result<()> {
+        match self {
+            // The trailer is written on drop.
+            AnimationWriter::Gif(_encoder) => Ok(()),
+            AnimationWriter::Apng(writer) => writer.finish().map_err(render_error),
+        }
+    }
+}
+
+pub fn render(store: &dyn SnapshotStore, output_dir: &Path, options: &RenderOptions) -> io::Result<()> {
+    let snapshots = persist::list_snapshots(store)?;
+    let (first, last) = match (snapshots.first(), snapshots.last()) {
+        (Some(first), Some(last)) => (first, last),
+        _ => return Err(io::Error::new(io::ErrorKind::NotFound, format!("No snapshots in {}", store.describe()))),
+    };
+    fs::create_dir_all(output_dir)?;
+    let layout = Layout::new(&persist::read_process_memory(store, last)?, options.max_side);
+    info!("Rendering {} snapshots: {} pages at {} per pixel, {}x{} frames",
+          snapshots.len(), layout.pages, layout.scale, layout.side, layout.side + layout.label_height);
+    let mut animation = AnimationWriter::create(output_dir, options.animation, &layout, snapshots.len(), options.frame_delay_ms)?;
+    let start = parse_timestamp(first)?;
+    let mut series = Vec::with_capacity(snapshots.len());
+    for (idx, timestamp) in snapshots.iter().enumerate() {
+        let memory = persist::read_process_memory(store, timestamp)?;
+        let elapsed = (parse_timestamp(timestamp)? - start).num_seconds();
+        let frame = page_map(&layout, &memory, &elapsed_label(elapsed));
+        if options.frames {
+            frame.write_png(&output_dir.join(format!("frame-{:03}.png", idx)))?;
+        }
+        if let Some(animation) = &mut animation {
+            animation.add(&frame, options.frame_delay_ms)?;
+        }
+        series.push(counts(&memory));
+        debug!("Rendered {} ({}/{})", timestamp, idx + 1, snapshots.len());
+    }
+    if let Some(animation) = animation {
+        animation.finish()?;
+    }
+    chart(&series).write_png(&output_dir.join("chart.png"))?;
+    info!("Rendered to {}", output_dir.display());
+    Ok(())
+}
+
+// HH:MM:SS, as frames are labelled.
+pub fn elapsed_label(seconds: i64) -> String {
+    format!("{:02}:{:02}:{:02}", seconds / 3600, seconds / 60 % 60, seconds % 60)
+}
+
+// Page maps of several snapshots as PNG files in memory, all laid out like the
+// last one, for embedding elsewhere. Labels are drawn as in frames.
+pub fn page_map_pngs(memories: &[(String, super::ProcessMemory)], max_side: usize) -> io::Result<Vec<Vec<u8>>> {
+    let layout = match memories.last() {
+        Some((_label, memory)) => Layout::new(memory, max_side),
+        None => return Ok(Vec::new()),
+    };
+    memories.iter().map(|(label, memory)| {
+        let mut png = Vec::new();
+        page_map(&layout, memory, label).encode_png(&mut png)?;
+        Ok(png)
+    }).collect()
+}
+
+pub fn parse_timestamp(timestamp: &str) -> io::Result<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(timestamp)
+        .map(|time| time.with_timezone(&Utc))
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Bad timestamp {}: {:?}", timestamp, e)))
+}
+
+fn render_error<E: std::fmt::Debug>(error: E) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, format!("Render error: {:?}", error))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::testing::memory;
+
+    #[test]
+    fn segments_stay_within_their_layout() {
+        let active = 1 << super::super::PRESENT_PAGE_BIT | 1 << super::super::ACTIVE_PAGE_BIT;
+        let newest = memory(0, vec![(0x1000, vec![0; 4]), (0x100000, vec![0; 5])]);
+        let layout = Layout::new(&newest, 1024);
+        assert_eq!(layout.pages, 9);
+        // The first segment was twice the size when this was taken.
+        let older = memory(0, vec![(0x1000, vec![active; 8]), (0x100000, vec![0; 5])]);
+        let canvas = page_map(&layout, &older, "");
+        assert_eq!(&canvas.pixels[..4], &[ACTIVE; 4]);
+        assert_eq!(&canvas.pixels[4..9], &[BLACK; 5]);
+    }
+}
@@ -0,0 +1,438 @@
+// A single self-contained HTML file describing a collection run, to attach to
+// reviews: charts of the statistics over time drawn as inline SVG, per-segment
+// tables, and page maps of a few snapshots embedded as base64 PNGs.
+//
+// Statistics come from the file written with --stats-output if there is one
+// (CSV or JSON lines), otherwise from the stats.csv persisted with each snapshot.
+
+use std::collections::BTreeMap;
+use std::fmt::Write as FmtWrite;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use super::persist;
+use super::render;
+use super::store::SnapshotStore;
+
+const CHART_WIDTH: f64 = 800.0;
+const CHART_HEIGHT: f64 = 240.0;
+const CHART_MARGIN_LEFT: f64 = 70.0;
+const CHART_MARGIN_BOTTOM: f64 = 24.0;
+const CHART_MARGIN_TOP: f64 = 28.0;
+const BASE64_ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+pub struct ReportOptions {
+    // Statistics file; found next to the input, or read from the snapshots, if None
+    pub stats: Option<PathBuf>,
+    // How many snapshots to show page maps of, spread evenly over the run
+    pub page_maps: usize,
+    pub max_side: usize,
+}
+
+// One statistics row, by column name. Missing columns are simply absent, so
+// files written by older versions still work.
+type Sample = BTreeMap<String, f64>;
+
+struct Series {
+    name: &'static str,
+    colour: &'static str,
+    // (seconds since the first sample, value)
+    points: Vec<(f64, f64)>,
+}
+
+#[derive(Default)]
+struct SegmentSummary {
+    path: String,
+    kind: String,
+    // From the newest snapshot the segment appears in
+    pages: u64,
+    present: u64,
+    active: u64,
+    zero: u64,
+    swapped: u64,
+    // Over every snapshot the segment appears in
+    snapshots: u64,
+    active_sum: u64,
+    active_peak: u64,
+}
+
+pub fn report(store: &dyn SnapshotStore, input: &Path, output: &Path, options: &ReportOptions) -> io::Result<()> {
+    let snapshots = persist::list_snapshots(store)?;
+    let last = match snapshots.last() {
+        Some(last) => last,
+        None => return Err(io::Error::new(io::ErrorKind::NotFound, format!("No snapshots in {}", store.describe()))),
+    };
+    let manifest = persist::read_manifest(store, last)?;
+
+    let stats_paths = find_stats(input, &options.stats);
+    let samples = if stats_paths.is_empty() {
+        info!("No statistics file; reading the statistics persisted with each snapshot");
+        snapshot_stats(store, &snapshots)?
+    } else {
+        info!("Reading statistics from {:?}", stats_paths);
+        read_stats_files(&stats_paths)?
+    };
+
+    // Page maps are drawn from evenly spaced snapshots, ending with the newest.
+    let wanted: Vec<usize> = match options.page_maps {
+        0 => Vec::new(),
+        1 => vec![snapshots.len() - 1],
+        n if n >= snapshots.len() => (0..snapshots.len()).collect(),
+        n => (0..n).map(|i| i * (snapshots.len() - 1) / (n - 1)).collect(),
+    };
+    let start = render::parse_timestamp(&snapshots[0])?;
+    let mut segments: BTreeMap<usize, SegmentSummary> = BTreeMap::new();
+    let mut page_map_memories = Vec::new();
+    for (idx, timestamp) in snapshots.iter().enumerate() {
+        let memory = persist::read_page_flags(store, timestamp)?;
+        for segment in &memory.segments {
+            let summary = segments.entry(segment.addr_start).or_default();
+            summary_update(summary, segment);
+        }
+        if wanted.contains(&idx) {
+            let elapsed = (memory.timestamp - start).num_seconds();
+            page_map_memories.push((render::elapsed_label(elapsed), memory));
+        }
+        debug!("Summarized {} ({}/{})", timestamp, idx + 1, snapshots.len());
+    }
+    let page_maps = render::page_map_pngs(&page_map_memories, options.max_side)?;
+
+    let mut html = String::new();
+    let title = match manifest["mode"].as_str() {
+        Some("host") => format!("Working set report: {}", manifest["hostname"].as_str().unwrap_or("host")),
+        _ => format!("Working set report: pid {} on {}", manifest["pid"], manifest["hostname"].as_str().unwrap_or("unknown host")),
+    };
+    let _ = write!(html, "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n<style>\n{}</style>\n</head>\n<body>\n<h1>{}</h1>\n",
+                   escape(&title), STYLE, escape(&title));
+
+    html.push_str("<h2>Summary</h2>\n<table>\n");
+    let wss_peak = samples.iter().filter_map(|sample| sample.get("active_pages")).fold(0.0, |peak: f64, &pages| peak.max(pages)) * 4096.0;
+    let newest = samples.last();
+    let newest_value = |name: &str| newest.and_then(|sample| sample.get(name)).cloned();
+    let mut summary: Vec<(&str, String)> = vec![
+        ("Snapshots", snapshots.len().to_string()),
+        ("First", snapshots[0].clone()),
+        ("Last", last.clone()),
+        ("Duration", render::elapsed_label((render::parse_timestamp(last)? - start).num_seconds())),
+        ("Kernel", manifest["kernel_version"].as_str().unwrap_or("").to_string()),
+        ("Statistics samples", samples.len().to_string()),
+        ("Peak WSS", format!("{:.1} MiB", mib(wss_peak))),
+    ];
+    if let Some(p95) = newest_value("wss_p95_bytes") {
+        summary.push(("p95 WSS (latest window)", format!("{:.1} MiB", mib(p95))));
+    }
+    if let Some(recommended) = newest_value("recommended_bytes") {
+        summary.push(("Recommended size", format!("{:.1} MiB", mib(recommended))));
+    }
+    for (name, value) in summary {
+        let _ = writeln!(html, "<tr><th>{}</th><td>{}</td></tr>", name, escape(&value));
+    }
+    html.push_str("</table>\n");
+
+    html.push_str("<h2>Over time</h2>\n");
+    let first_time = samples.first().and_then(|sample| sample.get("timestamp")).cloned().unwrap_or(0.0);
+    let bytes = |name: &'static str, colour: &'static str| Series {
+        name: name,
+        colour: colour,
+        points: column(&samples, name, first_time, 1.0 / (1 << 20) as f64),
+    };
+    html.push_str(&svg_chart("Working set", "MiB", &[
+        Series { name: "wss", colour: "#c00000", points: column(&samples, "active_pages", first_time, 4096.0 / (1 << 20) as f64) },
+        bytes("wss_p95_bytes", "#e08000"),
+        bytes("recommended_bytes", "#404040"),
+    ]));
+    html.push_str(&svg_chart("Zero and repeating pattern pages", "pages", &[
+        Series { name: "zero_pages", colour: "#00a000", points: column(&samples, "zero_pages", first_time, 1.0) },
+        Series { name: "pattern_pages", colour: "#0060c0", points: column(&samples, "pattern_pages", first_time, 1.0) },
+    ]));
+    html.push_str(&svg_chart("Swap", "MiB", &[bytes("swap_bytes", "#606060")]));
+    html.push_str(&svg_chart("Faults", "per second", &[
+        Series { name: "minflt", colour: "#0060c0", points: rate(&samples, "minflt", first_time) },
+        Series { name: "majflt", colour: "#c00000", points: rate(&samples, "majflt", first_time) },
+    ]));
+
+    html.push_str("<h2>Segments</h2>\n<table>\n<tr><th>Address</th><th>Path</th><th>Kind</th><th>Size (MiB)</th>\
+                   <th>Present</th><th>Active</th><th>Idle</th><th>Zero</th><th>Swapped</th>\
+                   <th>Mean active</th><th>Peak active</th></tr>\n");
+    for (addr_start, summary) in &segments {
+        let _ = writeln!(html, "<tr><td>0x{:x}</td><td>{}</td><td>{}</td><td>{:.1}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{:.1}</td><td>{}</td></tr>",
+                         addr_start, escape(&summary.path), escape(&summary.kind), mib(summary.pages as f64 * 4096.0),
+                         summary.present, summary.active, summary.present.saturating_sub(summary.active), summary.zero, summary.swapped,
+                         summary.active_sum as f64 / std::cmp::max(summary.snapshots, 1) as f64, summary.active_peak);
+    }
+    html.push_str("</table>\n<p>Page counts are from the newest snapshot each segment appears in; mean and peak are over every snapshot.</p>\n");
+
+    html.push_str("<h2>Page maps</h2>\n<p>Red: active, green: idle (brighter when zero-filled), grey: swapped, black: not present. \
+                   Segments are laid end to end from the lowest address.</p>\n<div class=\"maps\">\n");
+    for ((label, memory), png) in page_map_memories.iter().zip(page_maps.iter()) {
+        let _ = writeln!(html, "<figure><img alt=\"{}\" src=\"data:image/png;base64,{}\"><figcaption>{} (+{})</figcaption></figure>",
+                         label, base64(png), memory.timestamp.to_rfc3339(), label);
+    }
+    html.push_str("</div>\n</body>\n</html>\n");
+
+    if let Some(parent) = output.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(output, html)?;
+    info!("Wrote report of {} snapshots to {}", snapshots.len(), output.display());
+    Ok(())
+}
+
+const STYLE: &str = "body { font-family: sans-serif; margin: 2em; }
+table { border-collapse: collapse; margin-bottom: 1em; }
+th, td { border: 1px solid #ccc; padding: 2px 8px; text-align: right; }
+th { background: #eee; }
+svg { display: block; margin-bottom: 1em; }
+.maps { display: flex; flex-wrap: wrap; }
+figure { margin: 0 1em 1em 0; }
+figure img { image-rendering: pixelated; width: 320px; }
+";
+
+fn summary_update(summary: &mut SegmentSummary, segment: &super::Segment) {
+    let set = |flags: u64, bit: u8| flags & (1 << bit) != 0;
+    summary.path = segment.path.clone();
+    summary.kind = segment.kind().to_string();
+    summary.pages = segment.page_flags.len() as u64;
+    summary.present = segment.page_flags.iter().filter(|&&flags| set(flags, super::PRESENT_PAGE_BIT)).count() as u64;
+    summary.active = segment.page_flags.iter().filter(|&&flags| set(flags, super::ACTIVE_PAGE_BIT)).count() as u64;
+    summary.zero = segment.page_flags.iter().filter(|&&flags| set(flags, super::ZERO_PAGE_BIT)).count() as u64;
+    summary.swapped = segment.page_flags.iter().filter(|&&flags| set(flags, super::SWAPPED_PAGE_BIT)).count() as u64;
+    summary.snapshots += 1;
+    summary.active_sum += summary.active;
+    summary.active_peak = std::cmp::max(summary.active_peak, summary.active);
+}
+
+// The given file, or <input>.csv or <input>.jsonl as main writes them by default,
+// after any older rows rotation has moved aside to <file>.1, <file>.2, ...
+fn find_stats(input: &Path, stats: &Option<PathBuf>) -> Vec<PathBuf> {
+    let path = match stats {
+        Some(stats) => stats.clone(),
+        None => match ["csv", "jsonl"].iter().map(|extension| input.with_extension(extension)).find(|path| path.is_file()) {
+            Some(path) => path,
+            None => return Vec::new(),
+        },
+    };
+    let mut paths = rotated_stats(&path);
+    paths.push(path);
+    paths
+}
+
+// Oldest first, so the highest number first.
+fn rotated_stats(path: &Path) -> Vec<PathBuf> {
+    let name = match path.file_name() {
+        Some(name) => format!("{}.", name.to_string_lossy()),
+        None => return Vec::new(),
+    };
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let mut rotated: Vec<(u32, PathBuf)> = match fs::read_dir(dir) {
+        Ok(entries) => entries.filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let generation = entry.file_name().to_string_lossy().strip_prefix(name.as_str())?.parse().ok()?;
+                Some((generation, entry.path()))
+            })
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    rotated.sort_by_key(|&(generation, _)| std::cmp::Reverse(generation));
+    rotated.into_iter().map(|(_generation, path)| path).collect()
+}
+
+// Rotated files keep the format of the file they were rotated from.
+fn read_stats_files(paths: &[PathBuf]) -> io::Result<Vec<Sample>> {
+    let jsonl = paths.last().and_then(|path| path.extension()).map_or(false, |extension| extension == "jsonl");
+    let mut samples = Vec::new();
+    for path in paths {
+        samples.extend(read_stats_file(path, jsonl)?);
+    }
+    samples.sort_by(|a, b| a.get("timestamp").partial_cmp(&b.get("timestamp")).unwrap_or(std::cmp::Ordering::Equal));
+    Ok(samples)
+}
+
+fn read_stats_file(path: &Path, jsonl: bool) -> io::Result<Vec<Sample>> {
+    if !jsonl {
+        return read_stats_csv(csv::Reader::from_path(path)?);
+    }
+    let mut samples = Vec::new();
+    for line in fs::read_to_string(path)?.lines().filter(|line| !line.trim().is_empty()) {
+        let row = json::parse(line)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Bad line in {}: {:?}", path.display(), e)))?;
+        samples.push(row.entries().filter_map(|(name, value)| Some((name.to_string(), value.as_f64()?))).collect());
+    }
+    Ok(samples)
+}
+
+fn read_stats_csv<R: io::Read>(mut reader: csv::Reader<R>) -> io::Result<Vec<Sample>> {
+    let header: Vec<String> = reader.headers()?.iter().map(|name| name.to_string()).collect();
+    let mut samples = Vec::new();
+    for record in reader.records() {
+        let record = record?;
+        samples.push(header.iter().zip(record.iter())
+            .filter_map(|(name, value)| Some((name.clone(), value.parse().ok()?)))
+            .collect());
+    }
+    Ok(samples)
+}
+
+fn snapshot_stats(store: &dyn SnapshotStore, snapshots: &[String]) -> io::Result<Vec<Sample>> {
+    let mut samples = Vec::new();
+    for timestamp in snapshots {
+        match store.get(&format!("{}/{}", timestamp, persist::STATS_NAME)) {
+            Ok(data) => samples.extend(read_stats_csv(csv::Reader::from_reader(data.as_slice()))?),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => debug!("No statistics for {}", timestamp),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(samples)
+}
+
+fn column(samples: &[Sample], name: &str, first_time: f64, scale: f64) -> Vec<(f64, f64)> {
+    samples.iter()
+        .filter_map(|sample| Some((sample.get("timestamp")? - first_time, sample.get(name)? * scale)))
+        .collect()
+}
+
+// Per-second rate of a cumulative counter. A counter going backwards, e.g.
+// when a process restarts, counts as zero.
+fn rate(samples: &[Sample], name: &str, first_time: f64) -> Vec<(f64, f64)> {
+    let points = column(samples, name, first_time, 1.0);
+    points.windows(2)
+        .filter(|pair| pair[1].0 > pair[0].0)
+        .map(|pair| (pair[1].0, (pair[1].1 - pair[0].1).max(0.0) / (pair[1].0 - pair[0].0)))
+        .collect()
+}
+
+fn svg_chart(title: &str, unit: &str, series: &[Series]) -> String {
+    let points = || series.iter().flat_map(|series| series.points.iter());
+    let x_max = points().fold(0.0, |max: f64, point| max.max(point.0)).max(1.0);
+    let y_max = points().fold(0.0, |max: f64, point| max.max(point.1)) * 1.05;
+    let y_max = if y_max > 0.0 { y_max } else { 1.0 };
+    let plot_width = CHART_WIDTH - CHART_MARGIN_LEFT - 10.0;
+    let plot_height = CHART_HEIGHT - CHART_MARGIN_TOP - CHART_MARGIN_BOTTOM;
+    let x = |value: f64| CHART_MARGIN_LEFT + value / x_max * plot_width;
+    let y = |value: f64| CHART_MARGIN_TOP + plot_height - value / y_max * plot_height;
+
+    let mut svg = String::new();
+    let _ = writeln!(svg, "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\" font-size=\"11\">", CHART_WIDTH, CHART_HEIGHT);
+    let _ = writeln!(svg, "<text x=\"{}\" y=\"16\" font-size=\"14\" font-weight=\"bold\">{} ({})</text>",
+                     CHART_MARGIN_LEFT, escape(title), escape(unit));
+    // Gridlines and labels every quarter
+    for quarter in 0..5 {
+        let value = y_max * quarter as f64 / 4.0;
+        let _ = writeln!(svg, "<line x1=\"{:.1}\" y1=\"{:.1}\" x2=\"{:.1}\" y2=\"{:.1}\" stroke=\"#ddd\"/>",
+                         x(0.0), y(value), x(x_max), y(value));
+        let _ = writeln!(svg, "<text x=\"{:.1}\" y=\"{:.1}\" text-anchor=\"end\">{:.1}</text>",
+                         CHART_MARGIN_LEFT - 4.0, y(value) + 4.0, value);
+        let elapsed = x_max * quarter as f64 / 4.0;
+        let _ = writeln!(svg, "<text x=\"{:.1}\" y=\"{:.1}\" text-anchor=\"middle\">{}</text>",
+                         x(elapsed), CHART_HEIGHT - 6.0, render::elapsed_label(elapsed as i64));
+    }
+    let mut legend_x = CHART_MARGIN_LEFT + 300.0;
+    for series in series.iter().filter(|series| !series.points.is_empty()) {
+        let coordinates: Vec<String> = series.points.iter()
+            .map(|&(time, value)| format!("{:.1},{:.1}", x(time), y(value)))
+            .collect();
+        let _ = writeln!(svg, "<polyline fill=\"none\" stroke=\"{}\" stroke-width=\"1.5\" points=\"{}\"/>",
+                         series.colour, coordinates.join(" "));
+        let _ = writeln!(svg, "<text x=\"{:.1}\" y=\"16\" fill=\"{}\">{}</text>", legend_x, series.colour, series.name);
+        legend_x += 8.0 * series.name.len() as f64 + 16.0;
+    }
+    if series.iter().all(|series| series.points.is_empty()) {
+        let _ = writeln!(svg, "<text x=\"{:.1}\" y=\"{:.1}\" text-anchor=\"middle\">No data</text>",
+                         x(x_max / 2.0), y(y_max / 2.0));
+    }
+    svg.push_str("</svg>\n");
+    svg
+}
+
+fn mib(bytes: f64) -> f64 {
+    bytes / (1 << 20) as f64
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+fn base64(data: &[u8]) -> String {
+    let mut out = String::with_capacity((data.len() + 2) / 3 * 4);
+    for chunk in data.chunks(3) {
+        let bytes = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let word = (bytes[0] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[2] as u32;
+        for idx in 0..4 {
+            if idx <= chunk.len() {
+                out.push(BASE64_ALPHABET[(word >> (18 - 6 * idx) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(timestamp: f64, name: &str, value: f64) -> Sample {
+        let mut sample = Sample::new();
+        sample.insert("timestamp".to_string(), timestamp);
+        sample.insert(name.to_string(), value);
+        sample
+    }
+
+    #[test]
+    fn base64_padding() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(base64(b"foob"), "Zm9vYg==");
+        assert_eq!(base64(&[0xff, 0xfe]), "//4=");
+    }
+
+    #[test]
+    fn rate_ignores_resets_and_repeated_timestamps() {
+        let samples: Vec<Sample> = [(100.0, 0.0), (110.0, 100.0), (120.0, 50.0), (130.0, 150.0)].iter()
+            .map(|&(timestamp, minflt)| sample(timestamp, "minflt", minflt))
+            .collect();
+        assert_eq!(rate(&samples, "minflt", 100.0), vec![(10.0, 10.0), (20.0, 0.0), (30.0, 10.0)]);
+        let samples: Vec<Sample> = [(100.0, 0.0), (100.0, 5.0), (110.0, 25.0)].iter()
+            .map(|&(timestamp, minflt)| sample(timestamp, "minflt", minflt))
+            .collect();
+        assert_eq!(rate(&samples, "minflt", 100.0), vec![(10.0, 2.0)]);
+    }
+
+    #[test]
+    fn csv_without_newer_columns() {
+        let csv = "timestamp,total_pages,active_pages\n1,10,4\n2,20,\n";
+        let samples = read_stats_csv(csv::Reader::from_reader(csv.as_bytes())).unwrap();
+        assert_eq!(samples.len(), 2);
+        assert_eq!(samples[0]["active_pages"], 4.0);
+        assert!(!samples[1].contains_key("active_pages"));
+        assert!(!samples[1].contains_key("wss_p95_bytes"));
+        assert_eq!(column(&samples, "total_pages", 1.0, 1.0), vec![(0.0, 10.0), (1.0, 20.0)]);
+        assert!(column(&samples, "recommended_bytes", 1.0, 1.0).is_empty());
+    }
+
+    #[test]
+    fn rotated_stats_are_merged() {
+        let dir = std::env::temp_dir().join(format!("wss-report-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("7.csv.2"), "timestamp,active_pages\n1,1\n2,2\n").unwrap();
+        fs::write(dir.join("7.csv.1"), "timestamp,active_pages\n3,3\n").unwrap();
+        fs::write(dir.join("7.csv"), "timestamp,active_pages\n4,4\n").unwrap();
+        fs::write(dir.join("7.csv.tmp"), "not stats").unwrap();
+        fs::write(dir.join("7.segments.csv.1"), "timestamp,address\n").unwrap();
+        let paths = find_stats(&dir.join("7"), &None);
+        assert_eq!(paths, vec![dir.join("7.csv.2"), dir.join("7.csv.1"), dir.join("7.csv")]);
+        let samples = read_stats_files(&paths).unwrap();
+        let timestamps: Vec<f64> = samples.iter().map(|sample| sample["timestamp"]).collect();
+        assert_eq!(timestamps, vec![1.0, 2.0, 3.0, 4.0]);
+        assert!(find_stats(&dir.join("8"), &None).is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
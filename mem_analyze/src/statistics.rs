@@ -1,12 +1,582 @@
+use std::fs;
 use std::fs::{File, OpenOptions};
-use std::io::Read;
+use std::io;
+use std::io::{Read, Write, BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::collections::{HashMap, VecDeque};
+use std::marker::PhantomData;
+use std::cell::Cell;
+use chrono::{DateTime, Duration, Utc};
 use csv::Writer;
+use json::JsonValue;
 use sysinfo::{System, SystemExt, ProcessExt, RefreshKind};
 
-pub fn page_analytics(pid: i32, memory: &super::ProcessMemory) {
+use super::locality::Locality;
+
+// One sample's statistics. New columns only ever go on the end, and every CSV
+// starts with a header row, so readers can look columns up by name.
+#[derive(Clone, Debug, Default)]
+pub struct StatsRecord {
+    // Seconds since the epoch
+    pub timestamp: i64,
+    pub total_pages: u64,
+    pub lru_pages: u64,
+    pub zero_pages: u64,
+    pub active_pages: u64,
+    pub present_pages: u64,
+    pub minflt: u64,
+    pub majflt: u64,
+    pub swap_bytes: u64,
+    pub pattern_pages: u64,
+    // System-wide, whichever mode we're in
+    pub mem_total_bytes: u64,
+    pub mem_available_bytes: u64,
+    pub pswpin: u64,
+    pub pswpout: u64,
+    pub pgmajfault: u64,
+    pub workingset_refault: u64,
+    // Idle pages in fully idle, 2 MiB-aligned blocks
+    pub huge_idle_pages: u64,
+    // Rolling-window working set estimate; see WssEstimator
+    pub wss_mean_bytes: u64,
+    pub wss_p50_bytes: u64,
+    pub wss_p95_bytes: u64,
+    pub wss_min_bytes: u64,
+    pub wss_max_bytes: u64,
+    pub wss_ewma_bytes: u64,
+    pub recommended_bytes: u64,
+    // Resident pages by access frequency; see AccessHistory
+    pub hot_pages: u64,
+    pub warm_pages: u64,
+    pub cold_pages: u64,
+}
+
+impl StatsRecord {
+    pub const HEADER: [&'static str; 27] = [
+        "timestamp", "total_pages", "lru_pages", "zero_pages", "active_pages", "present_pages",
+        "minflt", "majflt", "swap_bytes", "pattern_pages", "mem_total_bytes", "mem_available_bytes",
+        "pswpin", "pswpout", "pgmajfault", "workingset_refault", "huge_idle_pages",
+        "wss_mean_bytes", "wss_p50_bytes", "wss_p95_bytes", "wss_min_bytes", "wss_max_bytes",
+        "wss_ewma_bytes", "recommended_bytes", "hot_pages", "warm_pages", "cold_pages"];
+}
+
+// Something StatsWriter can append: a fixed set of named columns.
+pub trait StatsRow {
+    fn header() -> &'static [&'static str];
+    // In header order
+    fn to_row(&self) -> Vec<String>;
+    fn to_json(&self) -> JsonValue;
+
+    // Header and this record, as a standalone CSV file.
+    fn to_csv(&self) -> io::Result<Vec<u8>> {
+        let mut wtr = Writer::from_writer(Vec::new());
+        wtr.write_record(Self::header())?;
+        wtr.write_record(&self.to_row())?;
+        wtr.into_inner().map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{:?}", e)))
+    }
+}
+
+impl StatsRow for StatsRecord {
+    fn header() -> &'static [&'static str] {
+        &StatsRecord::HEADER
+    }
+
+    fn to_row(&self) -> Vec<String> {
+        vec![
+            self.timestamp.to_string(),
+            self.total_pages.to_string(),
+            self.lru_pages.to_string(),
+            self.zero_pages.to_string(),
+            self.active_pages.to_string(),
+            self.present_pages.to_string(),
+            self.minflt.to_string(),
+            self.majflt.to_string(),
+            self.swap_bytes.to_string(),
+            self.pattern_pages.to_string(),
+            self.mem_total_bytes.to_string(),
+            self.mem_available_bytes.to_string(),
+            self.pswpin.to_string(),
+            self.pswpout.to_string(),
+            self.pgmajfault.to_string(),
+            self.workingset_refault.to_string(),
+            self.huge_idle_pages.to_string(),
+            self.wss_mean_bytes.to_string(),
+            self.wss_p50_bytes.to_string(),
+            self.wss_p95_bytes.to_string(),
+            self.wss_min_bytes.to_string(),
+            self.wss_max_bytes.to_string(),
+            self.wss_ewma_bytes.to_string(),
+            self.recommended_bytes.to_string(),
+            self.hot_pages.to_string(),
+            self.warm_pages.to_string(),
+            self.cold_pages.to_string()]
+    }
+
+    fn to_json(&self) -> JsonValue {
+        object!{
+            "timestamp" => self.timestamp,
+            "total_pages" => self.total_pages,
+            "lru_pages" => self.lru_pages,
+            "zero_pages" => self.zero_pages,
+            "active_pages" => self.active_pages,
+            "present_pages" => self.present_pages,
+            "minflt" => self.minflt,
+            "majflt" => self.majflt,
+            "swap_bytes" => self.swap_bytes,
+            "pattern_pages" => self.pattern_pages,
+            "mem_total_bytes" => self.mem_total_bytes,
+            "mem_available_bytes" => self.mem_available_bytes,
+            "pswpin" => self.pswpin,
+            "pswpout" => self.pswpout,
+            "pgmajfault" => self.pgmajfault,
+            "workingset_refault" => self.workingset_refault,
+            "huge_idle_pages" => self.huge_idle_pages,
+            "wss_mean_bytes" => self.wss_mean_bytes,
+            "wss_p50_bytes" => self.wss_p50_bytes,
+            "wss_p95_bytes" => self.wss_p95_bytes,
+            "wss_min_bytes" => self.wss_min_bytes,
+            "wss_max_bytes" => self.wss_max_bytes,
+            "wss_ewma_bytes" => self.wss_ewma_bytes,
+            "recommended_bytes" => self.recommended_bytes,
+            "hot_pages" => self.hot_pages,
+            "warm_pages" => self.warm_pages,
+            "cold_pages" => self.cold_pages,
+        }
+    }
+}
+
+// Idle-age buckets for segment rows: pages idle for 1, 2-3, 4-7, 8-15 and 16+
+// consecutive samples.
+const IDLE_AGE_BUCKETS: usize = 5;
+
+// One segment's share of a sample.
+#[derive(Clone, Debug, Default)]
+pub struct SegmentRecord {
+    pub timestamp: i64,
+    pub address: usize,
+    pub size_bytes: usize,
+    pub path: String,
+    pub kind: String,
+    pub present_pages: u64,
+    pub active_pages: u64,
+    pub zero_pages: u64,
+    pub swapped_pages: u64,
+    pub lru_pages: u64,
+    pub idle_pages_by_age: [u64; IDLE_AGE_BUCKETS],
+    pub locality: Locality,
+    pub tiers: Tiers,
+    // Host mode only, so JSON rows only: CSV columns stay the same in both modes.
+    pub page_types: HashMap<super::PageType, PageTypeCounts>,
+}
+
+// Only present pages have a meaningful idle bit; in host mode that's the LRU pages.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PageTypeCounts {
+    pub active: u64,
+    pub idle: u64,
+    pub untracked: u64,
+}
+
+impl PageTypeCounts {
+    fn add(&mut self, other: &PageTypeCounts) {
+        self.active += other.active;
+        self.idle += other.idle;
+        self.untracked += other.untracked;
+    }
+}
+
+impl StatsRow for SegmentRecord {
+    fn header() -> &'static [&'static str] {
+        &["timestamp", "address", "size_bytes", "path", "kind", "present_pages", "active_pages",
+          "zero_pages", "swapped_pages", "idle_1", "idle_2_3", "idle_4_7", "idle_8_15", "idle_16_plus",
+          "active_runs_1", "active_runs_2_7", "active_runs_8_63", "active_runs_64_511", "active_runs_512_plus",
+          "idle_runs_1", "idle_runs_2_7", "idle_runs_8_63", "idle_runs_64_511", "idle_runs_512_plus",
+          "longest_idle_run", "idle_fragmentation", "huge_idle_pages", "hot_pages", "warm_pages", "cold_pages",
+          "lru_pages"]
+    }
+
+    fn to_row(&self) -> Vec<String> {
+        let mut row = vec![
+            self.timestamp.to_string(),
+            format!("0x{:x}", self.address),
+            self.size_bytes.to_string(),
+            self.path.clone(),
+            self.kind.clone(),
+            self.present_pages.to_string(),
+            self.active_pages.to_string(),
+            self.zero_pages.to_string(),
+            self.swapped_pages.to_string()];
+        row.extend(self.idle_pages_by_age.iter().map(|pages| pages.to_string()));
+        row.extend(self.locality.active.buckets.iter().map(|runs| runs.to_string()));
+        row.extend(self.locality.idle.buckets.iter().map(|runs| runs.to_string()));
+        row.push(self.locality.idle.longest.to_string());
+        row.push(format!("{:.3}", self.locality.idle_fragmentation()));
+        row.push(self.locality.huge_idle_pages.to_string());
+        row.push(self.tiers.hot.to_string());
+        row.push(self.tiers.warm.to_string());
+        row.push(self.tiers.cold.to_string());
+        row.push(self.lru_pages.to_string());
+        row
+    }
+
+    fn to_json(&self) -> JsonValue {
+        let mut record = object!{
+            "timestamp" => self.timestamp,
+            "address" => format!("0x{:x}", self.address),
+            "size_bytes" => self.size_bytes,
+            "path" => self.path.clone(),
+            "kind" => self.kind.clone(),
+            "present_pages" => self.present_pages,
+            "active_pages" => self.active_pages,
+            "zero_pages" => self.zero_pages,
+            "swapped_pages" => self.swapped_pages,
+            "longest_idle_run" => self.locality.idle.longest,
+            "idle_fragmentation" => self.locality.idle_fragmentation(),
+            "huge_idle_pages" => self.locality.huge_idle_pages,
+            "hot_pages" => self.tiers.hot,
+            "warm_pages" => self.tiers.warm,
+            "cold_pages" => self.tiers.cold,
+            "lru_pages" => self.lru_pages,
+        };
+        // The bucket columns, idle_1 to idle_runs_512_plus, are in header order.
+        let buckets = self.idle_pages_by_age.iter()
+            .chain(self.locality.active.buckets.iter())
+            .chain(self.locality.idle.buckets.iter());
+        for (name, &count) in SegmentRecord::header()[9..].iter().zip(buckets) {
+            record[*name] = count.into();
+        }
+        if !self.page_types.is_empty() {
+            let mut page_types = JsonValue::new_object();
+            for page_type in super::PageType::ALL.iter() {
+                if let Some(counts) = self.page_types.get(page_type) {
+                    page_types[page_type.name()] = object!{
+                        "active" => counts.active,
+                        "idle" => counts.idle,
+                        "untracked" => counts.untracked,
+                    };
+                }
+            }
+            record["page_types"] = page_types;
+        }
+        record
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum StatsFormat {
+    Csv,
+    // One JSON object per line
+    JsonLines,
+}
+
+impl std::str::FromStr for StatsFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<StatsFormat, String> {
+        match s {
+            "csv" => Ok(StatsFormat::Csv),
+            "jsonl" => Ok(StatsFormat::JsonLines),
+            _ => Err(format!("Unsupported stats format: {}", s)),
+        }
+    }
+}
+
+impl StatsFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            StatsFormat::Csv => "csv",
+            StatsFormat::JsonLines => "jsonl",
+        }
+    }
+}
+
+// Appends records to a file, e.g. one StatsRecord per sample.
+pub struct StatsWriter<R: StatsRow> {
+    path: PathBuf,
+    format: StatsFormat,
+    // Limits from rotate(); the file is rolled over to <path>.1 at half of each.
+    max_age: Option<Duration>,
+    max_bytes: Option<u64>,
+    started: Cell<DateTime<Utc>>,
+    rows: PhantomData<R>,
+}
+
+impl<R: StatsRow> StatsWriter<R> {
+    // A CSV whose header doesn't match the current columns (e.g. from an older
+    // version, or headerless) is moved aside rather than appended to.
+    pub fn new<P: AsRef<Path>>(path: P, format: StatsFormat) -> io::Result<StatsWriter<R>> {
+        let path = path.as_ref().to_path_buf();
+        if let StatsFormat::Csv = format {
+            if path.is_file() && fs::metadata(&path)?.len() > 0 {
+                let mut header = String::new();
+                BufReader::new(File::open(&path)?).read_line(&mut header)?;
+                if header.trim_end() != R::header().join(",") {
+                    let moved = path.with_extension(format!("{}.csv", Utc::now().timestamp()));
+                    warn!("{} has different columns; moving it to {}", path.display(), moved.display());
+                    fs::rename(&path, &moved)?;
+                }
+            }
+        }
+        Ok(StatsWriter { path: path, format: format, max_age: None, max_bytes: None, started: Cell::new(Utc::now()), rows: PhantomData })
+    }
+
+    // Keep roughly max_age of rows, and no more than max_bytes, between this file
+    // and the one it was last rolled over to.
+    pub fn rotate(mut self, max_age: Option<Duration>, max_bytes: Option<u64>) -> StatsWriter<R> {
+        self.max_age = max_age;
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    fn rotate_if_due(&self) -> io::Result<()> {
+        let now = Utc::now();
+        let too_old = self.max_age.map_or(false, |max_age| now - self.started.get() >= max_age / 2);
+        let too_big = match self.max_bytes {
+            Some(max_bytes) if self.path.is_file() => fs::metadata(&self.path)?.len() >= max_bytes / 2,
+            _ => false,
+        };
+        if too_old || too_big {
+            let rotated = PathBuf::from(format!("{}.1", self.path.display()));
+            if self.path.is_file() {
+                debug!("Rotating {} to {}", self.path.display(), rotated.display());
+                fs::rename(&self.path, &rotated)?;
+            }
+            self.started.set(now);
+        }
+        Ok(())
+    }
+
+    pub fn append(&self, record: &R) -> io::Result<()> {
+        self.append_all(std::slice::from_ref(record))
+    }
+
+    pub fn append_all(&self, records: &[R]) -> io::Result<()> {
+        self.rotate_if_due()?;
+        let mut file = OpenOptions::new().append(true).create(true).open(&self.path)?;
+        match self.format {
+            StatsFormat::Csv => {
+                let write_header = file.metadata()?.len() == 0;
+                let mut wtr = Writer::from_writer(file);
+                if write_header {
+                    wtr.write_record(R::header())?;
+                }
+                for record in records {
+                    wtr.write_record(&record.to_row())?;
+                }
+                wtr.flush()?;
+            },
+            StatsFormat::JsonLines => {
+                for record in records {
+                    writeln!(file, "{}", record.to_json().dump())?;
+                }
+            },
+        }
+        Ok(())
+    }
+}
+
+// A single sample's WSS depends heavily on what happened to run during that
+// interval, so sizing decisions should come from a window of them. One of these
+// per monitored PID.
+pub struct WssEstimator {
+    window: VecDeque<u64>,
+    capacity: usize,
+    ewma: Option<f64>,
+    // Fraction added on top of the p95 WSS for the recommendation, e.g. 0.1
+    headroom: f64,
+}
+
+// Weight of the newest sample in the EWMA
+const WSS_EWMA_ALPHA: f64 = 0.2;
+
+impl WssEstimator {
+    pub fn new(capacity: usize, headroom: f64) -> WssEstimator {
+        WssEstimator {
+            window: VecDeque::with_capacity(capacity),
+            capacity: std::cmp::max(capacity, 1),
+            ewma: None,
+            headroom: headroom,
+        }
+    }
+
+    // Adds the sample's WSS to the window and fills in the estimate columns.
+    pub fn update(&mut self, record: &mut StatsRecord) {
+        let wss_bytes = record.active_pages * 4096;
+        if self.window.len() == self.capacity {
+            self.window.pop_front();
+        }
+        self.window.push_back(wss_bytes);
+        self.ewma = Some(match self.ewma {
+            Some(ewma) => WSS_EWMA_ALPHA * wss_bytes as f64 + (1.0 - WSS_EWMA_ALPHA) * ewma,
+            None => wss_bytes as f64,
+        });
+
+        let mut sorted: Vec<u64> = self.window.iter().cloned().collect();
+        sorted.sort();
+        // Nearest rank
+        let percentile = |p: f64| sorted[((p * sorted.len() as f64).ceil() as usize).saturating_sub(1)];
+        record.wss_mean_bytes = sorted.iter().sum::<u64>() / sorted.len() as u64;
+        record.wss_p50_bytes = percentile(0.5);
+        record.wss_p95_bytes = percentile(0.95);
+        record.wss_min_bytes = sorted[0];
+        record.wss_max_bytes = sorted[sorted.len() - 1];
+        record.wss_ewma_bytes = self.ewma.unwrap() as u64;
+        record.recommended_bytes = (record.wss_p95_bytes as f64 * (1.0 + self.headroom)) as u64;
+        info!("WSS over last {} samples: mean {} MiB, p50 {} MiB, p95 {} MiB, min {} MiB, max {} MiB, EWMA {} MiB",
+              sorted.len(), record.wss_mean_bytes >> 20, record.wss_p50_bytes >> 20, record.wss_p95_bytes >> 20,
+              record.wss_min_bytes >> 20, record.wss_max_bytes >> 20, record.wss_ewma_bytes >> 20);
+        if sorted.len() < self.capacity {
+            info!("Recommended size: {} MiB (low confidence: {} of {} samples)",
+                  record.recommended_bytes >> 20, sorted.len(), self.capacity);
+        } else {
+            info!("Recommended size: {} MiB", record.recommended_bytes >> 20);
+        }
+    }
+}
+
+// How many consecutive samples each resident page has been idle for, per segment.
+pub struct IdleAges {
+    ages: HashMap<usize, Vec<u16>>,
+}
+
+impl IdleAges {
+    pub fn new() -> IdleAges {
+        IdleAges { ages: HashMap::new() }
+    }
+
+    pub fn update(&mut self, memory: &super::ProcessMemory) {
+        let mut ages: HashMap<usize, Vec<u16>> = HashMap::new();
+        for segment in &memory.segments {
+            // A segment which has changed size starts again from scratch.
+            let mut segment_ages = match self.ages.remove(&segment.addr_start) {
+                Some(previous) if previous.len() == segment.page_flags.len() => previous,
+                _ => vec![0; segment.page_flags.len()],
+            };
+            for (age, page_flags) in segment_ages.iter_mut().zip(segment.page_flags.iter()) {
+                let idle = page_flags & (1 << super::PRESENT_PAGE_BIT) != 0
+                    && page_flags & (1 << super::ACTIVE_PAGE_BIT) == 0;
+                *age = if idle { age.saturating_add(1) } else { 0 };
+            }
+            ages.insert(segment.addr_start, segment_ages);
+        }
+        self.ages = ages;
+    }
+
+    // Empty if the segment hasn't been seen.
+    pub fn ages(&self, addr_start: usize) -> &[u16] {
+        match self.ages.get(&addr_start) {
+            Some(ages) => ages,
+            None => &[],
+        }
+    }
+}
+
+// Resident pages split by how often they were accessed over the history window.
+#[derive(Clone, Debug, Default)]
+pub struct Tiers {
+    // Active in at least the hot threshold's worth of samples
+    pub hot: u64,
+    // Active at least once, but not hot
+    pub warm: u64,
+    // Not active at all
+    pub cold: u64,
+}
+
+// A sliding bit history per page: bit 0 is whether the page was active in the
+// latest sample, bit 1 the one before, and so on for up to 64 samples.
+// That's a u64 per page of every segment, whatever the window: 2 MiB per GiB
+// sampled, so in host mode 0.2% of the machine's RAM.
+pub struct AccessHistory {
+    window: u32,
+    hot_threshold: u32,
+    samples: u32,
+    bits: HashMap<usize, Vec<u64>>,
+}
+
+impl AccessHistory {
+    // A page is hot if it was active in at least hot_threshold of the last window samples.
+    pub fn new(window: u32, hot_threshold: u32) -> AccessHistory {
+        let window = std::cmp::min(std::cmp::max(window, 1), 64);
+        AccessHistory {
+            window: window,
+            hot_threshold: std::cmp::min(std::cmp::max(hot_threshold, 1), window),
+            samples: 0,
+            bits: HashMap::new(),
+        }
+    }
+
+    pub fn update(&mut self, memory: &super::ProcessMemory) {
+        let mask = if self.window == 64 { !0 } else { (1u64 << self.window) - 1 };
+        let mut bits: HashMap<usize, Vec<u64>> = HashMap::new();
+        for segment in &memory.segments {
+            // As with IdleAges, a segment which has changed size starts again.
+            let mut segment_bits = match self.bits.remove(&segment.addr_start) {
+                Some(previous) if previous.len() == segment.page_flags.len() => previous,
+                _ => vec![0; segment.page_flags.len()],
+            };
+            for (history, page_flags) in segment_bits.iter_mut().zip(segment.page_flags.iter()) {
+                let active = (page_flags & (1 << super::ACTIVE_PAGE_BIT) != 0) as u64;
+                *history = ((*history << 1) | active) & mask;
+            }
+            bits.insert(segment.addr_start, segment_bits);
+        }
+        self.bits = bits;
+        self.samples = std::cmp::min(self.samples + 1, self.window);
+    }
+
+    // Of the last window samples, how many each page was active in. Empty if the
+    // segment hasn't been seen.
+    pub fn active_counts(&self, addr_start: usize) -> Vec<u32> {
+        match self.bits.get(&addr_start) {
+            Some(bits) => bits.iter().map(|history| history.count_ones()).collect(),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn tiers(&self, segment: &super::Segment) -> Tiers {
+        let mut tiers = Tiers::default();
+        let bits = match self.bits.get(&segment.addr_start) {
+            Some(bits) => bits,
+            None => return tiers,
+        };
+        for (history, page_flags) in bits.iter().zip(segment.page_flags.iter()) {
+            if page_flags & (1 << super::PRESENT_PAGE_BIT) == 0 {
+                continue;
+            }
+            match history.count_ones() {
+                0 => tiers.cold += 1,
+                count if count >= self.hot_threshold => tiers.hot += 1,
+                _ => tiers.warm += 1,
+            }
+        }
+        tiers
+    }
+
+    // Fills in the tier columns for the whole sample.
+    pub fn tier_analytics(&self, memory: &super::ProcessMemory, record: &mut StatsRecord) {
+        let mut total = Tiers::default();
+        for segment in &memory.segments {
+            let tiers = self.tiers(segment);
+            total.hot += tiers.hot;
+            total.warm += tiers.warm;
+            total.cold += tiers.cold;
+        }
+        record.hot_pages = total.hot;
+        record.warm_pages = total.warm;
+        record.cold_pages = total.cold;
+        info!("Tiers over last {} samples: hot {} kB (active in >= {}), warm {} kB, cold {} kB",
+              self.samples, total.hot * 4, self.hot_threshold, total.warm * 4, total.cold * 4);
+    }
+}
+
+// Logs a breakdown of the sample and returns its statistics record.
+// pid is None in host mode; fault and swap counts are then system-wide.
+// localities are from Locality::of_segments.
+pub fn page_analytics(pid: Option<i32>, memory: &super::ProcessMemory, localities: &[Locality]) -> StatsRecord {
     let mut total_pages = 0;
     let mut lru_pages = 0;
     let mut zero_pages = 0;
+    let mut pattern_pages = 0;
     let mut active_pages = 0;
     let mut present_pages = 0;
     for segment in &memory.segments {
@@ -18,6 +588,9 @@ pub fn page_analytics(pid: i32, memory: &super::ProcessMemory) {
             if page_flags & (1 << super::ZERO_PAGE_BIT) != 0 {
                 zero_pages += 1;
             }
+            if page_flags & (1 << super::PATTERN_PAGE_BIT) != 0 {
+                pattern_pages += 1;
+            }
             if page_flags & (1 << super::ACTIVE_PAGE_BIT) != 0 {
                 active_pages += 1;
             }
@@ -26,39 +599,189 @@ pub fn page_analytics(pid: i32, memory: &super::ProcessMemory) {
             }
         }
         debug!("Segment start {:x} with size {}", segment.addr_start, segment.page_flags.len());
+        for mapping in &segment.guest_mappings {
+            debug!("  offset {:x} is guest-physical {:x}-{:x}", mapping.segment_offset,
+                   mapping.guest_phys_addr, mapping.guest_phys_addr + mapping.size - 1);
+        }
     }
     info!("Total pages: {}", total_pages);
     log_info("LRU", lru_pages, total_pages);
     log_info("Zero", zero_pages, total_pages);
+    log_info("Repeating pattern", pattern_pages, total_pages);
     log_info("Active", active_pages, total_pages);
     log_info("Present", present_pages, total_pages);
+    page_type_analytics(memory);
+    ksm_analytics(memory);
+    compression_analytics(memory);
+    let locality = super::locality::locality_analytics(localities);
 
-    let mut row: Vec<String> = vec![
-        memory.timestamp.timestamp().to_string(),
-        total_pages.to_string(),
-        lru_pages.to_string(),
-        zero_pages.to_string(),
-        active_pages.to_string(),
-        present_pages.to_string()];
-    append_process_stats(pid, memory, &mut row);
-    let mut wtr = Writer::from_writer(
-        OpenOptions::new().append(true).create(true)
-            .open(format!("/tmp/wss/{}.csv", pid)).unwrap());
-    wtr.write_record(row).unwrap();
-    wtr.flush().unwrap();
+    let mut record = StatsRecord {
+        timestamp: memory.timestamp.timestamp(),
+        total_pages: total_pages as u64,
+        lru_pages: lru_pages as u64,
+        zero_pages: zero_pages as u64,
+        active_pages: active_pages as u64,
+        present_pages: present_pages as u64,
+        pattern_pages: pattern_pages as u64,
+        huge_idle_pages: locality.huge_idle_pages,
+        ..Default::default()
+    };
+    // Read once and shared by the host and system statistics.
+    let tables = read_proc_table("/proc/vmstat")
+        .and_then(|vmstat| Ok((vmstat, read_proc_table("/proc/meminfo")?)));
+    if let Some(pid) = pid {
+        append_process_stats(pid, memory, &mut record);
+    }
+    match &tables {
+        Ok((vmstat, meminfo)) => {
+            if pid.is_none() {
+                append_host_stats(&mut record, vmstat, meminfo);
+            }
+            append_system_stats(&mut record, vmstat, meminfo);
+        },
+        Err(e) => warn!("Unable to read system memory statistics: {:?}", e),
+    }
+    return record;
 
     fn log_info(name: &str, val: i64, total: i64) {
         info!("{}", format!("{} pages: {} = {:.1}%", name, val, 100.0 * val as f32 / total as f32));
     }
 }
 
-fn append_process_stats(pid: i32, memory: &super::ProcessMemory, row: &mut Vec<String>) {
+// One row per segment, so it's clear which mapping or RAM block holds the idle
+// memory. idle_ages and history should already have been updated with this sample.
+pub fn segment_analytics(memory: &super::ProcessMemory, localities: &[Locality], idle_ages: &IdleAges, history: &AccessHistory) -> Vec<SegmentRecord> {
+    memory.segments.iter().zip(localities).map(|(segment, locality)| {
+        let mut record = SegmentRecord {
+            timestamp: memory.timestamp.timestamp(),
+            address: segment.addr_start,
+            size_bytes: segment.page_flags.len() * 4096,
+            path: segment.path.clone(),
+            kind: segment.kind().to_string(),
+            locality: locality.clone(),
+            tiers: history.tiers(segment),
+            page_types: page_type_counts(segment),
+            ..Default::default()
+        };
+        for page_flags in &segment.page_flags {
+            let set = |bit: u8| (page_flags & (1 << bit) != 0) as u64;
+            record.present_pages += set(super::PRESENT_PAGE_BIT);
+            record.active_pages += set(super::ACTIVE_PAGE_BIT);
+            record.zero_pages += set(super::ZERO_PAGE_BIT);
+            record.swapped_pages += set(super::SWAPPED_PAGE_BIT);
+            record.lru_pages += set(super::LRU_PAGE_BIT);
+        }
+        for &age in idle_ages.ages(segment.addr_start) {
+            if age > 0 {
+                // 1 -> 0, 2-3 -> 1, 4-7 -> 2, ...
+                let bucket = (15 - age.leading_zeros()) as usize;
+                record.idle_pages_by_age[std::cmp::min(bucket, IDLE_AGE_BUCKETS - 1)] += 1;
+            }
+        }
+        let idle_pages: u64 = record.idle_pages_by_age.iter().sum();
+        info!("Segment {:x} ({}, {} kB): {} kB present, {} kB active, {} kB idle",
+              record.address, if record.path.is_empty() { &record.kind } else { &record.path },
+              record.size_bytes >> 10, record.present_pages * 4, record.active_pages * 4, idle_pages * 4);
+        record
+    }).collect()
+}
+
+// Host mode only: break active and idle memory down by what the kernel is using it for.
+fn page_type_analytics(memory: &super::ProcessMemory) {
+    let mut totals: HashMap<super::PageType, PageTypeCounts> = HashMap::new();
+    for segment in &memory.segments {
+        for (page_type, counts) in page_type_counts(segment) {
+            totals.entry(page_type).or_default().add(&counts);
+        }
+    }
+    for page_type in super::PageType::ALL.iter() {
+        if let Some(counts) = totals.get(page_type) {
+            info!("{:>9} pages: {} active, {} idle, {} untracked",
+                  page_type.name(), counts.active, counts.idle, counts.untracked);
+        }
+    }
+}
+
+fn page_type_counts(segment: &super::Segment) -> HashMap<super::PageType, PageTypeCounts> {
+    let mut counts: HashMap<super::PageType, PageTypeCounts> = HashMap::new();
+    for (page_flags, page_type) in segment.page_flags.iter().zip(segment.page_types.iter()) {
+        let count = counts.entry(*page_type).or_default();
+        if page_flags & (1 << super::PRESENT_PAGE_BIT) == 0 {
+            count.untracked += 1;
+        } else if page_flags & (1 << super::ACTIVE_PAGE_BIT) != 0 {
+            count.active += 1;
+        } else {
+            count.idle += 1;
+        }
+    }
+    counts
+}
+
+// Pages KSM has already merged, plus an estimate of how many more it could merge:
+// any hashed page whose contents duplicate another page's.
+fn ksm_analytics(memory: &super::ProcessMemory) {
+    let mut total_pages = 0;
+    let mut ksm_pages = 0;
+    // content hash -> (unmerged pages with that content, whether a KSM copy already exists)
+    let mut duplicates: HashMap<u64, (i64, bool)> = HashMap::new();
+    for segment in &memory.segments {
+        for (page_flags, content_hash) in segment.page_flags.iter().zip(segment.content_hashes.iter()) {
+            total_pages += 1;
+            let ksm = page_flags & (1 << super::MERGED_PAGE_BIT) != 0;
+            if ksm {
+                ksm_pages += 1;
+            }
+            if *content_hash == 0 {
+                continue;
+            }
+            let entry = duplicates.entry(*content_hash).or_insert((0, false));
+            if ksm {
+                entry.1 = true;
+            } else {
+                entry.0 += 1;
+            }
+        }
+    }
+    let mergeable_pages: i64 = duplicates.values()
+        .map(|&(unmerged, has_ksm_copy)| if has_ksm_copy { unmerged } else { unmerged - 1 })
+        .filter(|&pages| pages > 0)
+        .sum();
+    info!("KSM pages: {} = {:.1}%, estimated {} more mergeable", ksm_pages,
+          100.0 * ksm_pages as f32 / total_pages as f32, mergeable_pages);
+}
+
+// How much memory compressing the idle pages in zswap/zram would free, as opposed
+// to swapping them out. Each bucket saves what the page rounds up to short of a
+// whole page; incompressible pages are stored as is and save nothing.
+fn compression_analytics(memory: &super::ProcessMemory) {
+    let mut estimated_pages = 0;
+    let mut incompressible_pages = 0;
+    let mut saved_bytes = 0;
+    for segment in &memory.segments {
+        for page_flags in &segment.page_flags {
+            let bucket = (page_flags >> super::COMPRESSION_BUCKET_SHIFT) & super::COMPRESSION_BUCKET_MASK;
+            match bucket {
+                0 => continue,
+                super::COMPRESSION_BUCKET_INCOMPRESSIBLE => incompressible_pages += 1,
+                _ => saved_bytes += (8 - std::cmp::min(bucket, 8)) * 4096 / 8,
+            }
+            estimated_pages += 1;
+        }
+    }
+    if estimated_pages == 0 {
+        return;
+    }
+    info!("Compressing {} idle pages would save {} kB = {:.1}%; {} don't compress at all", estimated_pages, saved_bytes >> 10,
+          100.0 * saved_bytes as f32 / (estimated_pages * 4096) as f32, incompressible_pages);
+}
+
+fn append_process_stats(pid: i32, memory: &super::ProcessMemory, record: &mut StatsRecord) {
     // TODO: error handling. :P
     let mut system = System::new_with_specifics(RefreshKind::new());
     system.refresh_process(pid);
     let process = system.get_process(pid).unwrap();
-    row.push(process.minflt().to_string());
-    row.push(process.majflt().to_string());
+    record.minflt = process.minflt();
+    record.majflt = process.majflt();
 
     let mut smaps = String::new();
     File::open(format!("/proc/{}/smaps", pid)).unwrap().read_to_string(&mut smaps).unwrap();
@@ -66,7 +789,47 @@ fn append_process_stats(pid: i32, memory: &super::ProcessMemory, row: &mut Vec<S
         .map(|segment| swap_for_segment(&smaps, segment))
         .fold(0,|a, b| a + b);
     info!("Swap usage: {} kB", swap_usage >> 10);
-    row.push(swap_usage.to_string());
+    record.swap_bytes = swap_usage;
+}
+
+// Host mode stand-ins for the per-process fault and swap counts.
+fn append_host_stats(record: &mut StatsRecord, vmstat: &HashMap<String, u64>, meminfo: &HashMap<String, u64>) {
+    let pgfault = vmstat.get("pgfault").cloned().unwrap_or(0);
+    record.majflt = vmstat.get("pgmajfault").cloned().unwrap_or(0);
+    record.minflt = pgfault.saturating_sub(record.majflt);
+    let kb = |name: &str| meminfo.get(name).cloned().unwrap_or(0) << 10;
+    record.swap_bytes = kb("SwapTotal").saturating_sub(kb("SwapFree"));
+    info!("Swap usage: {} kB", record.swap_bytes >> 10);
+}
+
+fn append_system_stats(record: &mut StatsRecord, vmstat: &HashMap<String, u64>, meminfo: &HashMap<String, u64>) {
+    let kb = |name: &str| meminfo.get(name).cloned().unwrap_or(0) << 10;
+    let counter = |name: &str| vmstat.get(name).cloned().unwrap_or(0);
+    record.mem_total_bytes = kb("MemTotal");
+    record.mem_available_bytes = kb("MemAvailable");
+    record.pswpin = counter("pswpin");
+    record.pswpout = counter("pswpout");
+    record.pgmajfault = counter("pgmajfault");
+    // Split into anon and file from 5.9
+    record.workingset_refault = counter("workingset_refault")
+        + counter("workingset_refault_anon") + counter("workingset_refault_file");
+    info!("System: {} kB available of {} kB; pswpin {}, pswpout {}, pgmajfault {}, workingset_refault {}",
+          record.mem_available_bytes >> 10, record.mem_total_bytes >> 10,
+          record.pswpin, record.pswpout, record.pgmajfault, record.workingset_refault);
+}
+
+// "name value" or "name: value kB" lines, as in /proc/vmstat and /proc/meminfo.
+fn read_proc_table(path: &str) -> io::Result<HashMap<String, u64>> {
+    let mut contents = String::new();
+    File::open(path)?.read_to_string(&mut contents)?;
+    Ok(contents.lines()
+       .filter_map(|line| {
+           let mut fields = line.split_whitespace();
+           let name = fields.next()?.trim_end_matches(':');
+           let value = fields.next()?.parse().ok()?;
+           Some((name.to_string(), value))
+       })
+       .collect())
 }
 
 fn swap_for_segment(smaps: &str, segment: &super::Segment) -> u64 {
@@ -93,3 +856,119 @@ fn swap_for_segment(smaps: &str, segment: &super::Segment) -> u64 {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::testing::memory;
+
+    #[test]
+    fn segment_json_matches_row() {
+        let mut record = SegmentRecord { address: 0x1000, path: "[heap]".to_string(), present_pages: 3, ..Default::default() };
+        record.idle_pages_by_age[4] = 7;
+        record.locality.idle.buckets[0] = 2;
+        record.locality.idle.pages = 4;
+        record.locality.idle.longest = 1;
+        let json = record.to_json();
+        assert_eq!(json.len(), SegmentRecord::header().len());
+        for (name, value) in SegmentRecord::header().iter().zip(record.to_row()) {
+            match *name {
+                "address" | "path" | "kind" => assert_eq!(json[*name], value.as_str()),
+                _ => assert_eq!(json[*name].as_f64(), value.parse::<f64>().ok(), "{}", name),
+            }
+        }
+    }
+
+    #[test]
+    fn wss_estimate_over_a_partial_window() {
+        let mut estimator = WssEstimator::new(20, 0.1);
+        let mut record = StatsRecord::default();
+        for &active_pages in &[100, 300, 200] {
+            record = StatsRecord { active_pages: active_pages, ..Default::default() };
+            estimator.update(&mut record);
+        }
+        // Nearest rank over the 3 samples so far, not over the 20 the window will hold.
+        assert_eq!(record.wss_p50_bytes, 200 * 4096);
+        assert_eq!(record.wss_p95_bytes, 300 * 4096);
+        assert_eq!(record.wss_mean_bytes, 200 * 4096);
+        assert_eq!((record.wss_min_bytes, record.wss_max_bytes), (100 * 4096, 300 * 4096));
+        assert_eq!(record.recommended_bytes, (300.0 * 4096.0 * 1.1) as u64);
+        // 0.2 * 200 + 0.8 * (0.2 * 300 + 0.8 * 100)
+        assert_eq!(record.wss_ewma_bytes, 152 * 4096);
+
+        // Once full, the oldest sample drops out.
+        let mut estimator = WssEstimator::new(2, 0.0);
+        for &active_pages in &[300, 100, 200] {
+            record = StatsRecord { active_pages: active_pages, ..Default::default() };
+            estimator.update(&mut record);
+        }
+        assert_eq!((record.wss_min_bytes, record.wss_max_bytes), (100 * 4096, 200 * 4096));
+    }
+
+    #[test]
+    fn idle_ages_saturate_and_reset() {
+        let present = 1 << super::super::PRESENT_PAGE_BIT;
+        let active = present | 1 << super::super::ACTIVE_PAGE_BIT;
+        let mut ages = IdleAges::new();
+        ages.update(&memory(0, vec![(0x1000, vec![present, present, present])]));
+        ages.update(&memory(1, vec![(0x1000, vec![present, present, present])]));
+        assert_eq!(ages.ages(0x1000), &[2, 2, 2]);
+        ages.ages.get_mut(&0x1000).unwrap()[0] = u16::MAX - 1;
+        ages.update(&memory(2, vec![(0x1000, vec![present, active, 0])]));
+        ages.update(&memory(3, vec![(0x1000, vec![present, present, present])]));
+        assert_eq!(ages.ages(0x1000), &[u16::MAX, 1, 1]);
+        // A segment which changes size, or goes away, starts again.
+        ages.update(&memory(4, vec![(0x1000, vec![present; 4]), (0x10000, vec![present])]));
+        assert_eq!(ages.ages(0x1000), &[1, 1, 1, 1]);
+        ages.update(&memory(5, vec![(0x10000, vec![present])]));
+        assert!(ages.ages(0x1000).is_empty());
+        assert_eq!(ages.ages(0x10000), &[2]);
+    }
+
+    #[test]
+    fn access_history_tiers() {
+        let present = 1 << super::super::PRESENT_PAGE_BIT;
+        let active = present | 1 << super::super::ACTIVE_PAGE_BIT;
+        // Hot when active in 2 of the last 4 samples.
+        let mut history = AccessHistory::new(4, 2);
+        let mut tiers = Vec::new();
+        for (secs, &flags) in [active, active, present, present, present, present, active, 0].iter().enumerate() {
+            let sample = memory(secs as i64, vec![(0x1000, vec![flags])]);
+            history.update(&sample);
+            let segment_tiers = history.tiers(&sample.segments[0]);
+            tiers.push((segment_tiers.hot, segment_tiers.warm, segment_tiers.cold));
+        }
+        let (hot, warm, cold) = ((1, 0, 0), (0, 1, 0), (0, 0, 1));
+        assert_eq!(tiers, vec![
+            warm, hot,
+            // Still 2 of the last 4 until the older access drops out of the window.
+            hot, hot, warm, cold,
+            warm,
+            // Pages which aren't present aren't in any tier.
+            (0, 0, 0)]);
+        assert_eq!(history.active_counts(0x1000), vec![1]);
+        assert_eq!(history.samples, 4);
+    }
+
+    #[test]
+    fn page_types_split_untracked_pages() {
+        use super::super::PageType;
+        let present = 1 << super::super::PRESENT_PAGE_BIT;
+        let active = present | 1 << super::super::ACTIVE_PAGE_BIT;
+        let mut segment = super::super::Segment::from_page_flags(0, vec![active, present, present, 0, 0, active]);
+        segment.page_types = vec![PageType::Anon, PageType::Anon, PageType::File, PageType::Slab, PageType::Anon, PageType::File];
+        let counts = page_type_counts(&segment);
+        assert_eq!(counts[&PageType::Anon], PageTypeCounts { active: 1, idle: 1, untracked: 1 });
+        assert_eq!(counts[&PageType::File], PageTypeCounts { active: 1, idle: 1, untracked: 0 });
+        assert_eq!(counts[&PageType::Slab], PageTypeCounts { active: 0, idle: 0, untracked: 1 });
+        assert!(!counts.contains_key(&PageType::Buddy));
+
+        let record = SegmentRecord { page_types: counts, ..Default::default() };
+        let json = record.to_json();
+        assert_eq!(json["page_types"]["anon"]["untracked"], 1);
+        assert_eq!(json["page_types"]["slab"]["idle"], 0);
+        assert!(json["page_types"]["buddy"].is_null());
+        // Process mode has no page types and no extra key.
+        assert!(SegmentRecord::default().to_json()["page_types"].is_null());
+    }
+}
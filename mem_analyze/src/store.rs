@@ -0,0 +1,351 @@
+// Where persisted snapshots go. Keys are '/'-separated relative paths such as
+// "2019-06-01T12:00:00Z/0x7f0000000000"; each store decides where they are rooted.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use sys_info::hostname;
+
+use rusoto_core::{Region, HttpClient, RusotoError};
+use rusoto_core::credential::{StaticProvider, DefaultCredentialsProvider};
+use rusoto_s3::S3Client;
+use rusoto_s3::S3;
+use rusoto_s3::{PutObjectRequest, GetObjectRequest, GetObjectError, DeleteObjectRequest, ListObjectsV2Request};
+
+pub trait SnapshotStore: Send + Sync {
+    fn put(&self, key: &str, data: &[u8]) -> io::Result<()>;
+    fn get(&self, key: &str) -> io::Result<Vec<u8>>;
+    // All keys starting with prefix, sorted.
+    fn list(&self, prefix: &str) -> io::Result<Vec<String>>;
+    fn delete(&self, key: &str) -> io::Result<()>;
+    // For log messages
+    fn describe(&self) -> String;
+    // Whether the persister should prune old snapshots here. Object stores are
+    // better served by their own lifecycle rules than by listing every iteration.
+    fn enforce_retention(&self) -> bool {
+        true
+    }
+}
+
+// Open previously persisted snapshots: a local snapshot directory, e.g.
+// /tmp/wss/<pid>, or an archive file. Archives are opened read-only, since the
+// run may still be writing to them.
+pub fn open_path(path: &Path) -> io::Result<Box<dyn SnapshotStore>> {
+    if path.is_dir() {
+        Ok(Box::new(LocalStore::new(path)))
+    } else if path.is_file() {
+        Ok(Box::new(super::archive::ArchiveStore::open_read_only(path)?))
+    } else {
+        Err(io::Error::new(io::ErrorKind::NotFound, format!("No snapshots at {}", path.display())))
+    }
+}
+
+pub struct LocalStore {
+    root: PathBuf,
+}
+
+impl LocalStore {
+    pub fn new<P: AsRef<Path>>(root: P) -> LocalStore {
+        LocalStore { root: root.as_ref().to_path_buf() }
+    }
+
+    fn list_dir(&self, dir: &Path, keys: &mut Vec<String>) -> io::Result<()> {
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                self.list_dir(&path, keys)?;
+            } else if let Ok(relative) = path.strip_prefix(&self.root) {
+                keys.push(relative.to_string_lossy().replace(std::path::MAIN_SEPARATOR, "/"));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl SnapshotStore for LocalStore {
+    fn put(&self, key: &str, data: &[u8]) -> io::Result<()> {
+        let path = self.root.join(key);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut file = File::create(&path)?;
+        info!("Persisting process memory metadata to: {:?}", &file);
+        file.write_all(data)?;
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> io::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        File::open(self.root.join(key))?.read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    fn list(&self, prefix: &str) -> io::Result<Vec<String>> {
+        let mut keys = Vec::new();
+        if self.root.is_dir() {
+            self.list_dir(&self.root, &mut keys)?;
+        }
+        keys.retain(|key| key.starts_with(prefix));
+        keys.sort();
+        Ok(keys)
+    }
+
+    fn delete(&self, key: &str) -> io::Result<()> {
+        let path = self.root.join(key);
+        fs::remove_file(&path)?;
+        // Tidy up the snapshot directory once its last file goes. Fails harmlessly if not empty.
+        if let Some(parent) = path.parent() {
+            if parent != self.root {
+                let _ = fs::remove_dir(parent);
+            }
+        }
+        Ok(())
+    }
+
+    fn describe(&self) -> String {
+        format!("local:{}", self.root.display())
+    }
+}
+
+pub struct S3Store {
+    client: S3Client,
+    bucket: String,
+    prefix: String,
+}
+
+// Everything needed to talk to S3 or an S3-compatible object store such as MinIO.
+pub struct S3Config {
+    pub region: String,
+    // e.g. "http://minio.local:9000"; when set, region is just a name to sign with.
+    pub endpoint: Option<String>,
+    // Defaults to jgowans-wss-<region>
+    pub bucket: Option<String>,
+    // Defaults to the hostname
+    pub prefix: Option<String>,
+    // (access key id, secret access key). Otherwise the usual AWS provider chain is used.
+    pub credentials: Option<(String, String)>,
+}
+
+impl S3Store {
+    pub fn new(config: &S3Config) -> io::Result<S3Store> {
+        let region = match &config.endpoint {
+            Some(endpoint) => Region::Custom {
+                name: config.region.clone(),
+                endpoint: endpoint.clone(),
+            },
+            None => config.region.parse().map_err(|e| io::Error::new(io::ErrorKind::InvalidInput,
+                format!("Invalid region {}: {:?}", config.region, e)))?,
+        };
+        let http_client = HttpClient::new().map_err(s3_error)?;
+        let client = match &config.credentials {
+            Some((access_key, secret_key)) => S3Client::new_with(
+                http_client, StaticProvider::new_minimal(access_key.clone(), secret_key.clone()), region),
+            None => S3Client::new_with(
+                http_client, DefaultCredentialsProvider::new().map_err(s3_error)?, region),
+        };
+        let prefix = match &config.prefix {
+            Some(prefix) => prefix.trim_matches('/').to_string(),
+            None => hostname().map_err(s3_error)?,
+        };
+        Ok(S3Store {
+            client: client,
+            bucket: config.bucket.clone().unwrap_or_else(|| format!("jgowans-wss-{}", config.region)),
+            prefix: prefix,
+        })
+    }
+
+    fn object_key(&self, key: &str) -> String {
+        if self.prefix.is_empty() {
+            key.to_string()
+        } else {
+            format!("{}/{}", self.prefix, key)
+        }
+    }
+}
+
+impl SnapshotStore for S3Store {
+    fn put(&self, key: &str, data: &[u8]) -> io::Result<()> {
+        self.client.put_object(PutObjectRequest {
+            body: Some(data.to_vec().into()),
+            bucket: self.bucket.clone(),
+            key: self.object_key(key),
+            ..Default::default()
+        }).sync().map_err(s3_error)?;
+        info!("PutObject success");
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> io::Result<Vec<u8>> {
+        let output = self.client.get_object(GetObjectRequest {
+            bucket: self.bucket.clone(),
+            key: self.object_key(key),
+            ..Default::default()
+        }).sync().map_err(get_object_error)?;
+        let mut data = Vec::new();
+        if let Some(body) = output.body {
+            body.into_blocking_read().read_to_end(&mut data)?;
+        }
+        Ok(data)
+    }
+
+    fn list(&self, prefix: &str) -> io::Result<Vec<String>> {
+        let mut keys = Vec::new();
+        let mut continuation_token = None;
+        loop {
+            let output = self.client.list_objects_v2(ListObjectsV2Request {
+                bucket: self.bucket.clone(),
+                prefix: Some(self.object_key(prefix)),
+                continuation_token: continuation_token,
+                ..Default::default()
+            }).sync().map_err(s3_error)?;
+            for object in output.contents.unwrap_or_default() {
+                if let Some(key) = object.key {
+                    let strip = if self.prefix.is_empty() { 0 } else { self.prefix.len() + 1 };
+                    keys.push(key[strip..].to_string());
+                }
+            }
+            continuation_token = output.next_continuation_token;
+            if !output.is_truncated.unwrap_or(false) || continuation_token.is_none() {
+                break;
+            }
+        }
+        keys.sort();
+        Ok(keys)
+    }
+
+    fn delete(&self, key: &str) -> io::Result<()> {
+        self.client.delete_object(DeleteObjectRequest {
+            bucket: self.bucket.clone(),
+            key: self.object_key(key),
+            ..Default::default()
+        }).sync().map_err(s3_error)?;
+        Ok(())
+    }
+
+    fn describe(&self) -> String {
+        format!("s3://{}/{}", self.bucket, self.prefix)
+    }
+
+    fn enforce_retention(&self) -> bool {
+        false
+    }
+}
+
+fn s3_error<E: std::fmt::Debug>(error: E) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, format!("S3 error: {:?}", error))
+}
+
+// A missing key is NotFound, as from every other store, so callers can tell it
+// from the store being unreachable.
+fn get_object_error(error: RusotoError<GetObjectError>) -> io::Error {
+    match error {
+        RusotoError::Service(GetObjectError::NoSuchKey(message)) =>
+            io::Error::new(io::ErrorKind::NotFound, format!("S3 error: no such key: {}", message)),
+        error => s3_error(error),
+    }
+}
+
+// Keeps everything in process, without limit; for tests.
+pub struct MemoryStore {
+    objects: Mutex<BTreeMap<String, Vec<u8>>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore { objects: Mutex::new(BTreeMap::new()) }
+    }
+}
+
+impl SnapshotStore for MemoryStore {
+    fn put(&self, key: &str, data: &[u8]) -> io::Result<()> {
+        self.objects.lock().unwrap().insert(key.to_string(), data.to_vec());
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> io::Result<Vec<u8>> {
+        match self.objects.lock().unwrap().get(key) {
+            Some(data) => Ok(data.clone()),
+            None => Err(io::Error::new(io::ErrorKind::NotFound, format!("No such key: {}", key))),
+        }
+    }
+
+    fn list(&self, prefix: &str) -> io::Result<Vec<String>> {
+        Ok(self.objects.lock().unwrap().keys()
+           .filter(|key| key.starts_with(prefix))
+           .cloned()
+           .collect())
+    }
+
+    fn delete(&self, key: &str) -> io::Result<()> {
+        match self.objects.lock().unwrap().remove(key) {
+            Some(_data) => Ok(()),
+            None => Err(io::Error::new(io::ErrorKind::NotFound, format!("No such key: {}", key))),
+        }
+    }
+
+    fn describe(&self) -> String {
+        "memory".to_string()
+    }
+}
+
+// Refuses every put while failing is set.
+#[cfg(test)]
+pub struct FlakyStore {
+    pub inner: MemoryStore,
+    pub failing: std::sync::Arc<std::sync::atomic::AtomicBool>,
+}
+
+#[cfg(test)]
+impl SnapshotStore for FlakyStore {
+    fn put(&self, key: &str, data: &[u8]) -> io::Result<()> {
+        if self.failing.load(std::sync::atomic::Ordering::SeqCst) {
+            return Err(io::Error::new(io::ErrorKind::Other, "unavailable"));
+        }
+        self.inner.put(key, data)
+    }
+    fn get(&self, key: &str) -> io::Result<Vec<u8>> {
+        self.inner.get(key)
+    }
+    fn list(&self, prefix: &str) -> io::Result<Vec<String>> {
+        self.inner.list(prefix)
+    }
+    fn delete(&self, key: &str) -> io::Result<()> {
+        self.inner.delete(key)
+    }
+    fn describe(&self) -> String {
+        "flaky".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn memory_store_round_trip() {
+        let store = MemoryStore::new();
+        store.put("b/1", b"one").unwrap();
+        store.put("a/2", b"two").unwrap();
+        store.put("b/3", b"three").unwrap();
+        store.put("b/1", b"uno").unwrap();
+        assert_eq!(store.get("b/1").unwrap(), b"uno".to_vec());
+        assert_eq!(store.list("").unwrap(), vec!["a/2", "b/1", "b/3"]);
+        assert_eq!(store.list("b/").unwrap(), vec!["b/1", "b/3"]);
+        store.delete("b/1").unwrap();
+        assert_eq!(store.get("b/1").unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert_eq!(store.delete("b/1").unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert_eq!(store.list("b/").unwrap(), vec!["b/3"]);
+    }
+
+    #[test]
+    fn missing_s3_object_is_not_found() {
+        let error = get_object_error(RusotoError::Service(GetObjectError::NoSuchKey("1/stats.csv".to_string())));
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+        let error = get_object_error(RusotoError::Validation("bad key".to_string()));
+        assert_eq!(error.kind(), io::ErrorKind::Other);
+    }
+}
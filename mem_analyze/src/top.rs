@@ -0,0 +1,483 @@
+// A top-style live view. Each refresh shows the page counts and fault and swap
+// rates for the selected target, a list of its segments, and the selected
+// segment's page map as a grid of coloured blocks.
+//
+// Sampling runs on its own thread, since a sample takes the whole interval; the
+// terminal thread reads keys and redraws in the meantime. Switching PID or
+// changing the interval takes effect from the next sample.
+//
+// Keys: q quit, n/p next/previous PID, j/k or arrows select a segment,
+// +/- change the interval.
+
+use std::collections::HashMap;
+use std::io;
+use std::io::Write;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use nix::libc;
+use nix::poll::{poll, EventFlags, PollFd};
+use nix::sys::termios;
+use nix::unistd;
+use chrono::Utc;
+
+use super::render;
+use super::statistics::{self, StatsRecord};
+use super::locality::Locality;
+
+const STDIN: i32 = 0;
+const STDOUT: i32 = 1;
+// Seconds; + and - step through these.
+const INTERVALS: [u64; 9] = [1, 2, 5, 10, 15, 30, 60, 120, 300];
+const SEGMENT_ROWS: usize = 6;
+// Key poll timeout, in milliseconds
+const POLL_MS: i32 = 250;
+
+ioctl_read_bad!(window_size, libc::TIOCGWINSZ, libc::winsize);
+
+pub struct TopOptions {
+    // Empty for the whole host
+    pub pids: Vec<i32>,
+    pub interval: u64,
+}
+
+// What the sampling thread should look at next.
+struct Control {
+    target: Option<i32>,
+    interval: u64,
+}
+
+struct Sample {
+    target: Option<i32>,
+    memory: super::ProcessMemory,
+    stats: StatsRecord,
+    // From the previous sample of the same target, per second
+    minflt_rate: Option<f64>,
+    majflt_rate: Option<f64>,
+    // Since the previous sample of the same target
+    pswpout: Option<u64>,
+    pswpin: Option<u64>,
+}
+
+struct View {
+    targets: Vec<Option<i32>>,
+    target_idx: usize,
+    segment_idx: usize,
+    interval: u64,
+    sample: Option<Sample>,
+    error: Option<String>,
+}
+
+// Puts the terminal in non-canonical, no-echo mode on the alternate screen, and
+// restores it when dropped.
+struct Terminal {
+    original: termios::Termios,
+}
+
+impl Terminal {
+    fn enter() -> io::Result<Terminal> {
+        let original = termios::tcgetattr(STDIN).map_err(nix_error)?;
+        let mut raw = original.clone();
+        // ISIG off too, so Ctrl-C comes through as a key and we get to restore the terminal.
+        raw.local_flags.remove(termios::LocalFlags::ICANON | termios::LocalFlags::ECHO | termios::LocalFlags::ISIG);
+        raw.control_chars[termios::SpecialCharacterIndices::VMIN as usize] = 1;
+        raw.control_chars[termios::SpecialCharacterIndices::VTIME as usize] = 0;
+        termios::tcsetattr(STDIN, termios::SetArg::TCSAFLUSH, &raw).map_err(nix_error)?;
+        let mut stdout = io::stdout();
+        write!(stdout, "\x1b[?1049h\x1b[?25l")?;
+        stdout.flush()?;
+        Ok(Terminal { original: original })
+    }
+
+    // (columns, rows)
+    fn size(&self) -> (usize, usize) {
+        let mut size = libc::winsize { ws_row: 0, ws_col: 0, ws_xpixel: 0, ws_ypixel: 0 };
+        match unsafe { window_size(STDOUT, &mut size) } {
+            Ok(_) if size.ws_col > 0 && size.ws_row > 0 => (size.ws_col as usize, size.ws_row as usize),
+            _ => (80, 24),
+        }
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        let mut stdout = io::stdout();
+        let _ = write!(stdout, "\x1b[0m\x1b[?25h\x1b[?1049l");
+        let _ = stdout.flush();
+        let _ = termios::tcsetattr(STDIN, termios::SetArg::TCSAFLUSH, &self.original);
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Key {
+    Quit,
+    NextPid,
+    PreviousPid,
+    NextSegment,
+    PreviousSegment,
+    Faster,
+    Slower,
+}
+
+// Takes the complete keys off the front of input. An escape sequence cut off by
+// the end of a read is left in input, to be finished by the next one.
+fn parse_keys(input: &mut Vec<u8>) -> Vec<Key> {
+    let mut keys = Vec::new();
+    let mut idx = 0;
+    while idx < input.len() {
+        // Arrow keys are ESC [ A-D.
+        if input[idx] == 0x1b {
+            match (input.get(idx + 1), input.get(idx + 2)) {
+                (None, _) | (Some(b'['), None) => break,
+                (Some(b'['), Some(final_byte)) => {
+                    match final_byte {
+                        b'A' => keys.push(Key::PreviousSegment),
+                        b'B' => keys.push(Key::NextSegment),
+                        b'C' => keys.push(Key::NextPid),
+                        b'D' => keys.push(Key::PreviousPid),
+                        _ => {},
+                    }
+                    idx += 3;
+                },
+                // Not a sequence; the ESC alone means nothing.
+                _ => idx += 1,
+            }
+            continue;
+        }
+        match input[idx] {
+            b'q' | b'Q' | 0x03 => keys.push(Key::Quit),
+            b'n' | b'\t' => keys.push(Key::NextPid),
+            b'p' => keys.push(Key::PreviousPid),
+            b'j' => keys.push(Key::NextSegment),
+            b'k' => keys.push(Key::PreviousSegment),
+            b'+' | b'=' => keys.push(Key::Slower),
+            b'-' | b'_' => keys.push(Key::Faster),
+            _ => {},
+        }
+        idx += 1;
+    }
+    input.drain(..idx);
+    keys
+}
+
+pub fn top(options: &TopOptions) -> io::Result<()> {
+    let targets: Vec<Option<i32>> = if options.pids.is_empty() {
+        vec![None]
+    } else {
+        options.pids.iter().map(|&pid| Some(pid)).collect()
+    };
+    let control = Arc::new(Mutex::new(Control { target: targets[0], interval: options.interval }));
+    let (sender, receiver) = mpsc::channel();
+    let sampler_control = control.clone();
+    thread::Builder::new()
+        .name("sampler".to_string())
+        .spawn(move || sample_loop(&sampler_control, &sender))?;
+
+    let terminal = Terminal::enter()?;
+    let mut view = View {
+        targets: targets,
+        target_idx: 0,
+        segment_idx: 0,
+        interval: options.interval,
+        sample: None,
+        error: None,
+    };
+    let mut size = (0, 0);
+    let mut dirty = true;
+    // Input left over from the last read: part of an escape sequence
+    let mut pending_input: Vec<u8> = Vec::new();
+    loop {
+        if terminal.size() != size {
+            size = terminal.size();
+            dirty = true;
+        }
+        if dirty {
+            draw(&view, size)?;
+            dirty = false;
+        }
+
+        let mut fds = [PollFd::new(STDIN, EventFlags::POLLIN)];
+        if poll(&mut fds, POLL_MS).map_err(nix_error)? > 0 {
+            let mut input = [0u8; 64];
+            let len = unistd::read(STDIN, &mut input).map_err(nix_error)?;
+            pending_input.extend_from_slice(&input[..len]);
+            for key in parse_keys(&mut pending_input) {
+                let segments = view.sample.as_ref().map_or(0, |sample| sample.memory.segments.len());
+                match key {
+                    Key::Quit => return Ok(()),
+                    Key::NextPid => view.target_idx = (view.target_idx + 1) % view.targets.len(),
+                    Key::PreviousPid => view.target_idx = (view.target_idx + view.targets.len() - 1) % view.targets.len(),
+                    Key::NextSegment => view.segment_idx = std::cmp::min(view.segment_idx + 1, segments.saturating_sub(1)),
+                    Key::PreviousSegment => view.segment_idx = view.segment_idx.saturating_sub(1),
+                    Key::Slower => view.interval = *INTERVALS.iter().find(|&&i| i > view.interval).unwrap_or(&view.interval),
+                    Key::Faster => view.interval = *INTERVALS.iter().rev().find(|&&i| i < view.interval).unwrap_or(&view.interval),
+                }
+                let mut control = control.lock().unwrap();
+                control.target = view.targets[view.target_idx];
+                control.interval = view.interval;
+                dirty = true;
+            }
+        }
+
+        while let Ok((target, result)) = receiver.try_recv() {
+            // A sample started before switching PID is stale.
+            if target != view.targets[view.target_idx] {
+                continue;
+            }
+            match result {
+                Ok(sample) => {
+                    if view.sample.as_ref().map_or(true, |previous| previous.target != sample.target) {
+                        view.segment_idx = 0;
+                    }
+                    view.segment_idx = std::cmp::min(view.segment_idx, sample.memory.segments.len().saturating_sub(1));
+                    view.sample = Some(sample);
+                    view.error = None;
+                },
+                Err(e) => view.error = Some(format!("Unable to sample {}: {}", target_name(target), e)),
+            }
+            dirty = true;
+        }
+    }
+}
+
+fn sample_loop(control: &Mutex<Control>, sender: &mpsc::Sender<(Option<i32>, io::Result<Sample>)>) {
+    let mut previous: HashMap<Option<i32>, StatsRecord> = HashMap::new();
+    loop {
+        let (target, interval) = {
+            let control = control.lock().unwrap();
+            (control.target, control.interval)
+        };
+        let memory = match target {
+            Some(pid) => super::dump::get_memory(pid, interval, None),
+            None => super::dump::get_host_memory(interval, false, None),
+        };
+        let result = memory.map(|memory| {
+            let stats = statistics::page_analytics(target, &memory, &Locality::of_segments(&memory));
+            let last = previous.insert(target, stats.clone());
+            let elapsed = last.as_ref().map(|last| stats.timestamp - last.timestamp).filter(|&elapsed| elapsed > 0);
+            let rate = |now: u64, then: u64| elapsed.map(|elapsed| now.saturating_sub(then) as f64 / elapsed as f64);
+            Sample {
+                target: target,
+                minflt_rate: last.as_ref().and_then(|last| rate(stats.minflt, last.minflt)),
+                majflt_rate: last.as_ref().and_then(|last| rate(stats.majflt, last.majflt)),
+                pswpout: last.as_ref().map(|last| stats.pswpout.saturating_sub(last.pswpout)),
+                pswpin: last.as_ref().map(|last| stats.pswpin.saturating_sub(last.pswpin)),
+                memory: memory,
+                stats: stats,
+            }
+        });
+        // Don't spin on a PID that has gone away.
+        let failed = result.is_err();
+        if sender.send((target, result)).is_err() {
+            return;
+        }
+        if failed {
+            thread::sleep(std::time::Duration::from_secs(interval));
+        }
+    }
+}
+
+fn target_name(target: Option<i32>) -> String {
+    match target {
+        Some(pid) => format!("pid {}", pid),
+        None => "host".to_string(),
+    }
+}
+
+fn percent(part: u64, total: u64) -> f64 {
+    if total == 0 { 0.0 } else { 100.0 * part as f64 / total as f64 }
+}
+
+fn mib(bytes: u64) -> f64 {
+    bytes as f64 / (1 << 20) as f64
+}
+
+fn optional<T: std::fmt::Display>(value: Option<T>) -> String {
+    value.map_or("-".to_string(), |value| value.to_string())
+}
+
+fn draw(view: &View, (columns, rows): (usize, usize)) -> io::Result<()> {
+    let mut lines: Vec<String> = Vec::new();
+    let target = view.targets[view.target_idx];
+    lines.push(format!("wss top - {} ({}/{})  interval {}s  {}", target_name(target), view.target_idx + 1,
+                       view.targets.len(), view.interval, Utc::now().format("%H:%M:%S")));
+    if let Some(error) = &view.error {
+        lines.push(format!("\x1b[31m{}\x1b[0m", error));
+    }
+    let sample = match &view.sample {
+        Some(sample) if sample.target == target => sample,
+        _ => {
+            lines.push(format!("Sampling {} for {}s...", target_name(target), view.interval));
+            return write_screen(&lines, columns);
+        },
+    };
+
+    let stats = &sample.stats;
+    let swapped: u64 = sample.memory.segments.iter()
+        .map(|segment| segment.page_flags.iter().filter(|&&flags| flags & (1 << super::SWAPPED_PAGE_BIT) != 0).count() as u64)
+        .sum();
+    lines.push(format!("Sample {}  pages {}  active {:.1}%  idle {:.1}%  zero {:.1}%  swapped {:.1}%",
+                       sample.memory.timestamp.format("%H:%M:%S"), stats.total_pages,
+                       percent(stats.active_pages, stats.total_pages),
+                       percent(stats.present_pages.saturating_sub(stats.active_pages), stats.total_pages),
+                       percent(stats.zero_pages, stats.total_pages),
+                       percent(swapped, stats.total_pages)));
+    lines.push(format!("WSS {:.1} MiB  faults/s minor {}  major {}  pageouts {}  pageins {}  swap {:.1} MiB",
+                       mib(stats.active_pages * 4096),
+                       optional(sample.minflt_rate.map(|rate| format!("{:.1}", rate))),
+                       optional(sample.majflt_rate.map(|rate| format!("{:.1}", rate))),
+                       optional(sample.pswpout), optional(sample.pswpin), mib(stats.swap_bytes)));
+    lines.push(String::new());
+
+    // A window of segments around the selected one
+    let segments = &sample.memory.segments;
+    let first = std::cmp::min(view.segment_idx.saturating_sub(SEGMENT_ROWS / 2),
+                              segments.len().saturating_sub(SEGMENT_ROWS));
+    for (idx, segment) in segments.iter().enumerate().skip(first).take(SEGMENT_ROWS) {
+        let active = segment.page_flags.iter().filter(|&&flags| flags & (1 << super::ACTIVE_PAGE_BIT) != 0).count() as u64;
+        let line = format!("{} {:>16x} {:>10.1} MiB  active {:>5.1}%  {}",
+                           if idx == view.segment_idx { ">" } else { " " }, segment.addr_start,
+                           mib(segment.page_flags.len() as u64 * 4096),
+                           percent(active, segment.page_flags.len() as u64),
+                           if segment.path.is_empty() { segment.kind() } else { &segment.path });
+        lines.push(if idx == view.segment_idx { format!("\x1b[7m{}\x1b[0m", line) } else { line });
+    }
+    lines.push(String::new());
+
+    let footer = "q quit  n/p pid  j/k segment  +/- interval   \x1b[31m█\x1b[0m active  \x1b[32m█\x1b[0m idle  \
+                  \x1b[91m█\x1b[0m\x1b[92m█\x1b[0m zero  \x1b[90m█\x1b[0m swapped";
+    if let Some(segment) = segments.get(view.segment_idx) {
+        let grid_rows = rows.saturating_sub(lines.len() + 2);
+        let cells = std::cmp::max(columns * grid_rows, 1);
+        let pages_per_cell = std::cmp::max((segment.page_flags.len() + cells - 1) / cells, 1);
+        lines.push(format!("{:x}-{:x}, {} pages per block",
+                           segment.addr_start, segment.addr_start + segment.page_flags.len() * 4096, pages_per_cell));
+        lines.extend(page_grid(segment, columns, pages_per_cell));
+    }
+    while lines.len() + 1 < rows {
+        lines.push(String::new());
+    }
+    lines.push(footer.to_string());
+    write_screen(&lines, columns)
+}
+
+// One character per block of pages, coloured by its hottest page.
+fn page_grid(segment: &super::Segment, columns: usize, pages_per_cell: usize) -> Vec<String> {
+    let colours: Vec<u8> = segment.page_flags.chunks(pages_per_cell)
+        .map(|block| block.iter().map(|&flags| render::page_colour(flags)).max_by_key(|&colour| render::heat(colour)).unwrap())
+        .collect();
+    colours.chunks(std::cmp::max(columns, 1)).map(|row| {
+        let mut line = String::new();
+        let mut current = None;
+        for &colour in row {
+            if current != Some(colour) {
+                line.push_str(match colour {
+                    render::ACTIVE => "\x1b[31m",
+                    render::ACTIVE_ZERO => "\x1b[91m",
+                    render::IDLE => "\x1b[32m",
+                    render::IDLE_ZERO => "\x1b[92m",
+                    render::SWAPPED => "\x1b[90m",
+                    _ => "\x1b[0m",
+                });
+                current = Some(colour);
+            }
+            line.push(if colour == render::BLACK { ' ' } else { '█' });
+        }
+        line.push_str("\x1b[0m");
+        line
+    }).collect()
+}
+
+fn write_screen(lines: &[String], columns: usize) -> io::Result<()> {
+    // Overwrite in place rather than clearing first, which flickers.
+    let mut screen = String::from("\x1b[H");
+    for (idx, line) in lines.iter().enumerate() {
+        if idx > 0 {
+            screen.push_str("\r\n");
+        }
+        screen.push_str(&truncate(line, columns));
+        screen.push_str("\x1b[K");
+    }
+    screen.push_str("\x1b[J");
+    let mut stdout = io::stdout();
+    stdout.write_all(screen.as_bytes())?;
+    stdout.flush()
+}
+
+// Cuts a line to the given number of visible characters, leaving escape sequences alone.
+fn truncate(line: &str, columns: usize) -> String {
+    let mut out = String::new();
+    let mut visible = 0;
+    let mut in_escape = false;
+    for c in line.chars() {
+        if c == '\x1b' {
+            in_escape = true;
+        }
+        if in_escape {
+            out.push(c);
+            in_escape = !c.is_ascii_alphabetic();
+            continue;
+        }
+        if visible < columns {
+            out.push(c);
+            visible += 1;
+        }
+    }
+    out
+}
+
+fn nix_error(error: nix::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, format!("{:?}", error))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_and_arrows() {
+        let mut input = b"qn\tpjk+=-_x\x1b[A\x1b[B\x1b[C\x1b[D\x1b[Z\x03".to_vec();
+        assert_eq!(parse_keys(&mut input), vec![
+            Key::Quit, Key::NextPid, Key::NextPid, Key::PreviousPid, Key::NextSegment, Key::PreviousSegment,
+            Key::Slower, Key::Slower, Key::Faster, Key::Faster,
+            Key::PreviousSegment, Key::NextSegment, Key::NextPid, Key::PreviousPid,
+            Key::Quit]);
+        assert!(input.is_empty());
+    }
+
+    #[test]
+    fn escape_split_across_reads() {
+        // ESC at the very end of one read, [ A at the start of the next.
+        let mut input = b"j\x1b".to_vec();
+        assert_eq!(parse_keys(&mut input), vec![Key::NextSegment]);
+        assert_eq!(input, b"\x1b");
+        input.extend_from_slice(b"[A");
+        assert_eq!(parse_keys(&mut input), vec![Key::PreviousSegment]);
+        assert!(input.is_empty());
+
+        // Split after the [, so the D isn't taken for a key of its own.
+        let mut input = b"\x1b[".to_vec();
+        assert!(parse_keys(&mut input).is_empty());
+        assert_eq!(input, b"\x1b[");
+        input.extend_from_slice(b"Dq");
+        assert_eq!(parse_keys(&mut input), vec![Key::PreviousPid, Key::Quit]);
+        assert!(input.is_empty());
+
+        // An ESC followed by anything but [ is dropped, and what follows still counts.
+        let mut input = b"\x1bq".to_vec();
+        assert_eq!(parse_keys(&mut input), vec![Key::Quit]);
+        assert!(input.is_empty());
+    }
+
+    #[test]
+    fn truncate_counts_visible_characters() {
+        assert_eq!(truncate("hello", 3), "hel");
+        assert_eq!(truncate("hi", 10), "hi");
+        assert_eq!(truncate("hello", 0), "");
+        // Escape sequences take no room, and ones past the cut are kept so colours are reset.
+        assert_eq!(truncate("\x1b[1;31mhello\x1b[0m", 2), "\x1b[1;31mhe\x1b[0m");
+        assert_eq!(truncate("ab\x1b[7mcd\x1b[0mef", 3), "ab\x1b[7mc\x1b[0m");
+        // Characters, not bytes
+        assert_eq!(truncate("\u{2588}\u{2588}\u{2591}x", 3), "\u{2588}\u{2588}\u{2591}");
+    }
+}
@@ -0,0 +1,342 @@
+// Uploads to slow or flaky stores (S3) happen on a background thread so the
+// sampling loop never waits on the network. Memory use is bounded: once the queue
+// holds too many bytes, further snapshots go straight to a spool directory, as do
+// uploads which exhaust their retries. The spool is drained again after the next
+// upload that succeeds, or every SPOOL_RETRY_SECS while uploads are failing, and
+// whatever is left in it is picked up when the queue is next created.
+
+use std::collections::VecDeque;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Condvar};
+use std::{thread, time};
+
+use super::store::SnapshotStore;
+
+// Backoff before retry n is INITIAL_BACKOFF_MS * 2^n, capped at MAX_BACKOFF_MS.
+const INITIAL_BACKOFF_MS: u64 = 500;
+const MAX_BACKOFF_MS: u64 = 60 * 1000;
+const SPOOL_RETRY_SECS: u64 = 5 * 60;
+
+pub struct UploadQueue {
+    inner: Arc<dyn SnapshotStore>,
+    shared: Arc<(Mutex<QueueState>, Condvar)>,
+    spool_dir: PathBuf,
+    max_queued_bytes: usize,
+}
+
+struct QueueState {
+    pending: VecDeque<Upload>,
+    // Only counts data held in memory, not spooled uploads.
+    queued_bytes: usize,
+}
+
+struct Upload {
+    key: String,
+    data: UploadData,
+}
+
+enum UploadData {
+    Memory(Vec<u8>),
+    Spooled(PathBuf),
+}
+
+impl UploadQueue {
+    pub fn new(inner: Box<dyn SnapshotStore>, spool_dir: &Path, max_queued_bytes: usize, retries: u32) -> io::Result<UploadQueue> {
+        fs::create_dir_all(spool_dir)?;
+        let queue = UploadQueue {
+            inner: Arc::from(inner),
+            shared: Arc::new((Mutex::new(QueueState {
+                pending: VecDeque::new(),
+                queued_bytes: 0,
+            }), Condvar::new())),
+            spool_dir: spool_dir.to_path_buf(),
+            max_queued_bytes: max_queued_bytes,
+        };
+        let resumed = queue.resume_spool()?;
+        if resumed > 0 {
+            info!("Resuming {} spooled uploads from {}", resumed, spool_dir.display());
+        }
+        let inner = queue.inner.clone();
+        let shared = queue.shared.clone();
+        let spool_dir = queue.spool_dir.clone();
+        thread::Builder::new()
+            .name("upload".to_string())
+            .spawn(move || upload_worker(inner, shared, spool_dir, retries))?;
+        Ok(queue)
+    }
+
+    fn resume_spool(&self) -> io::Result<usize> {
+        let (lock, condvar) = &*self.shared;
+        let count = requeue_spool(&self.spool_dir, &mut lock.lock().unwrap())?;
+        condvar.notify_one();
+        Ok(count)
+    }
+}
+
+// Queue everything in the spool which isn't queued already.
+fn requeue_spool(spool_dir: &Path, state: &mut QueueState) -> io::Result<usize> {
+    let mut spooled: Vec<PathBuf> = fs::read_dir(spool_dir)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().map_or(true, |ext| ext != "tmp"))
+        .filter(|path| !state.pending.iter().any(|upload| match &upload.data {
+            UploadData::Spooled(pending) => pending == path,
+            UploadData::Memory(_data) => false,
+        }))
+        .collect();
+    spooled.sort();
+    let count = spooled.len();
+    for path in spooled {
+        let key = unescape_key(&path.file_name().unwrap().to_string_lossy());
+        state.pending.push_back(Upload { key: key, data: UploadData::Spooled(path) });
+    }
+    Ok(count)
+}
+
+impl SnapshotStore for UploadQueue {
+    fn put(&self, key: &str, data: &[u8]) -> io::Result<()> {
+        let (lock, condvar) = &*self.shared;
+        let mut state = lock.lock().unwrap();
+        let upload = if state.queued_bytes + data.len() > self.max_queued_bytes {
+            warn!("Upload queue full ({} bytes); spooling {}", state.queued_bytes, key);
+            Upload { key: key.to_string(), data: UploadData::Spooled(spool(&self.spool_dir, key, data)?) }
+        } else {
+            state.queued_bytes += data.len();
+            Upload { key: key.to_string(), data: UploadData::Memory(data.to_vec()) }
+        };
+        state.pending.push_back(upload);
+        condvar.notify_one();
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> io::Result<Vec<u8>> {
+        self.inner.get(key)
+    }
+
+    fn list(&self, prefix: &str) -> io::Result<Vec<String>> {
+        self.inner.list(prefix)
+    }
+
+    fn delete(&self, key: &str) -> io::Result<()> {
+        self.inner.delete(key)
+    }
+
+    fn describe(&self) -> String {
+        format!("queued {}", self.inner.describe())
+    }
+
+    fn enforce_retention(&self) -> bool {
+        self.inner.enforce_retention()
+    }
+}
+
+fn upload_worker(inner: Arc<dyn SnapshotStore>, shared: Arc<(Mutex<QueueState>, Condvar)>, spool_dir: PathBuf, retries: u32) {
+    let (lock, condvar) = &*shared;
+    let spool_retry = time::Duration::from_secs(SPOOL_RETRY_SECS);
+    // When an upload was last left in the spool, if none has succeeded since
+    let mut spool_failed: Option<time::Instant> = None;
+    loop {
+        let upload = {
+            let mut state = lock.lock().unwrap();
+            loop {
+                if spool_failed.map_or(false, |failed| failed.elapsed() >= spool_retry) {
+                    requeue(&spool_dir, &mut state);
+                    spool_failed = None;
+                }
+                if let Some(upload) = state.pending.pop_front() {
+                    break upload;
+                }
+                state = match spool_failed {
+                    Some(failed) => {
+                        let timeout = spool_retry.checked_sub(failed.elapsed()).unwrap_or_default();
+                        condvar.wait_timeout(state, timeout).unwrap().0
+                    },
+                    None => condvar.wait(state).unwrap(),
+                };
+            }
+        };
+        let data = match &upload.data {
+            UploadData::Memory(data) => data.clone(),
+            UploadData::Spooled(path) => match read_file(path) {
+                Ok(data) => data,
+                Err(e) => {
+                    error!("Unable to read spooled upload {:?}: {:?}", path, e);
+                    continue;
+                }
+            },
+        };
+        let uploaded = put_with_retries(&*inner, &upload.key, &data, retries);
+        match (&upload.data, uploaded) {
+            (UploadData::Spooled(path), true) => {
+                if let Err(e) = fs::remove_file(path) {
+                    error!("Unable to remove spooled upload {:?}: {:?}", path, e);
+                }
+            },
+            (UploadData::Memory(_data), false) => {
+                match spool(&spool_dir, &upload.key, &data) {
+                    Ok(path) => warn!("Giving up on {} for now; spooled to {:?}", upload.key, path),
+                    Err(e) => error!("Dropping {}: unable to spool: {:?}", upload.key, e),
+                }
+                spool_failed = Some(time::Instant::now());
+            },
+            // Spooled uploads which fail again stay in the spool for the next drain.
+            (UploadData::Spooled(_path), false) => spool_failed = Some(time::Instant::now()),
+            (UploadData::Memory(_data), true) => {},
+        }
+        // The store is back; don't wait for the timer.
+        if uploaded && spool_failed.is_some() {
+            requeue(&spool_dir, &mut lock.lock().unwrap());
+            spool_failed = None;
+        }
+        if let UploadData::Memory(data) = &upload.data {
+            lock.lock().unwrap().queued_bytes -= data.len();
+        }
+    }
+}
+
+fn requeue(spool_dir: &Path, state: &mut QueueState) {
+    match requeue_spool(spool_dir, state) {
+        Ok(0) => {},
+        Ok(count) => info!("Retrying {} spooled uploads from {}", count, spool_dir.display()),
+        Err(e) => error!("Unable to read spool {}: {:?}", spool_dir.display(), e),
+    }
+}
+
+fn put_with_retries(store: &dyn SnapshotStore, key: &str, data: &[u8], retries: u32) -> bool {
+    for attempt in 0..=retries {
+        match store.put(key, data) {
+            Ok(()) => return true,
+            Err(e) => {
+                error!("Upload of {} to {} failed (attempt {}): {:?}", key, store.describe(), attempt + 1, e);
+                if attempt < retries {
+                    let backoff = std::cmp::min(INITIAL_BACKOFF_MS << attempt, MAX_BACKOFF_MS);
+                    thread::sleep(time::Duration::from_millis(backoff));
+                }
+            }
+        }
+    }
+    false
+}
+
+// Write via a temporary file so a crash never leaves a truncated upload to resume.
+fn spool(spool_dir: &Path, key: &str, data: &[u8]) -> io::Result<PathBuf> {
+    let path = spool_dir.join(escape_key(key));
+    let tmp_path = path.with_extension("tmp");
+    File::create(&tmp_path)?.write_all(data)?;
+    fs::rename(&tmp_path, &path)?;
+    Ok(path)
+}
+
+fn read_file(path: &Path) -> io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    File::open(path)?.read_to_end(&mut data)?;
+    Ok(data)
+}
+
+// Keys contain '/', spool files are flat.
+fn escape_key(key: &str) -> String {
+    key.replace('%', "%25").replace('/', "%2F").replace('.', "%2E")
+}
+
+fn unescape_key(file_name: &str) -> String {
+    file_name.replace("%2E", ".").replace("%2F", "/").replace("%25", "%")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use super::super::store::{FlakyStore, MemoryStore};
+
+    fn spool_dir(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("wss-spool-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        path
+    }
+
+    fn spooled(spool_dir: &Path) -> Vec<String> {
+        let mut keys: Vec<String> = fs::read_dir(spool_dir).unwrap()
+            .map(|entry| unescape_key(&entry.unwrap().file_name().to_string_lossy()))
+            .collect();
+        keys.sort();
+        keys
+    }
+
+    // The worker runs on its own thread, so poll until it catches up.
+    fn wait_for<F: Fn() -> bool>(condition: F) -> bool {
+        let deadline = time::Instant::now() + time::Duration::from_secs(10);
+        while time::Instant::now() < deadline {
+            if condition() {
+                return true;
+            }
+            thread::sleep(time::Duration::from_millis(10));
+        }
+        condition()
+    }
+
+    fn flaky_queue(spool_dir: &Path, failing: &Arc<AtomicBool>) -> UploadQueue {
+        let store = FlakyStore { inner: MemoryStore::new(), failing: failing.clone() };
+        UploadQueue::new(Box::new(store), spool_dir, 16, 0).unwrap()
+    }
+
+    #[test]
+    fn failed_uploads_are_spooled() {
+        let dir = spool_dir("failed");
+        let failing = Arc::new(AtomicBool::new(true));
+        let queue = flaky_queue(&dir, &failing);
+        queue.put("1/small", &[1; 8]).unwrap();
+        // Past the bound, so it never goes into memory.
+        queue.put("1/large", &[2; 64]).unwrap();
+        assert!(queue.shared.0.lock().unwrap().queued_bytes <= 16);
+        assert!(wait_for(|| spooled(&dir) == vec!["1/large", "1/small"]));
+        assert!(wait_for(|| queue.shared.0.lock().unwrap().queued_bytes == 0));
+        assert_eq!(queue.get("1/small").unwrap_err().kind(), io::ErrorKind::NotFound);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn spool_is_drained_once_the_store_recovers() {
+        let dir = spool_dir("drained");
+        let failing = Arc::new(AtomicBool::new(true));
+        let queue = flaky_queue(&dir, &failing);
+        queue.put("1/a", &[1; 8]).unwrap();
+        queue.put("1/b", &[2; 64]).unwrap();
+        assert!(wait_for(|| spooled(&dir).len() == 2));
+        failing.store(false, Ordering::SeqCst);
+        // The next successful upload drains the spool without waiting for the timer.
+        queue.put("2/a", &[3; 8]).unwrap();
+        assert!(wait_for(|| spooled(&dir).is_empty()));
+        assert!(wait_for(|| queue.list("").unwrap() == vec!["1/a", "1/b", "2/a"]));
+        assert_eq!(queue.get("1/b").unwrap(), vec![2; 64]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn existing_spool_is_uploaded_on_start() {
+        let dir = spool_dir("resumed");
+        fs::create_dir_all(&dir).unwrap();
+        spool(&dir, "1/manifest.json", b"{}").unwrap();
+        spool(&dir, "1/flags/0", &[4; 32]).unwrap();
+        // Left behind by a crash mid-write, never uploaded.
+        File::create(dir.join("partial.tmp")).unwrap();
+        let failing = Arc::new(AtomicBool::new(false));
+        let queue = flaky_queue(&dir, &failing);
+        assert!(wait_for(|| queue.list("").unwrap() == vec!["1/flags/0", "1/manifest.json"]));
+        assert_eq!(queue.get("1/manifest.json").unwrap(), b"{}".to_vec());
+        assert!(wait_for(|| spooled(&dir) == vec!["partial.tmp"]));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn keys_survive_escaping() {
+        for key in &["1/flags/0", "a%2Fb", "manifest.json", "%"] {
+            let escaped = escape_key(key);
+            assert!(!escaped.contains('/') && !escaped.contains('.'));
+            assert_eq!(unescape_key(&escaped), *key);
+        }
+    }
+}
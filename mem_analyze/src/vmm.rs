@@ -1,11 +1,41 @@
 use telnet::Telnet;
 use telnet::TelnetEvent;
 use rand::seq::SliceRandom;
+use json::JsonValue;
+use std::collections::HashMap;
 
 pub struct Vmm {
     telnet: Telnet,
 }
 
+// Which addresses pageout_pages is sent: host-virtual addresses in the QEMU
+// process, or guest-physical addresses, which need the segment's guest mappings.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PageoutAddresses {
+    Host,
+    GuestPhysical,
+}
+
+impl std::str::FromStr for PageoutAddresses {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<PageoutAddresses, String> {
+        match s {
+            "host" => Ok(PageoutAddresses::Host),
+            "guest-physical" => Ok(PageoutAddresses::GuestPhysical),
+            _ => Err(format!("Unsupported pageout addresses: {}", s)),
+        }
+    }
+}
+
+// A QEMU RAM block as seen through the guest's flat memory view.
+struct RamBlock {
+    name: String,
+    size: usize,
+    // (offset into the block, guest-physical address, length)
+    ranges: Vec<(usize, usize, usize)>,
+}
+
 impl Vmm {
     pub fn new() -> Vmm {
         let mut vmm = match Telnet::connect(("127.0.0.1", 4444), 256) {
@@ -24,7 +54,7 @@ impl Vmm {
         vmm
     }
 
-    pub fn swap_some_out(&mut self, segment: &super::Segment, pages_to_swap: u64) {
+    pub fn swap_some_out(&mut self, segment: &super::Segment, pages_to_swap: u64, addresses: PageoutAddresses) {
         info!("Selecting pages to sample...");
         let idle_pages: Vec<usize> = segment.page_flags.iter().enumerate()
             .filter(|(_idx, &val)| val & (1 << super::PRESENT_PAGE_BIT) != 0)
@@ -32,10 +62,25 @@ impl Vmm {
             .map(|(idx, _val)| idx)
             .collect();
         let mut rng = rand::thread_rng();
-        let selected: Vec<usize> = idle_pages.as_slice()
+        let selected_offsets: Vec<usize> = idle_pages.as_slice()
             .choose_multiple(&mut rng, pages_to_swap as usize)
-            .map(|page_offset| segment.addr_start + (4096 * page_offset))
+            .cloned()
             .collect();
+        let selected: Vec<usize> = match addresses {
+            PageoutAddresses::Host => selected_offsets.iter()
+                .map(|page_offset| segment.addr_start + (4096 * page_offset))
+                .collect(),
+            PageoutAddresses::GuestPhysical => {
+                if segment.guest_mappings.is_empty() {
+                    error!("Segment {:x} has no guest-physical mappings; not paging out", segment.addr_start);
+                    return;
+                }
+                // Pages QEMU doesn't map into the guest have no GPA to send.
+                selected_offsets.iter()
+                    .filter_map(|&page_offset| segment.guest_phys_addr(page_offset))
+                    .collect()
+            },
+        };
         debug!("Idle_pages len: {}", idle_pages.len());
         debug!("pageout len: {}", selected.len());
         let data = object!{
@@ -48,4 +93,183 @@ impl Vmm {
             _ => print!("Other?"),
         }
     }
+
+    // Ask QEMU for the guest memory layout and record, for every segment that backs
+    // a RAM block, which guest-physical ranges it provides.
+    // QEMU doesn't report the host address of a RAM block, so segments are matched
+    // to blocks by size, in address order.
+    pub fn annotate_guest_physical(&mut self, memory: &mut super::ProcessMemory) {
+        let blocks = self.ram_blocks();
+        let mut claimed: Vec<bool> = vec![false; memory.segments.len()];
+        for block in blocks {
+            let candidates: Vec<usize> = memory.segments.iter().enumerate()
+                .filter(|(idx, segment)| !claimed[*idx] && segment.page_flags.len() * 4096 == block.size)
+                .map(|(idx, _segment)| idx)
+                .collect();
+            if candidates.len() > 1 {
+                warn!("{} segments match RAM block {} with size {}; assuming the first, {:x}",
+                      candidates.len(), block.name, block.size, memory.segments[candidates[0]].addr_start);
+            }
+            match candidates.first().cloned() {
+                Some(idx) => {
+                    claimed[idx] = true;
+                    let segment = &mut memory.segments[idx];
+                    segment.guest_mappings = block.ranges.iter()
+                        .map(|&(offset, gpa, size)| super::GuestMapping {
+                            segment_offset: offset,
+                            guest_phys_addr: gpa,
+                            size: size,
+                        }).collect();
+                    debug!("RAM block {} ({} bytes) is segment {:x}", block.name, block.size, segment.addr_start);
+                },
+                None => warn!("No segment matches RAM block {} with size {}", block.name, block.size),
+            }
+        }
+    }
+
+    fn ram_blocks(&mut self) -> Vec<RamBlock> {
+        let mtree = self.execute(object!{
+            "execute" => "human-monitor-command",
+            "arguments" => object!{"command-line" => "info mtree -f"}
+        });
+        let mut blocks = parse_flat_mtree(mtree["return"].as_str().unwrap_or(""));
+        // Blocks backed by a memory backend object may not be fully mapped into the
+        // guest (e.g. hotpluggable DIMMs), so prefer the backend's own idea of size.
+        let memdevs = self.execute(object!{"execute" => "query-memdev"});
+        for memdev in memdevs["return"].members() {
+            if let (Some(id), Some(size)) = (memdev["id"].as_str(), memdev["size"].as_usize()) {
+                if let Some(block) = blocks.iter_mut().find(|b| b.name == id) {
+                    block.size = size;
+                }
+            }
+        }
+        blocks
+    }
+
+    // Issue a QMP command and wait for its reply, discarding any asynchronous events.
+    fn execute(&mut self, command: JsonValue) -> JsonValue {
+        self.telnet.write(command.dump().as_bytes()).unwrap();
+        let mut buffer = String::new();
+        loop {
+            match self.telnet.read().unwrap() {
+                TelnetEvent::Data(d) => buffer.push_str(std::str::from_utf8(&d).unwrap()),
+                _ => continue,
+            }
+            while let Some(end) = buffer.find('\n') {
+                let line: String = buffer.drain(..=end).collect();
+                match json::parse(&line) {
+                    Ok(reply) => {
+                        if reply.has_key("return") {
+                            return reply;
+                        }
+                        if reply.has_key("error") {
+                            error!("QMP command {} failed: {}", command.dump(), reply["error"].dump());
+                            return reply;
+                        }
+                    },
+                    Err(e) => error!("Unable to parse QMP line {:?}: {:?}", line, e),
+                }
+            }
+        }
+    }
+}
+
+// Parse the "memory" address space out of `info mtree -f`. Lines look like:
+//   0000000000100000-00000000bfffffff (prio 0, ram): pc.ram @0000000000100000 KVM
+fn parse_flat_mtree(mtree: &str) -> Vec<RamBlock> {
+    let mut blocks: HashMap<String, RamBlock> = HashMap::new();
+    let mut in_system_memory = false;
+    for line in mtree.lines() {
+        let line = line.trim();
+        if line.starts_with("FlatView") {
+            in_system_memory = false;
+        } else if line.starts_with("AS \"memory\"") {
+            in_system_memory = true;
+        }
+        if !in_system_memory || !line.contains(", ram):") {
+            continue;
+        }
+        let (start, end) = match scan_fmt!(line, "{x}-{x}", [hex usize], [hex usize]) {
+            Ok(range) => range,
+            Err(_) => {
+                error!("Unable to parse mtree line: {}", line);
+                continue;
+            }
+        };
+        let mut fields = line.splitn(2, "): ").nth(1).unwrap_or("").split_ascii_whitespace();
+        let name = match fields.next() {
+            Some(name) => name.to_string(),
+            None => continue,
+        };
+        let offset = match fields.next() {
+            Some(field) if field.starts_with('@') => usize::from_str_radix(&field[1..], 16).unwrap_or(0),
+            _ => 0,
+        };
+        let size = end - start + 1;
+        let block = blocks.entry(name.clone()).or_insert(RamBlock {
+            name: name,
+            size: 0,
+            ranges: Vec::new(),
+        });
+        block.size = std::cmp::max(block.size, offset + size);
+        block.ranges.push((offset, start, size));
+    }
+    let mut blocks: Vec<RamBlock> = blocks.into_iter().map(|(_name, block)| block).collect();
+    blocks.sort_by_key(|block| block.ranges[0].1);
+    blocks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // From a q35 guest with 4 GiB of RAM, trimmed to the interesting views.
+    const MTREE: &str = "\
+FlatView #0
+ AS \"I/O\", root: io
+ Root memory region: io
+  0000000000000000-0000000000000007 (prio 0, i/o): dma-chan
+  0000000000000cf8-0000000000000cfb (prio 0, i/o): pci-conf-idx
+
+FlatView #1
+ AS \"memory\", root: system
+ AS \"cpu-memory-0\", root: system
+ Root memory region: system
+  0000000000000000-000000000009ffff (prio 0, ram): pc.ram KVM
+  00000000000a0000-00000000000bffff (prio 1, i/o): vga-lowmem
+  00000000000c0000-00000000000dffff (prio 0, rom): pc.ram @00000000000c0000 KVM
+  00000000000e0000-00000000000fffff (prio 0, rom): pc.bios @0000000000020000 KVM
+  0000000000100000-000000007fffffff (prio 0, ram): pc.ram @0000000000100000 KVM
+  00000000fd000000-00000000fdffffff (prio 1, ram): vga.vram KVM
+  00000000feb80000-00000000feb80fff (prio 1, i/o): virtio-pci-common-virtio-net
+  00000000fffc0000-00000000ffffffff (prio 0, rom): pc.bios KVM
+  0000000100000000-000000017fffffff (prio 0, ram): pc.ram @0000000080000000 KVM
+
+FlatView #2
+ AS \"virtio-net-pci\", root: bus master container
+ Root memory region: (none)
+  0000000000000000-000000000000ffff (prio 0, ram): virtio-shadow KVM
+";
+
+    #[test]
+    fn ram_split_around_the_pci_hole() {
+        let blocks = parse_flat_mtree(MTREE);
+        let names: Vec<&str> = blocks.iter().map(|block| block.name.as_str()).collect();
+        assert_eq!(names, vec!["pc.ram", "vga.vram"]);
+        // Read-only views such as the option ROM area aren't ranges the guest can dirty.
+        assert_eq!(blocks[0].ranges, vec![
+            (0, 0, 0xa0000),
+            (0x100000, 0x100000, 0x7ff00000),
+            (0x80000000, 0x100000000, 0x80000000)]);
+        assert_eq!(blocks[0].size, 4 << 30);
+        assert_eq!(blocks[1].ranges, vec![(0, 0xfd000000, 16 << 20)]);
+        assert_eq!(blocks[1].size, 16 << 20);
+    }
+
+    #[test]
+    fn pageout_addresses_from_str() {
+        assert_eq!("host".parse::<PageoutAddresses>(), Ok(PageoutAddresses::Host));
+        assert_eq!("guest-physical".parse::<PageoutAddresses>(), Ok(PageoutAddresses::GuestPhysical));
+        assert!("gpa".parse::<PageoutAddresses>().is_err());
+    }
 }